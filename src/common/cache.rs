@@ -0,0 +1,142 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/*
+..####....####....####...##..##..######.
+.##..##..##..##..##..##..##..##..##.....
+.##......######..##......######..####...
+.##..##..##..##..##..##..##..##..##.....
+..####...##..##...####...##..##..######.
+........................................
+*/
+
+// ? Preprocessed data that is slow to produce, every file can be deleted at any time
+pub const CACHE_DIRECTORY: &str = "./cache";
+
+pub fn cache_path(name: &str) -> PathBuf {
+	Path::new(CACHE_DIRECTORY).join(name)
+}
+
+pub fn read_cache(path: &Path) -> Option<Vec<u8>> {
+	fs::read(path).ok()
+}
+
+// ? A failed write only costs a recomputation next time
+pub fn write_cache(path: &Path, bytes: &[u8]) {
+	let result = fs::create_dir_all(CACHE_DIRECTORY).and_then(|_| fs::write(path, bytes));
+	if let Err(error) = result {
+		println!("Failed to write {}: {}", path.display(), error);
+	}
+}
+
+// ? Little endian, prefixed with a magic and a version
+pub struct CacheWriter {
+	pub bytes: Vec<u8>,
+}
+
+impl CacheWriter {
+	pub fn new(magic: &[u8; 4], version: u32) -> Self {
+		let mut writer = CacheWriter { bytes: magic.to_vec() };
+		writer.u32(version);
+		writer
+	}
+
+	pub fn u8(&mut self, value: u8) {
+		self.bytes.push(value);
+	}
+
+	pub fn u32(&mut self, value: u32) {
+		self.bytes.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+	}
+
+	pub fn u64(&mut self, value: u64) {
+		self.u32(value as u32);
+		self.u32((value >> 32) as u32);
+	}
+
+	pub fn f32(&mut self, value: f32) {
+		self.u32(value.to_bits());
+	}
+
+	pub fn f32s(&mut self, values: &[f32]) {
+		for &value in values {
+			self.f32(value);
+		}
+	}
+
+	// ? Length prefixed
+	pub fn bytes(&mut self, bytes: &[u8]) {
+		self.u32(bytes.len() as u32);
+		self.bytes.extend_from_slice(bytes);
+	}
+
+	pub fn string(&mut self, string: &str) {
+		self.bytes(string.as_bytes());
+	}
+}
+
+// ? Reads what CacheWriter wrote, every read fails past the end
+pub struct CacheReader<'a> {
+	bytes: &'a [u8],
+	position: usize,
+}
+
+impl<'a> CacheReader<'a> {
+	// ? Fails when the magic or version don't match
+	pub fn new(bytes: &'a [u8], magic: &[u8; 4], version: u32) -> Option<Self> {
+		let mut reader = CacheReader { bytes, position: 0 };
+		if bytes.len() < 4 || &bytes[..4] != magic {
+			return None;
+		}
+		reader.position = 4;
+		if reader.u32()? != version {
+			return None;
+		}
+		Some(reader)
+	}
+
+	// ? Bytes left, to check a count against before allocating for it
+	pub fn remaining(&self) -> usize {
+		self.bytes.len() - self.position
+	}
+
+	pub fn u8(&mut self) -> Option<u8> {
+		let value = *self.bytes.get(self.position)?;
+		self.position += 1;
+		Some(value)
+	}
+
+	pub fn u32(&mut self) -> Option<u32> {
+		let bytes = self.bytes.get(self.position..self.position + 4)?;
+		self.position += 4;
+		Some(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+	}
+
+	pub fn u64(&mut self) -> Option<u64> {
+		let low = self.u32()? as u64;
+		let high = self.u32()? as u64;
+		Some(low | high << 32)
+	}
+
+	pub fn f32(&mut self) -> Option<f32> {
+		self.u32().map(f32::from_bits)
+	}
+
+	pub fn f32s(&mut self, values: &mut [f32]) -> Option<()> {
+		for value in values.iter_mut() {
+			*value = self.f32()?;
+		}
+		Some(())
+	}
+
+	pub fn bytes(&mut self) -> Option<&'a [u8]> {
+		let length = self.u32()? as usize;
+		let bytes = self.bytes.get(self.position..self.position + length)?;
+		self.position += length;
+		Some(bytes)
+	}
+
+	pub fn string(&mut self) -> Option<String> {
+		String::from_utf8(self.bytes()?.to_vec()).ok()
+	}
+}
@@ -2,43 +2,119 @@
 use gfx;
 use cgmath::{Deg, Matrix4, vec3, Vector3, Rad};
 
+pub mod cache;
+
 pub type ColorFormat = gfx::format::Rgba8;
+// ? Lighting is accumulated in linear floating point and tone mapped to ColorFormat at the end
+pub type HdrColorFormat = gfx::format::Rgba16F;
 pub type DepthFormat = gfx::format::DepthStencil;
 
 gfx_constant_struct!(ForwardLocals {
 	eye_position: [f32; 4] = "u_EyePosition",
+	ambient_color: [f32; 4] = "u_AmbientColor",
+	ibl: [f32; 4] = "u_Ibl",
+	cluster_scale: [f32; 4] = "u_ClusterScale",
 	num_lights: i32 = "u_NumLights",
 });
 
+gfx_constant_struct!(MaterialLocals {
+	ambient: [f32; 4] = "u_Ambient",
+	diffuse: [f32; 4] = "u_Diffuse",
+	specular: [f32; 4] = "u_Specular",
+	shininess: f32 = "u_Shininess",
+	dissolve: f32 = "u_Dissolve",
+	optical_density: f32 = "u_OpticalDensity",
+	illumination_model: i32 = "u_IlluminationModel",
+	emissive: [f32; 4] = "u_Emissive",
+	metallic: f32 = "u_Metallic",
+	roughness: f32 = "u_Roughness",
+	occlusion_strength: f32 = "u_OcclusionStrength",
+	pbr: i32 = "u_Pbr", // 1 selects the metallic-roughness path
+});
+
+impl MaterialLocals {
+	// ? Used for objects without a material: plain white Blinn-Phong
+	pub fn new_default() -> Self {
+		MaterialLocals {
+			ambient: [0.0, 0.0, 0.0, 1.0],
+			diffuse: [1.0, 1.0, 1.0, 1.0],
+			specular: [0.0, 0.0, 0.0, 1.0],
+			shininess: 1.0,
+			dissolve: 1.0,
+			optical_density: 1.0,
+			illumination_model: 2,
+			emissive: [0.0, 0.0, 0.0, 1.0],
+			metallic: 0.0,
+			roughness: 1.0,
+			occlusion_strength: 1.0,
+			pbr: 0,
+		}
+	}
+}
+
 #[derive(ConstantBuffer, Copy, Clone)]
 pub struct LightSourceInfo {
-	pos: [f32; 4],
-	color: [f32; 4],
+	pos: [f32; 4], // w: light kind, see LightKind::shader_id
+	color: [f32; 4], // w: intensity
+	direction: [f32; 4],
+	attenuation: [f32; 4], // constant, linear, quadratic, range
+	cone: [f32; 4], // cosines of the inner and outer cone angles
+	shadow: [f32; 4], // first shadow map layer, layer count (0 = no shadows), bias, normal bias
 }
 
 impl LightSourceInfo {
-	pub fn new(pos: [f32; 3], color: [f32; 3]) -> Self {
+	pub fn new(kind: i32, pos: [f32; 3], color: [f32; 3], intensity: f32, direction: [f32; 3], attenuation: [f32; 4], cone: [f32; 2]) -> Self {
 		LightSourceInfo {
-			pos: [pos[0], pos[1], pos[2], 1.0],
-			color: [color[0], color[1], color[2], 1.0],
+			pos: [pos[0], pos[1], pos[2], kind as f32],
+			color: [color[0], color[1], color[2], intensity],
+			direction: [direction[0], direction[1], direction[2], 0.0],
+			attenuation,
+			cone: [cone[0], cone[1], 0.0, 0.0],
+			shadow: [0.0; 4],
+		}
+	}
+
+	pub fn set_shadow(&mut self, first_layer: usize, layer_count: usize, bias: f32, normal_bias: f32) {
+		self.shadow = [first_layer as f32, layer_count as f32, bias, normal_bias];
+	}
+
+	// ? One row of the clustered light data texture, in the order of the fields
+	pub fn texels(&self) -> [[f32; 4]; 6] {
+		[self.pos, self.color, self.direction, self.attenuation, self.cone, self.shadow]
+	}
+}
+
+#[derive(ConstantBuffer, Copy, Clone)]
+pub struct ShadowMapInfo {
+	light_vp: [[f32; 4]; 4],
+	split: [f32; 4], // far view distance covered, texel world size, 1 if the size grows with the distance to the light
+}
+
+impl ShadowMapInfo {
+	pub fn new(light_vp: [[f32; 4]; 4], split_distance: f32, texel_size: f32, is_perspective: bool) -> Self {
+		ShadowMapInfo {
+			light_vp,
+			split: [split_distance, texel_size, if is_perspective { 1.0 } else { 0.0 }, 0.0],
 		}
 	}
 }
 
-#[derive(VertexData)]
+#[derive(VertexData, Copy, Clone)]
 pub struct Vertex {
     v_pos: [f32; 4],
 	v_color: [f32; 3],
 	v_normal: [f32; 4],
+	v_tangent: [f32; 4],
 	v_uv: [f32; 2],
 }
 
 impl Vertex {
-    pub fn new(pos: [f32; 3], color: [f32; 3], normal: [f32; 3], uv: [f32; 2]) -> Vertex {
+    pub fn new(pos: [f32; 3], color: [f32; 3], normal: [f32; 3], tangent: [f32; 4], uv: [f32; 2]) -> Vertex {
         Vertex {
 			v_pos: [pos[0], pos[1], pos[2], 1.0],
 			v_color: color,
 			v_normal: [normal[0], normal[1], normal[2], 1.0],
+			v_tangent: tangent,
 			v_uv: uv,
 		}
     }
@@ -48,20 +124,64 @@ impl Vertex {
 			pos,
 			[1.0; 3],
 			[0.0; 3],
+			[1.0, 0.0, 0.0, 1.0],
 			[0.0; 2]
 		)
     }
+
+	pub fn position(&self) -> [f32; 3] {
+		[self.v_pos[0], self.v_pos[1], self.v_pos[2]]
+	}
+
+	pub fn normal(&self) -> [f32; 3] {
+		[self.v_normal[0], self.v_normal[1], self.v_normal[2]]
+	}
+
+	pub fn color(&self) -> [f32; 3] {
+		self.v_color
+	}
+
+	pub fn tangent(&self) -> [f32; 4] {
+		self.v_tangent
+	}
+
+	pub fn uv(&self) -> [f32; 2] {
+		self.v_uv
+	}
+
+	pub fn set_position(&mut self, position: [f32; 3]) {
+		self.v_pos = [position[0], position[1], position[2], 1.0];
+	}
 }
 
 gfx_defines!{
+	// ? Uses all 16 texture samplers gfx (MAX_SAMPLERS) and GL 3.2 allow, a new texture has to share one of them
 	pipeline pipe {
 		vbuf: gfx::VertexBuffer<Vertex> = (),
+		ambient_texture: gfx::TextureSampler<[f32; 4]> = "u_AmbientTexture",
 		diffuse_texture: gfx::TextureSampler<[f32; 4]> = "u_DiffuseTexture",
+		specular_texture: gfx::TextureSampler<[f32; 4]> = "u_SpecularTexture",
+		normal_texture: gfx::TextureSampler<[f32; 4]> = "u_NormalTexture",
+		dissolve_texture: gfx::TextureSampler<[f32; 4]> = "u_DissolveTexture",
+		metallic_texture: gfx::TextureSampler<[f32; 4]> = "u_MetallicTexture",
+		roughness_texture: gfx::TextureSampler<[f32; 4]> = "u_RoughnessTexture",
+		emissive_texture: gfx::TextureSampler<[f32; 4]> = "u_EmissiveTexture",
+		occlusion_texture: gfx::TextureSampler<[f32; 4]> = "u_OcclusionTexture",
 		ps_locals: gfx::ConstantBuffer<ForwardLocals> = "Locals",
-		light_sources_info: gfx::ConstantBuffer<LightSourceInfo> = "b_lights",
-		out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+		material_locals: gfx::ConstantBuffer<MaterialLocals> = "MaterialLocals",
+		ambient_occlusion: gfx::TextureSampler<[f32; 4]> = "u_AmbientOcclusion",
+		environment_map: gfx::TextureSampler<[f32; 4]> = "u_EnvironmentMap",
+		prefiltered_map: gfx::TextureSampler<[f32; 4]> = "u_PrefilteredMap",
+		brdf_lut: gfx::TextureSampler<[f32; 4]> = "u_BrdfLut",
+		cluster_data: gfx::TextureSampler<[f32; 4]> = "u_ClusterData",
+		shadow_maps: gfx::TextureSampler<f32> = "u_ShadowMaps",
+		shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_shadow_maps",
+		point_shadow_maps: gfx::TextureSampler<f32> = "u_PointShadowMaps",
+		point_shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_point_shadow_maps",
+		out: gfx::BlendTarget<HdrColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
 		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
 		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
 		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
 	}
 }
@@ -19,8 +19,22 @@ use time::precise_time_s;
 
 mod scene;
 mod common;
+mod renderer;
 
 use common::*;
+use renderer::{shader_source, RenderPath, TextureLibrary, CLUSTERS_GLSL, FORWARD_GLSL, FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL};
+use renderer::cluster::{cluster_scale, LightClusters};
+use renderer::debug_view::{DebugRenderer, DebugSettings, DebugView};
+use renderer::deferred::DeferredRenderer;
+use renderer::lod::LodBuffers;
+use renderer::msaa::{self, MultisampleTarget};
+use renderer::overlay::Overlay;
+use renderer::post_process::PostProcessStack;
+use renderer::shadow::{ShadowMaps, MAX_POINT_SHADOW_CASTERS, MAX_SHADOW_MAPS};
+use renderer::skybox::EnvironmentMap;
+use renderer::ssao::Ssao;
+use renderer::tone_mapping::srgb_to_linear;
+use renderer::transparency::TransparencyRenderer;
 use scene::Scene;
 use scene::entity::Entity3D;
 
@@ -33,6 +47,9 @@ use scene::entity::Entity3D;
 ................................
 */
 
+// ? F6 cycles through these, every level multiplies the triangle count by 4
+const MAX_INTERACTIVE_SUBDIVISION_LEVELS: u32 = 2;
+
 #[derive(Eq, PartialEq)]
 enum CoreState {
 	Waiting,
@@ -43,12 +60,20 @@ enum CoreState {
 struct Core {
 	state: CoreState,
 	scene: Scene,
+	render_path: RenderPath,
+	debug: DebugSettings,
+	// ? Set when the objects' vertices changed, their buffers have to be uploaded again
+	geometry_changed: bool,
 }
 impl Core {
 	pub fn new() -> Self {
+		let scene = Scene::new_test_scene(std::env::args().skip(1));
 		Core {
 			state: CoreState::Waiting,
-			scene: Scene::new_test_scene(),
+			scene,
+			render_path: RenderPath::Forward,
+			debug: DebugSettings::default(),
+			geometry_changed: false,
 		}
 	}
 
@@ -69,6 +94,56 @@ impl Core {
 								glutin::VirtualKeyCode::Escape => {
 									return;
 								},
+								glutin::VirtualKeyCode::F1 => {
+									if input.state == glutin::ElementState::Pressed {
+										self.render_path = self.render_path.toggled();
+										println!("Render path: {:?}", self.render_path);
+									}
+								},
+								glutin::VirtualKeyCode::F3 => {
+									if input.state == glutin::ElementState::Pressed {
+										self.debug.view = self.debug.view.next();
+										println!("Debug view: {:?}", self.debug.view);
+									}
+								},
+								glutin::VirtualKeyCode::F4 => {
+									if input.state == glutin::ElementState::Pressed {
+										self.debug.wireframe = !self.debug.wireframe;
+										println!("Wireframe: {}", if self.debug.wireframe { "on" } else { "off" });
+									}
+								},
+								glutin::VirtualKeyCode::F5 => {
+									if input.state == glutin::ElementState::Pressed {
+										self.debug.vertex_normals = !self.debug.vertex_normals;
+										println!("Vertex normals: {}", if self.debug.vertex_normals { "on" } else { "off" });
+									}
+								},
+								glutin::VirtualKeyCode::F6 => {
+									if input.state == glutin::ElementState::Pressed {
+										let levels = (self.scene.subdivision.levels + 1) % (MAX_INTERACTIVE_SUBDIVISION_LEVELS + 1);
+										self.scene.set_subdivision_levels(levels);
+										self.geometry_changed = true;
+										println!("Subdivision levels: {}", levels);
+									}
+								},
+								glutin::VirtualKeyCode::C => {
+									if input.state == glutin::ElementState::Pressed {
+										// ? Leaves a copy of the current viewpoint, drawn as a frustum by the overlay
+										self.scene.cameras.push(self.scene.camera.clone());
+									}
+								},
+								glutin::VirtualKeyCode::T => {
+									if input.state == glutin::ElementState::Pressed {
+										self.scene.tone_mapping.operator = self.scene.tone_mapping.operator.next();
+										println!("Tone mapping: {:?}", self.scene.tone_mapping.operator);
+									}
+								},
+								glutin::VirtualKeyCode::PageUp => {
+									self.scene.tone_mapping.exposure *= 1.25;
+								},
+								glutin::VirtualKeyCode::PageDown => {
+									self.scene.tone_mapping.exposure /= 1.25;
+								},
 								glutin::VirtualKeyCode::Up => {
 									self.scene.camera.translate(0.0, 0.0, 0.25);
 									if let Some(object) = self.scene.objects.get_mut(1) {
@@ -111,25 +186,6 @@ impl Core {
 	}
 }
 
-/*
-.##..##..######..######..##.......####..
-.##..##....##......##....##......##.....
-.##..##....##......##....##.......####..
-.##..##....##......##....##..........##.
-..####.....##....######..######...####..
-........................................
-*/
-
-fn load_image_resource_view<F, R>(factory: &mut F, rgba_image: &image::RgbaImage) -> gfx::handle::ShaderResourceView<R, [f32; 4]>
-    where F: gfx::Factory<R>, R: gfx::Resources
-{
-    let (width, height) = rgba_image.dimensions();
-    let kind = gfx::texture::Kind::D2(width as u16, height as u16, gfx::texture::AaMode::Single);
-	let mipmap = gfx::texture::Mipmap::Provided;
-	let (_, view) = factory.create_texture_immutable_u8::<ColorFormat>(kind, mipmap, &[&rgba_image]).unwrap();
-    view
-}
-
 /*
 .##...##...####...######..##..##.
 .###.###..##..##....##....###.##.
@@ -145,7 +201,10 @@ fn main() {
 
 	let width = 800.0;
 	let height = 600.0;
-	let gl_builder = glutin::ContextBuilder::new().with_vsync(true);
+	let sample_count = msaa::sample_count_from_args(std::env::args().skip(1));
+	let gl_builder = glutin::ContextBuilder::new()
+		.with_vsync(true)
+		.with_multisampling(sample_count);
 	let builder = glutin::WindowBuilder::new()
 		.with_title("Renderer".to_string())
 		.with_dimensions(LogicalSize::new(width, height));
@@ -157,13 +216,45 @@ fn main() {
 
 	let mut encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
 
-	let pipeline_state = factory
-		.create_pipeline_simple(
+	let shader_set = factory
+		.create_shader_set(
 			include_bytes!("./shaders/basic_150.glslv"),
-			include_bytes!("./shaders/basic_150.glslf"),
-			pipe::new(),
+			&shader_source(&[FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL, CLUSTERS_GLSL, FORWARD_GLSL, include_bytes!("./shaders/basic_150.glslf")]),
 		)
 		.unwrap();
+	let pipeline_state = factory
+		.create_pipeline_state(&shader_set, gfx::Primitive::TriangleList, msaa::rasterizer(sample_count), pipe::new())
+		.unwrap();
+
+	// ? Everything is rendered to a floating point target, the post-process stack brings it to the window
+	let mut post_process = PostProcessStack::new(&mut factory, main_color);
+	post_process.apply_args(&mut factory, std::env::args().skip(1));
+
+	// ? With MSAA the scene goes to a multisampled target first, resolved before the post-processing
+	let multisample_target = if sample_count > 1 {
+		Some(MultisampleTarget::new(&mut factory, sample_count, post_process.scene_target()))
+	} else {
+		None
+	};
+	let (scene_color, scene_depth) = match multisample_target {
+		Some(ref target) => (target.color.clone(), target.depth.clone()),
+		None => (post_process.scene_target(), main_depth.clone()),
+	};
+
+	let shadow_maps = ShadowMaps::new(&mut factory);
+	let light_clusters = LightClusters::new(&mut factory);
+	let (main_width, main_height, ..) = main_depth.get_dimensions();
+	let mut ssao = Ssao::new(&mut factory, main_width, main_height);
+	ssao.apply_args(std::env::args().skip(1));
+	let mut environment_map = EnvironmentMap::new(
+		&mut factory,
+		core.scene.environment.as_ref(),
+		core.scene.clear_color,
+		scene_color.clone(),
+		msaa::rasterizer(sample_count)
+	);
+
+	let mut lod_buffers = LodBuffers::new(&mut factory, &core.scene);
 
 	let empty_vertices: Vec<Vertex> = vec![];
 	let empty_indices: Vec<u32> = vec![];
@@ -171,73 +262,132 @@ fn main() {
 		&empty_vertices.as_slice(), empty_indices.as_slice()
 	);
 
-	let no_texture_rgba_image = image::open("data/NO_TEXTURE.png").unwrap().to_rgba();
-	let texture = load_image_resource_view(&mut factory, &no_texture_rgba_image);
-    let sampler = factory.create_sampler_linear();
+	let texture_library = TextureLibrary::new(&mut factory, &core.scene.texture_map);
+	let sampler = texture_library.sampler.clone();
+	let default_textures = texture_library.material_textures(None);
 
 	let mut data = pipe::Data {
 		vbuf: empty_buffer,
-		diffuse_texture: (texture, sampler),
+		ambient_texture: (default_textures.ambient, sampler.clone()),
+		diffuse_texture: (default_textures.diffuse, sampler.clone()),
+		specular_texture: (default_textures.specular, sampler.clone()),
+		normal_texture: (default_textures.normal, sampler.clone()),
+		dissolve_texture: (default_textures.dissolve, sampler.clone()),
+		metallic_texture: (default_textures.metallic, sampler.clone()),
+		roughness_texture: (default_textures.roughness, sampler.clone()),
+		emissive_texture: (default_textures.emissive, sampler.clone()),
+		occlusion_texture: (default_textures.occlusion, sampler.clone()),
 		ps_locals: factory.create_constant_buffer(1),
-		out: main_color,
-		out_depth: main_depth,
+		material_locals: factory.create_constant_buffer(1),
+		out: scene_color,
+		out_depth: scene_depth,
 		mvp: Matrix4::from_scale(1.0).into(),
 		view_model: Matrix4::from_scale(1.0).into(),
-		light_sources_info: factory.create_constant_buffer(250), // 250 = MAX_NUM_LIGHTS
+		model: Matrix4::from_scale(1.0).into(),
+		ambient_occlusion: (ssao.ambient_occlusion.clone(), sampler.clone()),
+		environment_map: (environment_map.view.clone(), environment_map.sampler.clone()),
+		prefiltered_map: (environment_map.prefiltered.clone(), environment_map.sampler.clone()),
+		brdf_lut: (environment_map.brdf_lut.clone(), environment_map.lut_sampler.clone()),
+		cluster_data: (light_clusters.data.clone(), light_clusters.sampler.clone()),
+		shadow_maps: shadow_maps.resource.clone(),
+		shadow_maps_info: factory.create_constant_buffer(MAX_SHADOW_MAPS),
+		point_shadow_maps: shadow_maps.point_resource.clone(),
+		point_shadow_maps_info: factory.create_constant_buffer(6 * MAX_POINT_SHADOW_CASTERS),
 	};
 
+	let mut deferred_renderer = DeferredRenderer::new(&mut factory, &data, &texture_library);
+	let mut transparency_renderer = TransparencyRenderer::new(&mut factory, &data, sample_count);
+	transparency_renderer.apply_args(std::env::args().skip(1));
+	let mut debug_renderer = DebugRenderer::new(&mut factory, &data, &core.scene, sample_count);
+	let mut overlay = Overlay::new(&mut factory, &core.scene, data.out.clone(), data.out_depth.clone(), sample_count);
+
 	while core.state != CoreState::Stopping {
 		events_loop.poll_events(|event| {
 			core.handle_events(&event);
+			post_process.handle_event(&event);
+			ssao.handle_event(&event);
+			transparency_renderer.handle_event(&event);
+			overlay.handle_event(&event);
 		});
+		if core.geometry_changed {
+			lod_buffers = LodBuffers::new(&mut factory, &core.scene);
+			debug_renderer.update_geometry(&mut factory, &core.scene);
+			overlay.update_geometry(&core.scene);
+			core.geometry_changed = false;
+		}
 
-		encoder.clear(&data.out, core.scene.clear_color);
-		encoder.clear_depth(&data.out_depth, 1.0);
+		// ? Level of detail of each object for this frame
+		let object_buffers = lod_buffers.select(&core.scene);
+
+		// ? Shadow pass, also assigns the shadow map layers of each light
+		let mut light_params: Vec<_> = core.scene.light_sources.iter().map(|light| light.info()).collect();
+		let shadow_frame = shadow_maps.render(&mut encoder, &core.scene, &object_buffers, &mut light_params);
+		if !shadow_frame.shadow_maps_info.is_empty() {
+			encoder.update_buffer(&data.shadow_maps_info, &shadow_frame.shadow_maps_info, 0).unwrap();
+		}
+		if !shadow_frame.point_shadow_maps_info.is_empty() {
+			encoder.update_buffer(&data.point_shadow_maps_info, &shadow_frame.point_shadow_maps_info, 0).unwrap();
+		}
 
 		// ? Update local buffer (num lights)
+		let (target_width, target_height, ..) = data.out.get_dimensions();
+		let eye_position = core.scene.camera.position();
 		let locals = ForwardLocals {
-			eye_position: [core.scene.camera.eye.x, core.scene.camera.eye.y, core.scene.camera.eye.z, 1.0],
+			eye_position: [eye_position.x, eye_position.y, eye_position.z, 1.0],
+			ambient_color: core.scene.ambient_color,
+			ibl: [if environment_map.has_environment() { core.scene.ibl_intensity } else { 0.0 }, 0.0, 0.0, 0.0],
+			cluster_scale: cluster_scale(&core.scene.camera, target_width, target_height),
 			num_lights: core.scene.light_sources.len() as i32,
 		};
 		encoder.update_buffer(&data.ps_locals, &[locals], 0).unwrap();
 
-		// ? Update light data buffer
-		let light_params: Vec<_> = core.scene.light_sources.iter().map(
-			|light| LightSourceInfo::new(
-				light.translation.into(),
-				light.color,
-			)
-		).collect();
-		encoder.update_buffer(&data.light_sources_info, &light_params, 0).unwrap();
-
-		// ? Draw object
-		for object in &core.scene.objects {
-			let view_model_matrix = core.scene.camera.view * object.model_matrix();
-			/* let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(
-				&object.vertices.as_slice(),
-				object.indices.as_slice()
-			); */
-			let vertex_buffer = factory.create_vertex_buffer(
-				&object.vertices.as_slice()
-			);
-			let slice = gfx::Slice {
-				start: 0,
-				end: vertex_buffer.len() as u32,
-				base_vertex: 0,
-				instances: None,
-				buffer: gfx::IndexBuffer::Auto
-			};
-			data.vbuf = vertex_buffer;
-			data.mvp = (core.scene.camera.projection * view_model_matrix).into();
-			data.view_model = view_model_matrix.into();
-			if let Some(object_material_id) = object.material_id {
-				if let Some(object_material) = core.scene.materials.get(object_material_id) {
-					if let Some(object_diffure_texture) = core.scene.texture_map.get(&object_material.diffuse_texture) {
-						data.diffuse_texture.0 = load_image_resource_view(&mut factory, object_diffure_texture);
-					}
+		// ? Occlusion of the ambient term, read by both render paths
+		ssao.render(&mut encoder, &core.scene, &object_buffers);
+
+		// ? Bin the lights into view space clusters, transparent objects are forward shaded on both paths
+		light_clusters.update(&mut encoder, &core.scene, &light_params, target_width, target_height);
+
+		encoder.clear(&data.out, srgb_to_linear(core.scene.clear_color));
+		encoder.clear_depth(&data.out_depth, 1.0);
+		if core.debug.view == DebugView::Shaded {
+			environment_map.render_skybox(&mut encoder, &core.scene.camera);
+		}
+
+		if core.debug.view != DebugView::Shaded {
+			// ? Every object is drawn, transparent ones included
+			debug_renderer.render_view(&mut encoder, &core.scene, &object_buffers, core.debug.view);
+		} else if core.render_path == RenderPath::Deferred {
+			deferred_renderer.render(&mut encoder, &core.scene, &object_buffers, &texture_library, &light_params);
+			transparency_renderer.render_opaque_depth(&mut encoder, &core.scene, &object_buffers);
+		} else {
+			// ? Draw opaque objects
+			for (object, &(ref vertex_buffer, ref slice)) in core.scene.objects.iter().zip(&object_buffers) {
+				if core.scene.is_transparent(object) {
+					continue;
 				}
+				let view_model_matrix = core.scene.camera.view * object.model_matrix();
+				data.vbuf = vertex_buffer.clone();
+				data.mvp = (core.scene.camera.projection * view_model_matrix).into();
+				data.view_model = view_model_matrix.into();
+				data.model = object.model_matrix().into();
+				let material = core.scene.object_material(object);
+				texture_library.material_textures(material).bind(&mut encoder, &mut data, material);
+				encoder.draw(slice, &pipeline_state, &data);
 			}
-			encoder.draw(&slice, &pipeline_state, &data);
+		}
+		if core.debug.view == DebugView::Shaded {
+			transparency_renderer.render(&mut encoder, &core.scene, &object_buffers, &texture_library, &mut data);
+		}
+		debug_renderer.render_overlays(&mut encoder, &core.scene, &object_buffers, &core.debug);
+		overlay.render(&mut encoder, &core.scene);
+
+		if let Some(ref target) = multisample_target {
+			target.resolve(&mut encoder);
+		}
+		if core.debug.view == DebugView::Shaded {
+			post_process.render(&mut encoder, &core.scene.tone_mapping);
+		} else {
+			post_process.present_unprocessed(&mut encoder);
 		}
 
 		encoder.flush(&mut device);
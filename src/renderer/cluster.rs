@@ -0,0 +1,174 @@
+use cgmath::Vector4;
+use gfx;
+
+use common::*;
+use renderer::light_scissor;
+use scene::camera::Camera;
+use scene::light_source::LightSource;
+use scene::Scene;
+
+/*
+..####...##......##..##...####...######..######..#####....####..
+.##..##..##......##..##..##........##....##......##..##..##.....
+.##......##......##..##...####.....##....####....#####....####..
+.##..##..##......##..##......##....##....##......##..##......##.
+..####...######...####....####.....##....######..##..##...####..
+................................................................
+*/
+
+// ? Keep in sync with clusters_150.glsl
+pub const CLUSTER_TILES_X: usize = 16;
+pub const CLUSTER_TILES_Y: usize = 9;
+pub const CLUSTER_SLICES: usize = 24;
+pub const CLUSTER_COUNT: usize = CLUSTER_TILES_X * CLUSTER_TILES_Y * CLUSTER_SLICES;
+
+// ? The light data, the cluster grid and the light index lists share one texture, each in its own band of rows,
+// ? so that the forward pipeline only spends one sampler on them. Keep in sync with clusters_150.glsl.
+const CLUSTER_DATA_WIDTH: usize = 1024;
+
+// ? One texel per LightSourceInfo field, the lights follow each other along the rows
+pub const MAX_CLUSTERED_LIGHTS: usize = 4096;
+const LIGHT_TEXELS: usize = 6;
+const LIGHT_FIRST_ROW: usize = 0;
+const LIGHT_ROWS: usize = (MAX_CLUSTERED_LIGHTS * LIGHT_TEXELS + CLUSTER_DATA_WIDTH - 1) / CLUSTER_DATA_WIDTH;
+
+// ? Offset into the index list and light count of each cluster, in the order of ClusterFrame::grid
+const GRID_FIRST_ROW: usize = LIGHT_FIRST_ROW + LIGHT_ROWS;
+const GRID_ROWS: usize = (CLUSTER_COUNT + CLUSTER_DATA_WIDTH - 1) / CLUSTER_DATA_WIDTH;
+
+// ? Light indices are packed four per texel
+const INDEX_FIRST_ROW: usize = GRID_FIRST_ROW + GRID_ROWS;
+const INDEX_ROWS: usize = 64;
+pub const MAX_LIGHT_INDICES: usize = 4 * CLUSTER_DATA_WIDTH * INDEX_ROWS;
+
+const CLUSTER_DATA_HEIGHT: usize = INDEX_FIRST_ROW + INDEX_ROWS;
+
+pub type ClusterFormat = gfx::format::Rgba32F;
+type ClusterSurface = gfx::format::R32_G32_B32_A32;
+
+// ? Per frame light lists, built on the CPU
+pub struct ClusterFrame {
+	// ? Offset into the index list and light count of each cluster, tile rows first, then depth slices
+	pub grid: Vec<[f32; 4]>,
+	pub indices: Vec<f32>,
+}
+
+pub struct LightClusters<R: gfx::Resources> {
+	texture: gfx::handle::Texture<R, ClusterSurface>,
+	pub data: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub sampler: gfx::handle::Sampler<R>,
+}
+
+impl<R: gfx::Resources> LightClusters<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F) -> Self {
+		let kind = gfx::texture::Kind::D2(CLUSTER_DATA_WIDTH as u16, CLUSTER_DATA_HEIGHT as u16, gfx::texture::AaMode::Single);
+		let texture = factory.create_texture(kind, 1, gfx::memory::Bind::SHADER_RESOURCE, gfx::memory::Usage::Dynamic, Some(gfx::format::ChannelType::Float)).unwrap();
+		let data = factory.view_texture_as_shader_resource::<ClusterFormat>(&texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+		// ? Everything is read with texelFetch, no filtering
+		let sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+		LightClusters {
+			texture,
+			data,
+			sampler,
+		}
+	}
+
+	// ? Bins the lights of the scene and uploads the light data and the per-cluster lists
+	pub fn update<C: gfx::CommandBuffer<R>>(&self, encoder: &mut gfx::Encoder<R, C>, scene: &Scene, light_infos: &[LightSourceInfo], width: u16, height: u16) {
+		let light_count = light_infos.len().min(MAX_CLUSTERED_LIGHTS);
+		if light_count < light_infos.len() {
+			println!("Too many lights for the clusters, only the first {} are rendered", MAX_CLUSTERED_LIGHTS);
+		}
+		let frame = bin_lights(&scene.light_sources[..light_count], &scene.camera, width, height);
+
+		if light_count > 0 {
+			let light_texels: Vec<[f32; 4]> = light_infos[..light_count].iter().flat_map(|info| info.texels().to_vec()).collect();
+			self.update_rows(encoder, light_texels, LIGHT_FIRST_ROW);
+		}
+		self.update_rows(encoder, frame.grid, GRID_FIRST_ROW);
+		if !frame.indices.is_empty() {
+			let index_texels: Vec<[f32; 4]> = frame.indices.chunks(4).map(|chunk| {
+				let mut texel = [0.0; 4];
+				texel[..chunk.len()].copy_from_slice(chunk);
+				texel
+			}).collect();
+			self.update_rows(encoder, index_texels, INDEX_FIRST_ROW);
+		}
+	}
+
+	// ? Texels laid out row after row from `first_row`, the last row is padded
+	fn update_rows<C: gfx::CommandBuffer<R>>(&self, encoder: &mut gfx::Encoder<R, C>, mut texels: Vec<[f32; 4]>, first_row: usize) {
+		let rows = (texels.len() + CLUSTER_DATA_WIDTH - 1) / CLUSTER_DATA_WIDTH;
+		texels.resize(rows * CLUSTER_DATA_WIDTH, [0.0; 4]);
+		let mut image_info = self.texture.get_info().to_image_info(0);
+		image_info.yoffset = first_row as u16;
+		image_info.height = rows as u16;
+		encoder.update_texture::<ClusterSurface, ClusterFormat>(&self.texture, None, image_info, gfx::memory::cast_slice(&texels)).unwrap();
+	}
+}
+
+// ? Tile size in pixels and the logarithmic slice distribution, see cluster_coordinates in clusters_150.glsl
+pub fn cluster_scale(camera: &Camera, width: u16, height: u16) -> [f32; 4] {
+	let log_depth_range = (camera.far / camera.near).ln();
+	[
+		width as f32 / CLUSTER_TILES_X as f32,
+		height as f32 / CLUSTER_TILES_Y as f32,
+		CLUSTER_SLICES as f32 / log_depth_range,
+		-(CLUSTER_SLICES as f32) * camera.near.ln() / log_depth_range,
+	]
+}
+
+fn depth_slice(camera: &Camera, view_depth: f32) -> usize {
+	let depth = view_depth.max(camera.near).min(camera.far);
+	let slice = ((depth / camera.near).ln() / (camera.far / camera.near).ln() * CLUSTER_SLICES as f32).floor();
+	(slice.max(0.0) as usize).min(CLUSTER_SLICES - 1)
+}
+
+// ? Conservative assignment: each light goes to every cluster its bounding box touches on screen,
+// ? between the view depths of its bounding sphere. Lights without a range touch every cluster.
+pub fn bin_lights(lights: &[LightSource], camera: &Camera, width: u16, height: u16) -> ClusterFrame {
+	let mut cluster_lights: Vec<Vec<usize>> = vec![vec![]; CLUSTER_COUNT];
+	let tile_width = width as f32 / CLUSTER_TILES_X as f32;
+	let tile_height = height as f32 / CLUSTER_TILES_Y as f32;
+
+	for (index, light) in lights.iter().enumerate() {
+		let rect = match light_scissor(light, camera, width, height) {
+			Some(rect) => rect,
+			None => continue,
+		};
+		let (first_slice, last_slice) = match light.range() {
+			Some(range) => {
+				let center: Vector4<f32> = camera.view * light.translation.extend(1.0);
+				let (near, far) = (-center.z - range, -center.z + range);
+				if far < camera.near || near > camera.far {
+					continue;
+				}
+				(depth_slice(camera, near), depth_slice(camera, far))
+			},
+			None => (0, CLUSTER_SLICES - 1),
+		};
+		let first_x = (rect.x as f32 / tile_width).floor() as usize;
+		let last_x = ((((rect.x + rect.w) as f32 / tile_width).ceil() as usize).max(first_x + 1) - 1).min(CLUSTER_TILES_X - 1);
+		let first_y = (rect.y as f32 / tile_height).floor() as usize;
+		let last_y = ((((rect.y + rect.h) as f32 / tile_height).ceil() as usize).max(first_y + 1) - 1).min(CLUSTER_TILES_Y - 1);
+		for slice in first_slice..=last_slice {
+			for y in first_y..=last_y {
+				for x in first_x..=last_x {
+					cluster_lights[(slice * CLUSTER_TILES_Y + y) * CLUSTER_TILES_X + x].push(index);
+				}
+			}
+		}
+	}
+
+	let mut grid = Vec::with_capacity(CLUSTER_COUNT);
+	let mut indices = vec![];
+	for lights in &cluster_lights {
+		let offset = indices.len();
+		let count = lights.len().min(MAX_LIGHT_INDICES - offset);
+		indices.extend(lights[..count].iter().map(|&index| index as f32));
+		grid.push([offset as f32, count as f32, 0.0, 0.0]);
+	}
+	ClusterFrame { grid, indices }
+}
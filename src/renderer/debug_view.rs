@@ -0,0 +1,344 @@
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use renderer::{create_screen_triangle, msaa, shader_source, ScreenVertex};
+use scene::Scene;
+
+/*
+.#####...######..#####...##..##...####...
+.##..##..##......##..##..##..##..##......
+.##..##..####....#####...##..##..##.###..
+.##..##..##......##..##..##..##..##..##..
+.#####...######..#####....####....####...
+.........................................
+*/
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugView {
+	// ? The regular rendering
+	Shaded,
+	// ? World space normals, mapped from [-1, 1] to [0, 1]
+	Normals,
+	// ? UV coordinates over a checkerboard, shows stretching and seams
+	Uvs,
+	// ? Linear view depth between the camera planes, brighter is closer
+	Depth,
+	// ? A flat color per material
+	Materials,
+	// ? Fragments rasterized per pixel, depth testing disabled
+	Overdraw,
+}
+
+impl DebugView {
+	pub fn next(&self) -> Self {
+		match *self {
+			DebugView::Shaded => DebugView::Normals,
+			DebugView::Normals => DebugView::Uvs,
+			DebugView::Uvs => DebugView::Depth,
+			DebugView::Depth => DebugView::Materials,
+			DebugView::Materials => DebugView::Overdraw,
+			DebugView::Overdraw => DebugView::Shaded,
+		}
+	}
+
+	// ? Keep in sync with debug_150.glslf
+	pub fn shader_id(&self) -> i32 {
+		match *self {
+			DebugView::Shaded => 0,
+			DebugView::Normals => 1,
+			DebugView::Uvs => 2,
+			DebugView::Depth => 3,
+			DebugView::Materials => 4,
+			DebugView::Overdraw => 5,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DebugSettings {
+	pub view: DebugView,
+	pub wireframe: bool,
+	pub vertex_normals: bool,
+}
+
+impl Default for DebugSettings {
+	fn default() -> Self {
+		DebugSettings {
+			view: DebugView::Shaded,
+			wireframe: false,
+			vertex_normals: false,
+		}
+	}
+}
+
+// ? Spreads material indices over the hue circle so neighbours stand apart
+pub fn material_color(material_id: Option<usize>) -> [f32; 4] {
+	let index = match material_id {
+		Some(index) => index,
+		None => return [0.5, 0.5, 0.5, 1.0],
+	};
+	let hue = (index as f32 * 0.618_034).fract() * 6.0;
+	let x = 1.0 - (hue % 2.0 - 1.0).abs();
+	let (r, g, b) = match hue as u32 {
+		0 => (1.0, x, 0.0),
+		1 => (x, 1.0, 0.0),
+		2 => (0.0, 1.0, x),
+		3 => (0.0, x, 1.0),
+		4 => (x, 0.0, 1.0),
+		_ => (1.0, 0.0, x),
+	};
+	[r, g, b, 1.0]
+}
+
+const NORMAL_LINE_COLOR: [f32; 3] = [0.2, 0.6, 1.0];
+// ? Length of the normal lines relative to the diagonal of the object's bounds
+const NORMAL_LINE_SCALE: f32 = 0.02;
+
+pub type OverdrawFormat = gfx::format::Rgba16F;
+
+gfx_defines!{
+	vertex LineVertex {
+		pos: [f32; 4] = "v_pos",
+		color: [f32; 3] = "v_color",
+	}
+
+	constant DebugLocals {
+		material_color: [f32; 4] = "u_MaterialColor",
+		depth_range: [f32; 4] = "u_DepthRange",
+		mode: i32 = "u_DebugMode",
+	}
+
+	pipeline debug_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		locals: gfx::ConstantBuffer<DebugLocals> = "DebugLocals",
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+	}
+
+	// ? Counts fragments, every one of them adds 1 to the target
+	pipeline overdraw_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		locals: gfx::ConstantBuffer<DebugLocals> = "DebugLocals",
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
+		out: gfx::BlendTarget<OverdrawFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ADD),
+	}
+
+	pipeline heatmap_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		overdraw: gfx::TextureSampler<[f32; 4]> = "u_Overdraw",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+	}
+
+	// ? Object edges drawn over the scene, the vertex colors are white
+	pipeline wireframe_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
+	}
+
+	pipeline line_pipe {
+		vbuf: gfx::VertexBuffer<LineVertex> = (),
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
+	}
+}
+
+// ? One line per vertex, from the vertex along its normal
+fn create_normal_lines(object_vertices: &[Vertex], length: f32) -> Vec<LineVertex> {
+	let mut lines = Vec::with_capacity(object_vertices.len() * 2);
+	for vertex in object_vertices {
+		let position = vertex.position();
+		let normal = vertex.normal();
+		lines.push(LineVertex { pos: [position[0], position[1], position[2], 1.0], color: NORMAL_LINE_COLOR });
+		lines.push(LineVertex {
+			pos: [position[0] + normal[0] * length, position[1] + normal[1] * length, position[2] + normal[2] * length, 1.0],
+			color: NORMAL_LINE_COLOR,
+		});
+	}
+	lines
+}
+
+pub struct DebugRenderer<R: gfx::Resources> {
+	debug_pipeline_state: gfx::PipelineState<R, debug_pipe::Meta>,
+	overdraw_pipeline_state: gfx::PipelineState<R, overdraw_pipe::Meta>,
+	heatmap_pipeline_state: gfx::PipelineState<R, heatmap_pipe::Meta>,
+	wireframe_pipeline_state: gfx::PipelineState<R, wireframe_pipe::Meta>,
+	line_pipeline_state: gfx::PipelineState<R, line_pipe::Meta>,
+	debug_data: debug_pipe::Data<R>,
+	overdraw_data: overdraw_pipe::Data<R>,
+	heatmap_data: heatmap_pipe::Data<R>,
+	wireframe_data: wireframe_pipe::Data<R>,
+	line_data: line_pipe::Data<R>,
+	normal_lines: Vec<(gfx::handle::Buffer<R, LineVertex>, gfx::Slice<R>)>,
+	screen_slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> DebugRenderer<R> {
+	// ? Draws into the forward pipeline's targets, `update_geometry` has to be called when object geometry changes
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, forward_data: &pipe::Data<R>, scene: &Scene, sample_count: u16) -> Self {
+		let rasterizer = msaa::rasterizer(sample_count);
+		let debug_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/basic_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/debug_150.glslf")])
+		).unwrap();
+		let debug_pipeline_state = factory.create_pipeline_state(&debug_shader_set, gfx::Primitive::TriangleList, rasterizer, debug_pipe::new()).unwrap();
+		// ? The overdraw count is single sampled, it is read texel for texel by the heatmap
+		let overdraw_pipeline_state = factory.create_pipeline_state(
+			&debug_shader_set,
+			gfx::Primitive::TriangleList,
+			gfx::state::Rasterizer::new_fill(),
+			overdraw_pipe::new()
+		).unwrap();
+		let heatmap_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/overdraw_heatmap_150.glslf")])
+		).unwrap();
+		let heatmap_pipeline_state = factory.create_pipeline_state(&heatmap_shader_set, gfx::Primitive::TriangleList, rasterizer, heatmap_pipe::new()).unwrap();
+		let line_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/line_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/line_150.glslf")])
+		).unwrap();
+		// ? Pulled towards the camera so the edges win the depth test against their own faces
+		let mut wireframe_rasterizer = rasterizer;
+		wireframe_rasterizer.method = gfx::state::RasterMethod::Line(1);
+		wireframe_rasterizer.offset = Some(gfx::state::Offset(-1, -1));
+		let wireframe_pipeline_state = factory.create_pipeline_state(&line_shader_set, gfx::Primitive::TriangleList, wireframe_rasterizer, wireframe_pipe::new()).unwrap();
+		let line_pipeline_state = factory.create_pipeline_state(&line_shader_set, gfx::Primitive::LineList, rasterizer, line_pipe::new()).unwrap();
+
+		let normal_lines = DebugRenderer::create_object_normal_lines(factory, scene);
+
+		let (width, height, ..) = forward_data.out.get_dimensions();
+		let (_, overdraw_view, overdraw_target) = factory.create_render_target::<OverdrawFormat>(width, height).unwrap();
+		let point_sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+		let (screen_buffer, screen_slice) = create_screen_triangle(factory);
+		let locals = factory.create_constant_buffer(1);
+		let empty_lines: Vec<LineVertex> = vec![];
+
+		DebugRenderer {
+			debug_pipeline_state,
+			overdraw_pipeline_state,
+			heatmap_pipeline_state,
+			wireframe_pipeline_state,
+			line_pipeline_state,
+			debug_data: debug_pipe::Data {
+				vbuf: forward_data.vbuf.clone(),
+				locals: locals.clone(),
+				mvp: forward_data.mvp,
+				view_model: forward_data.view_model,
+				model: forward_data.model,
+				out: forward_data.out.clone(),
+				out_depth: forward_data.out_depth.clone(),
+			},
+			overdraw_data: overdraw_pipe::Data {
+				vbuf: forward_data.vbuf.clone(),
+				locals,
+				mvp: forward_data.mvp,
+				view_model: forward_data.view_model,
+				model: forward_data.model,
+				out: overdraw_target,
+			},
+			heatmap_data: heatmap_pipe::Data {
+				vbuf: screen_buffer,
+				overdraw: (overdraw_view, point_sampler),
+				out: forward_data.out.clone(),
+			},
+			wireframe_data: wireframe_pipe::Data {
+				vbuf: forward_data.vbuf.clone(),
+				mvp: forward_data.mvp,
+				out: forward_data.out.clone(),
+				out_depth: forward_data.out_depth.clone(),
+			},
+			line_data: line_pipe::Data {
+				vbuf: factory.create_vertex_buffer(&empty_lines),
+				mvp: forward_data.mvp,
+				out: forward_data.out.clone(),
+				out_depth: forward_data.out_depth.clone(),
+			},
+			normal_lines,
+			screen_slice,
+		}
+	}
+
+	pub fn update_geometry<F: gfx::Factory<R>>(&mut self, factory: &mut F, scene: &Scene) {
+		self.normal_lines = DebugRenderer::create_object_normal_lines(factory, scene);
+	}
+
+	fn create_object_normal_lines<F: gfx::Factory<R>>(factory: &mut F, scene: &Scene) -> Vec<(gfx::handle::Buffer<R, LineVertex>, gfx::Slice<R>)> {
+		scene.objects.iter().map(|object| {
+			let (min, max) = object.bounds();
+			let length = ((max - min).x.powi(2) + (max - min).y.powi(2) + (max - min).z.powi(2)).sqrt() * NORMAL_LINE_SCALE;
+			factory.create_vertex_buffer_with_slice(&create_normal_lines(&object.vertices, length), ())
+		}).collect()
+	}
+
+	// ? Replaces the shaded objects, expects the targets to be cleared
+	pub fn render_view<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)],
+		view: DebugView
+	) {
+		if view == DebugView::Overdraw {
+			encoder.clear(&self.overdraw_data.out, [0.0; 4]);
+		}
+		for (object, &(ref vertex_buffer, ref slice)) in scene.objects.iter().zip(object_buffers) {
+			let view_model_matrix = scene.camera.view * object.model_matrix();
+			let locals = DebugLocals {
+				material_color: material_color(object.material_id),
+				depth_range: [scene.camera.near, scene.camera.far, 0.0, 0.0],
+				mode: view.shader_id(),
+			};
+			encoder.update_buffer(&self.debug_data.locals, &[locals], 0).unwrap();
+			if view == DebugView::Overdraw {
+				self.overdraw_data.vbuf = vertex_buffer.clone();
+				self.overdraw_data.mvp = (scene.camera.projection * view_model_matrix).into();
+				self.overdraw_data.view_model = view_model_matrix.into();
+				self.overdraw_data.model = object.model_matrix().into();
+				encoder.draw(slice, &self.overdraw_pipeline_state, &self.overdraw_data);
+			} else {
+				self.debug_data.vbuf = vertex_buffer.clone();
+				self.debug_data.mvp = (scene.camera.projection * view_model_matrix).into();
+				self.debug_data.view_model = view_model_matrix.into();
+				self.debug_data.model = object.model_matrix().into();
+				encoder.draw(slice, &self.debug_pipeline_state, &self.debug_data);
+			}
+		}
+		if view == DebugView::Overdraw {
+			encoder.draw(&self.screen_slice, &self.heatmap_pipeline_state, &self.heatmap_data);
+		}
+	}
+
+	// ? Wireframe and vertex normals, drawn over whatever view is active
+	pub fn render_overlays<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)],
+		settings: &DebugSettings
+	) {
+		for ((object, &(ref vertex_buffer, ref slice)), &(ref line_buffer, ref line_slice)) in scene.objects.iter().zip(object_buffers).zip(&self.normal_lines) {
+			let mvp = (scene.camera.vp_matrix() * object.model_matrix()).into();
+			if settings.wireframe {
+				self.wireframe_data.vbuf = vertex_buffer.clone();
+				self.wireframe_data.mvp = mvp;
+				encoder.draw(slice, &self.wireframe_pipeline_state, &self.wireframe_data);
+			}
+			if settings.vertex_normals {
+				self.line_data.vbuf = line_buffer.clone();
+				self.line_data.mvp = mvp;
+				encoder.draw(line_slice, &self.line_pipeline_state, &self.line_data);
+			}
+		}
+	}
+}
@@ -0,0 +1,249 @@
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use renderer::{create_screen_triangle, full_screen_rect, light_scissor, shader_source, ScreenVertex, TextureLibrary, FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL};
+use scene::Scene;
+
+/*
+.#####...######..######..######..#####...#####...######..#####..
+.##..##..##......##......##......##..##..##..##..##......##..##.
+.##..##..####....####....####....#####...#####...####....##..##.
+.##..##..##......##......##......##..##..##..##..##......##..##.
+.#####...######..##......######..##..##..##..##..######..#####..
+................................................................
+*/
+
+pub type GBufferFormat = gfx::format::Rgba16F;
+
+// ? G-buffer layout:
+// ?   albedo: Kd or base color, occlusion
+// ?   normal: world normal after normal mapping, shininess
+// ?   position: world position, view depth (0 where nothing was drawn)
+// ?   material: Ks or (metallic, roughness, 0), shading model (-1 = PBR, otherwise the MTL illum)
+// ?   emissive: emitted color for PBR, Kd for illum 0, Ka for the other MTL models
+gfx_defines!{
+	pipeline gbuffer_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		ambient_texture: gfx::TextureSampler<[f32; 4]> = "u_AmbientTexture",
+		diffuse_texture: gfx::TextureSampler<[f32; 4]> = "u_DiffuseTexture",
+		specular_texture: gfx::TextureSampler<[f32; 4]> = "u_SpecularTexture",
+		normal_texture: gfx::TextureSampler<[f32; 4]> = "u_NormalTexture",
+		dissolve_texture: gfx::TextureSampler<[f32; 4]> = "u_DissolveTexture",
+		metallic_texture: gfx::TextureSampler<[f32; 4]> = "u_MetallicTexture",
+		roughness_texture: gfx::TextureSampler<[f32; 4]> = "u_RoughnessTexture",
+		emissive_texture: gfx::TextureSampler<[f32; 4]> = "u_EmissiveTexture",
+		occlusion_texture: gfx::TextureSampler<[f32; 4]> = "u_OcclusionTexture",
+		material_locals: gfx::ConstantBuffer<MaterialLocals> = "MaterialLocals",
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
+		out_albedo: gfx::RenderTarget<GBufferFormat> = "Target0",
+		out_normal: gfx::RenderTarget<GBufferFormat> = "Target1",
+		out_position: gfx::RenderTarget<GBufferFormat> = "Target2",
+		out_material: gfx::RenderTarget<GBufferFormat> = "Target3",
+		out_emissive: gfx::RenderTarget<GBufferFormat> = "Target4",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+	}
+
+	pipeline deferred_light_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		gbuffer_albedo: gfx::TextureSampler<[f32; 4]> = "u_GBufferAlbedo",
+		gbuffer_normal: gfx::TextureSampler<[f32; 4]> = "u_GBufferNormal",
+		gbuffer_position: gfx::TextureSampler<[f32; 4]> = "u_GBufferPosition",
+		gbuffer_material: gfx::TextureSampler<[f32; 4]> = "u_GBufferMaterial",
+		ps_locals: gfx::ConstantBuffer<ForwardLocals> = "Locals",
+		ambient_occlusion: gfx::TextureSampler<[f32; 4]> = "u_AmbientOcclusion",
+		environment_map: gfx::TextureSampler<[f32; 4]> = "u_EnvironmentMap",
+		prefiltered_map: gfx::TextureSampler<[f32; 4]> = "u_PrefilteredMap",
+		brdf_lut: gfx::TextureSampler<[f32; 4]> = "u_BrdfLut",
+		light: gfx::ConstantBuffer<LightSourceInfo> = "b_light",
+		shadow_maps: gfx::TextureSampler<f32> = "u_ShadowMaps",
+		shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_shadow_maps",
+		point_shadow_maps: gfx::TextureSampler<f32> = "u_PointShadowMaps",
+		point_shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_point_shadow_maps",
+		scissor: gfx::Scissor = (),
+		out: gfx::BlendTarget<GBufferFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ADD),
+	}
+
+	pipeline deferred_compose_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		light_accumulation: gfx::TextureSampler<[f32; 4]> = "u_LightAccumulation",
+		gbuffer_albedo: gfx::TextureSampler<[f32; 4]> = "u_GBufferAlbedo",
+		gbuffer_normal: gfx::TextureSampler<[f32; 4]> = "u_GBufferNormal",
+		gbuffer_position: gfx::TextureSampler<[f32; 4]> = "u_GBufferPosition",
+		gbuffer_material: gfx::TextureSampler<[f32; 4]> = "u_GBufferMaterial",
+		gbuffer_emissive: gfx::TextureSampler<[f32; 4]> = "u_GBufferEmissive",
+		ps_locals: gfx::ConstantBuffer<ForwardLocals> = "Locals",
+		ambient_occlusion: gfx::TextureSampler<[f32; 4]> = "u_AmbientOcclusion",
+		environment_map: gfx::TextureSampler<[f32; 4]> = "u_EnvironmentMap",
+		prefiltered_map: gfx::TextureSampler<[f32; 4]> = "u_PrefilteredMap",
+		brdf_lut: gfx::TextureSampler<[f32; 4]> = "u_BrdfLut",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+	}
+}
+
+pub struct DeferredRenderer<R: gfx::Resources> {
+	width: u16,
+	height: u16,
+	gbuffer_pipeline_state: gfx::PipelineState<R, gbuffer_pipe::Meta>,
+	light_pipeline_state: gfx::PipelineState<R, deferred_light_pipe::Meta>,
+	compose_pipeline_state: gfx::PipelineState<R, deferred_compose_pipe::Meta>,
+	gbuffer_data: gbuffer_pipe::Data<R>,
+	light_data: deferred_light_pipe::Data<R>,
+	compose_data: deferred_compose_pipe::Data<R>,
+	screen_slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> DeferredRenderer<R> {
+	// ? The light pass shares the per-frame locals and shadow resources of the forward pipeline
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, forward_data: &pipe::Data<R>, texture_library: &TextureLibrary<R>) -> Self {
+		let (width, height, ..) = forward_data.out.get_dimensions();
+
+		let gbuffer_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/basic_150.glslv"),
+			&shader_source(&[MATERIAL_GLSL, include_bytes!("../shaders/gbuffer_150.glslf")]),
+			gbuffer_pipe::new()
+		).unwrap();
+		let light_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[FRAME_GLSL, LIGHTING_GLSL, include_bytes!("../shaders/deferred_light_150.glslf")]),
+			deferred_light_pipe::new()
+		).unwrap();
+		let compose_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[FRAME_GLSL, include_bytes!("../shaders/deferred_compose_150.glslf")]),
+			deferred_compose_pipe::new()
+		).unwrap();
+
+		let (_, albedo_view, albedo_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let (_, normal_view, normal_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let (_, position_view, position_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let (_, material_view, material_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let (_, emissive_view, emissive_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let (_, accumulation_view, accumulation_target) = factory.create_render_target::<GBufferFormat>(width, height).unwrap();
+		let depth = factory.create_depth_stencil_view_only::<DepthFormat>(width, height).unwrap();
+
+		// ? The G-buffer is read texel for texel, no filtering
+		let point_sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+		let (screen_buffer, screen_slice) = create_screen_triangle(factory);
+		let textures = texture_library.material_textures(None);
+		let sampler = texture_library.sampler.clone();
+
+		let gbuffer_data = gbuffer_pipe::Data {
+			vbuf: forward_data.vbuf.clone(),
+			ambient_texture: (textures.ambient, sampler.clone()),
+			diffuse_texture: (textures.diffuse, sampler.clone()),
+			specular_texture: (textures.specular, sampler.clone()),
+			normal_texture: (textures.normal, sampler.clone()),
+			dissolve_texture: (textures.dissolve, sampler.clone()),
+			metallic_texture: (textures.metallic, sampler.clone()),
+			roughness_texture: (textures.roughness, sampler.clone()),
+			emissive_texture: (textures.emissive, sampler.clone()),
+			occlusion_texture: (textures.occlusion, sampler),
+			material_locals: forward_data.material_locals.clone(),
+			mvp: forward_data.mvp,
+			view_model: forward_data.view_model,
+			model: forward_data.model,
+			out_albedo: albedo_target,
+			out_normal: normal_target,
+			out_position: position_target,
+			out_material: material_target,
+			out_emissive: emissive_target,
+			out_depth: depth,
+		};
+		let light_data = deferred_light_pipe::Data {
+			vbuf: screen_buffer.clone(),
+			gbuffer_albedo: (albedo_view.clone(), point_sampler.clone()),
+			gbuffer_normal: (normal_view.clone(), point_sampler.clone()),
+			gbuffer_position: (position_view.clone(), point_sampler.clone()),
+			gbuffer_material: (material_view.clone(), point_sampler.clone()),
+			ps_locals: forward_data.ps_locals.clone(),
+			ambient_occlusion: forward_data.ambient_occlusion.clone(),
+			environment_map: forward_data.environment_map.clone(),
+			prefiltered_map: forward_data.prefiltered_map.clone(),
+			brdf_lut: forward_data.brdf_lut.clone(),
+			light: factory.create_constant_buffer(1),
+			shadow_maps: forward_data.shadow_maps.clone(),
+			shadow_maps_info: forward_data.shadow_maps_info.clone(),
+			point_shadow_maps: forward_data.point_shadow_maps.clone(),
+			point_shadow_maps_info: forward_data.point_shadow_maps_info.clone(),
+			scissor: full_screen_rect(width, height),
+			out: accumulation_target,
+		};
+		let compose_data = deferred_compose_pipe::Data {
+			vbuf: screen_buffer,
+			light_accumulation: (accumulation_view, point_sampler.clone()),
+			gbuffer_albedo: (albedo_view, point_sampler.clone()),
+			gbuffer_normal: (normal_view, point_sampler.clone()),
+			gbuffer_position: (position_view, point_sampler.clone()),
+			gbuffer_material: (material_view, point_sampler.clone()),
+			gbuffer_emissive: (emissive_view, point_sampler),
+			ps_locals: forward_data.ps_locals.clone(),
+			ambient_occlusion: forward_data.ambient_occlusion.clone(),
+			environment_map: forward_data.environment_map.clone(),
+			prefiltered_map: forward_data.prefiltered_map.clone(),
+			brdf_lut: forward_data.brdf_lut.clone(),
+			out: forward_data.out.clone(),
+		};
+
+		DeferredRenderer {
+			width,
+			height,
+			gbuffer_pipeline_state,
+			light_pipeline_state,
+			compose_pipeline_state,
+			gbuffer_data,
+			light_data,
+			compose_data,
+			screen_slice,
+		}
+	}
+
+	// ? Expects the forward locals and shadow buffers to be up to date for this frame and the target's background to be drawn
+	pub fn render<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)],
+		texture_library: &TextureLibrary<R>,
+		light_infos: &[LightSourceInfo]
+	) {
+		// ? Geometry pass
+		encoder.clear(&self.gbuffer_data.out_albedo, [0.0; 4]);
+		encoder.clear(&self.gbuffer_data.out_normal, [0.0; 4]);
+		encoder.clear(&self.gbuffer_data.out_position, [0.0; 4]);
+		encoder.clear(&self.gbuffer_data.out_material, [0.0; 4]);
+		encoder.clear(&self.gbuffer_data.out_emissive, [0.0; 4]);
+		encoder.clear_depth(&self.gbuffer_data.out_depth, 1.0);
+		for (object, &(ref vertex_buffer, ref slice)) in scene.objects.iter().zip(object_buffers) {
+			// ? The G-buffer holds a single surface per pixel, transparent objects are blended afterwards
+			if scene.is_transparent(object) {
+				continue;
+			}
+			let view_model_matrix = scene.camera.view * object.model_matrix();
+			let material = scene.object_material(object);
+			self.gbuffer_data.vbuf = vertex_buffer.clone();
+			self.gbuffer_data.mvp = (scene.camera.projection * view_model_matrix).into();
+			self.gbuffer_data.view_model = view_model_matrix.into();
+			self.gbuffer_data.model = object.model_matrix().into();
+			texture_library.material_textures(material).bind(encoder, &mut self.gbuffer_data, material);
+			encoder.draw(slice, &self.gbuffer_pipeline_state, &self.gbuffer_data);
+		}
+
+		// ? Lighting pass, one additive full-screen draw per light clipped to the light's screen bounds
+		encoder.clear(&self.light_data.out, [0.0; 4]);
+		for (light, light_info) in scene.light_sources.iter().zip(light_infos) {
+			if let Some(scissor) = light_scissor(light, &scene.camera, self.width, self.height) {
+				self.light_data.scissor = scissor;
+				encoder.update_buffer(&self.light_data.light, &[*light_info], 0).unwrap();
+				encoder.draw(&self.screen_slice, &self.light_pipeline_state, &self.light_data);
+			}
+		}
+
+		// ? Emissive and ambient, pixels without geometry keep the background the caller cleared or drew
+		encoder.draw(&self.screen_slice, &self.compose_pipeline_state, &self.compose_data);
+	}
+}
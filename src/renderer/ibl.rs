@@ -0,0 +1,274 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+
+use cgmath::{vec3, InnerSpace, Vector3};
+
+use common::cache::{cache_path, read_cache, write_cache, CacheReader, CacheWriter};
+use scene::environment::{texel_direction, Environment};
+
+/*
+.######..#####...##.....
+...##....##..##..##.....
+...##....#####...##.....
+...##....##..##..##.....
+.######..#####...######.
+........................
+*/
+
+// ? Keep in sync with frame_150.glsl
+pub const PREFILTERED_MIP_LEVELS: usize = 5;
+pub const PREFILTERED_SIZE: u32 = 128;
+// ? The irradiance is uploaded as the mip level after the prefiltered ones, it is smooth enough for the size
+pub const IRRADIANCE_SIZE: u32 = PREFILTERED_SIZE >> PREFILTERED_MIP_LEVELS;
+pub const BRDF_LUT_SIZE: u32 = 64;
+
+const IRRADIANCE_SOURCE_SIZE: u32 = 32;
+const IRRADIANCE_SAMPLES: u32 = 256;
+const PREFILTER_SAMPLES: u32 = 64;
+const BRDF_SAMPLES: u32 = 256;
+
+// ? Bump when the generated data changes, old cache files are then ignored
+const CACHE_VERSION: u32 = 2;
+
+// ? Low discrepancy sample i of n
+fn hammersley(i: u32, n: u32) -> (f32, f32) {
+	let mut bits = i;
+	bits = (bits << 16) | (bits >> 16);
+	bits = ((bits & 0x55555555) << 1) | ((bits & 0xAAAAAAAA) >> 1);
+	bits = ((bits & 0x33333333) << 2) | ((bits & 0xCCCCCCCC) >> 2);
+	bits = ((bits & 0x0F0F0F0F) << 4) | ((bits & 0xF0F0F0F0) >> 4);
+	bits = ((bits & 0x00FF00FF) << 8) | ((bits & 0xFF00FF00) >> 8);
+	(i as f32 / n as f32, bits as f32 * 2.328_306_4e-10)
+}
+
+fn to_world(local: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+	let up = if normal.z.abs() < 0.999 { vec3(0.0, 0.0, 1.0) } else { vec3(1.0, 0.0, 0.0) };
+	let tangent = up.cross(normal).normalize();
+	let bitangent = normal.cross(tangent);
+	(tangent * local.x + bitangent * local.y + normal * local.z).normalize()
+}
+
+fn cosine_sample(xi: (f32, f32), normal: Vector3<f32>) -> Vector3<f32> {
+	let phi = 2.0 * PI * xi.0;
+	let radius = xi.1.sqrt();
+	to_world(vec3(radius * phi.cos(), radius * phi.sin(), (1.0 - xi.1).max(0.0).sqrt()), normal)
+}
+
+// ? Half vectors distributed like the GGX lobe, see: https://learnopengl.com/PBR/IBL/Specular-IBL
+fn importance_sample_ggx(xi: (f32, f32), normal: Vector3<f32>, roughness: f32) -> Vector3<f32> {
+	let a = roughness * roughness;
+	let phi = 2.0 * PI * xi.0;
+	let cos_theta = ((1.0 - xi.1) / (1.0 + (a * a - 1.0) * xi.1)).sqrt();
+	let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+	to_world(vec3(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta), normal)
+}
+
+fn map_cube<F: Fn(Vector3<f32>) -> [f32; 3]>(face_size: u32, f: F) -> Environment {
+	let faces = (0..6).map(|face| {
+		let mut texels = Vec::with_capacity((face_size * face_size) as usize);
+		for y in 0..face_size {
+			for x in 0..face_size {
+				texels.push(f(texel_direction(face, x, y, face_size)));
+			}
+		}
+		texels
+	}).collect();
+	Environment { face_size, faces }
+}
+
+// ? Cosine weighted integral of the incoming light, the diffuse part of the lighting
+pub fn irradiance(environment: &Environment) -> Environment {
+	let source = environment.downsampled(IRRADIANCE_SOURCE_SIZE);
+	map_cube(IRRADIANCE_SIZE, |normal| {
+		let mut sum = [0.0; 3];
+		for i in 0..IRRADIANCE_SAMPLES {
+			let color = source.sample(cosine_sample(hammersley(i, IRRADIANCE_SAMPLES), normal));
+			for c in 0..3 {
+				sum[c] += color[c];
+			}
+		}
+		[sum[0] / IRRADIANCE_SAMPLES as f32, sum[1] / IRRADIANCE_SAMPLES as f32, sum[2] / IRRADIANCE_SAMPLES as f32]
+	})
+}
+
+// ? One cube per mip level, roughness going linearly from 0 to 1 along the chain
+pub fn prefiltered(environment: &Environment) -> Vec<Environment> {
+	let source = environment.downsampled(PREFILTERED_SIZE);
+	(0..PREFILTERED_MIP_LEVELS).map(|level| {
+		let roughness = level as f32 / (PREFILTERED_MIP_LEVELS - 1) as f32;
+		let face_size = (PREFILTERED_SIZE >> level).max(1);
+		if level == 0 {
+			return map_cube(face_size, |direction| source.sample(direction));
+		}
+		// ? The view direction is assumed to be the normal and the reflection direction
+		map_cube(face_size, |normal| {
+			let mut sum = [0.0; 3];
+			let mut total_weight = 0.0;
+			for i in 0..PREFILTER_SAMPLES {
+				let half = importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), normal, roughness);
+				let light = half * (2.0 * normal.dot(half)) - normal;
+				let n_dot_l = normal.dot(light);
+				if n_dot_l > 0.0 {
+					let color = source.sample(light);
+					for c in 0..3 {
+						sum[c] += color[c] * n_dot_l;
+					}
+					total_weight += n_dot_l;
+				}
+			}
+			let total_weight = f32::max(total_weight, 1e-4);
+			[sum[0] / total_weight, sum[1] / total_weight, sum[2] / total_weight]
+		})
+	}).collect()
+}
+
+// ? Scale and bias applied to F0 by the split sum approximation, indexed by (n.v, roughness)
+pub fn brdf_lut() -> Vec<[f32; 2]> {
+	let mut texels = Vec::with_capacity((BRDF_LUT_SIZE * BRDF_LUT_SIZE) as usize);
+	let normal = vec3(0.0, 0.0, 1.0);
+	for y in 0..BRDF_LUT_SIZE {
+		let roughness = (y as f32 + 0.5) / BRDF_LUT_SIZE as f32;
+		let k = roughness * roughness / 2.0;
+		for x in 0..BRDF_LUT_SIZE {
+			let n_dot_v = (x as f32 + 0.5) / BRDF_LUT_SIZE as f32;
+			let view = vec3((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+			let (mut scale, mut bias) = (0.0, 0.0);
+			for i in 0..BRDF_SAMPLES {
+				let half = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), normal, roughness);
+				let light = half * (2.0 * view.dot(half)) - view;
+				let n_dot_l = light.z.max(0.0);
+				let n_dot_h = half.z.max(0.0);
+				let v_dot_h = view.dot(half).max(0.0);
+				if n_dot_l > 0.0 {
+					let geometry = (n_dot_v / (n_dot_v * (1.0 - k) + k)) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+					let visibility = geometry * v_dot_h / (n_dot_h * n_dot_v).max(1e-4);
+					let fresnel = (1.0 - v_dot_h).powi(5);
+					scale += (1.0 - fresnel) * visibility;
+					bias += fresnel * visibility;
+				}
+			}
+			texels.push([scale / BRDF_SAMPLES as f32, bias / BRDF_SAMPLES as f32]);
+		}
+	}
+	texels
+}
+
+/*
+..####....####....####...##..##..######.
+.##..##..##..##..##..##..##..##..##.....
+.##......######..##......######..####...
+.##..##..##..##..##..##..##..##..##.....
+..####...##..##...####...##..##..######.
+........................................
+*/
+
+// ? Precomputed lighting of an environment
+pub struct IblMaps {
+	pub irradiance: Environment,
+	pub prefiltered: Vec<Environment>,
+}
+
+impl IblMaps {
+	pub fn compute(environment: &Environment) -> Self {
+		IblMaps {
+			irradiance: irradiance(environment),
+			prefiltered: prefiltered(environment),
+		}
+	}
+
+	// ? Convolutions take a while, their results are cached by environment content
+	pub fn load_or_compute(environment: &Environment) -> Self {
+		let path = cache_path(&format!("ibl_{:016x}.bin", environment_hash(environment)));
+		if let Some(maps) = read_cache(&path).and_then(|data| Self::decode(&data)) {
+			return maps;
+		}
+		println!("Computing image-based lighting maps...");
+		let maps = Self::compute(environment);
+		write_cache(&path, &maps.encode());
+		maps
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		let mut writer = CacheWriter::new(CACHE_MAGIC, CACHE_VERSION);
+		write_cube(&mut writer, &self.irradiance);
+		writer.u32(self.prefiltered.len() as u32);
+		for level in &self.prefiltered {
+			write_cube(&mut writer, level);
+		}
+		writer.bytes
+	}
+
+	fn decode(data: &[u8]) -> Option<Self> {
+		let mut reader = CacheReader::new(data, CACHE_MAGIC, CACHE_VERSION)?;
+		let irradiance = read_cube(&mut reader)?;
+		let levels = reader.u32()? as usize;
+		if levels != PREFILTERED_MIP_LEVELS {
+			return None;
+		}
+		let prefiltered = (0..levels).map(|_| read_cube(&mut reader)).collect::<Option<Vec<_>>>()?;
+		Some(IblMaps { irradiance, prefiltered })
+	}
+}
+
+pub fn load_or_compute_brdf_lut() -> Vec<[f32; 2]> {
+	let path = cache_path("brdf_lut.bin");
+	let cached = read_cache(&path).and_then(|data| {
+		let mut reader = CacheReader::new(&data, CACHE_MAGIC, CACHE_VERSION)?;
+		let count = reader.u32()? as usize;
+		if count != (BRDF_LUT_SIZE * BRDF_LUT_SIZE) as usize {
+			return None;
+		}
+		(0..count).map(|_| Some([reader.f32()?, reader.f32()?])).collect::<Option<Vec<_>>>()
+	});
+	if let Some(lut) = cached {
+		return lut;
+	}
+	let lut = brdf_lut();
+	let mut writer = CacheWriter::new(CACHE_MAGIC, CACHE_VERSION);
+	writer.u32(lut.len() as u32);
+	for texel in &lut {
+		writer.f32(texel[0]);
+		writer.f32(texel[1]);
+	}
+	write_cache(&path, &writer.bytes);
+	lut
+}
+
+fn environment_hash(environment: &Environment) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	CACHE_VERSION.hash(&mut hasher);
+	environment.face_size.hash(&mut hasher);
+	for face in &environment.faces {
+		for texel in face {
+			for component in texel {
+				component.to_bits().hash(&mut hasher);
+			}
+		}
+	}
+	hasher.finish()
+}
+
+const CACHE_MAGIC: &[u8; 4] = b"IBLC";
+
+fn write_cube(writer: &mut CacheWriter, cube: &Environment) {
+	writer.u32(cube.face_size);
+	for face in &cube.faces {
+		for texel in face {
+			writer.f32s(texel);
+		}
+	}
+}
+
+fn read_cube(reader: &mut CacheReader) -> Option<Environment> {
+	let face_size = reader.u32()?;
+	// ? The size comes from the disk, a corrupt file must not overflow or allocate more than it holds
+	let texel_count = (face_size as usize).checked_mul(face_size as usize)?;
+	if texel_count.checked_mul(6 * 12)? > reader.remaining() {
+		return None;
+	}
+	let faces = (0..6).map(|_| {
+		(0..texel_count).map(|_| Some([reader.f32()?, reader.f32()?, reader.f32()?])).collect::<Option<Vec<_>>>()
+	}).collect::<Option<Vec<_>>>()?;
+	Some(Environment { face_size, faces })
+}
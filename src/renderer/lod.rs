@@ -0,0 +1,59 @@
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use scene::Scene;
+use scene::lod::{screen_size, select_level};
+use scene::optimize;
+
+/*
+.##.......####...#####..
+.##......##..##..##..##.
+.##......##..##..##..##.
+.##......##..##..##..##.
+.######...####...#####..
+........................
+*/
+
+// ? Every level of an object lives in the same indexed vertex buffer, a level is only a range of its indices
+pub struct LodBuffers<R: gfx::Resources> {
+	objects: Vec<(gfx::handle::Buffer<R, Vertex>, Vec<gfx::Slice<R>>)>,
+}
+
+impl<R: gfx::Resources> LodBuffers<R> {
+	// ? Uploads every object's vertices, to be created again when they change.
+	// ? Identical corners are shared through the index buffer, which keeps the triangle order of Object3D::optimize.
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, scene: &Scene) -> Self {
+		let objects = scene.objects.iter().map(|object| {
+			let mut vertices = vec![];
+			let mut indices = vec![];
+			let mut ranges = vec![];
+			for level in Some(&object.vertices).into_iter().chain(object.lods.iter()) {
+				let (level_vertices, level_indices) = optimize::index(level);
+				let (base_vertex, start) = (vertices.len() as u32, indices.len() as u32);
+				vertices.extend(level_vertices);
+				indices.extend(level_indices.into_iter().map(|index| base_vertex + index));
+				ranges.push((start, indices.len() as u32));
+			}
+			let (vertex_buffer, full_slice) = factory.create_vertex_buffer_with_slice(&vertices.as_slice(), indices.as_slice());
+			let slices = ranges.into_iter().map(|(start, end)| gfx::Slice {
+				start,
+				end,
+				base_vertex: 0,
+				instances: None,
+				buffer: full_slice.buffer.clone()
+			}).collect();
+			(vertex_buffer, slices)
+		}).collect();
+		LodBuffers { objects }
+	}
+
+	// ? The buffer and level of each object for the current camera, in the order of `scene.objects`.
+	// ? Every pass draws these, shadow maps included, so they stay consistent with what is on screen.
+	pub fn select(&self, scene: &Scene) -> Vec<(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)> {
+		scene.objects.iter().zip(&self.objects).map(|(object, &(ref vertex_buffer, ref slices))| {
+			let level = select_level(screen_size(object, &scene.camera), &scene.lod, slices.len());
+			(vertex_buffer.clone(), slices[level].clone())
+		}).collect()
+	}
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::f32;
+
+use cgmath::{vec3, Vector4};
+use gfx;
+use gfx::traits::FactoryExt;
+use image;
+
+pub mod cluster;
+pub mod debug_view;
+pub mod deferred;
+pub mod ibl;
+pub mod lod;
+pub mod msaa;
+pub mod overlay;
+pub mod post_process;
+pub mod shadow;
+pub mod skybox;
+pub mod ssao;
+pub mod tone_mapping;
+pub mod transparency;
+
+use common::*;
+use scene::camera::Camera;
+use scene::light_source::LightSource;
+use scene::material::Material;
+
+/*
+.#####...######..##..##..#####...######..#####...######..#####..
+.##..##..##......###.##..##..##..##......##..##..##......##..##.
+.#####...####....##.###..##..##..####....#####...####....#####..
+.##..##..##......##..##..##..##..##......##..##..##......##..##.
+.##..##..######..##..##..#####...######..##..##..######..##..##.
+................................................................
+*/
+
+pub const FRAME_GLSL: &[u8] = include_bytes!("../shaders/frame_150.glsl");
+pub const LIGHTING_GLSL: &[u8] = include_bytes!("../shaders/lighting_150.glsl");
+pub const MATERIAL_GLSL: &[u8] = include_bytes!("../shaders/material_150.glsl");
+pub const CLUSTERS_GLSL: &[u8] = include_bytes!("../shaders/clusters_150.glsl");
+pub const FORWARD_GLSL: &[u8] = include_bytes!("../shaders/forward_150.glsl");
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RenderPath {
+	// ? Every light is evaluated for every fragment of every object
+	Forward,
+	// ? Objects are rasterized once into a G-buffer, lights are then applied per pixel
+	Deferred,
+}
+
+impl RenderPath {
+	pub fn toggled(&self) -> Self {
+		match *self {
+			RenderPath::Forward => RenderPath::Deferred,
+			RenderPath::Deferred => RenderPath::Forward,
+		}
+	}
+}
+
+gfx_defines!{
+	vertex ScreenVertex {
+		pos: [f32; 2] = "a_Pos",
+	}
+}
+
+// ? GLSL has no #include, fragment shaders are assembled from the shared parts they need
+pub fn shader_source(parts: &[&[u8]]) -> Vec<u8> {
+	let mut source = b"#version 150 core\n".to_vec();
+	for part in parts {
+		source.extend_from_slice(part);
+		source.push(b'\n');
+	}
+	source
+}
+
+// ? A single triangle covering the whole viewport, for full-screen passes
+pub fn create_screen_triangle<F, R>(factory: &mut F) -> (gfx::handle::Buffer<R, ScreenVertex>, gfx::Slice<R>)
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	let vertices = [
+		ScreenVertex { pos: [-1.0, -1.0] },
+		ScreenVertex { pos: [3.0, -1.0] },
+		ScreenVertex { pos: [-1.0, 3.0] },
+	];
+	factory.create_vertex_buffer_with_slice(&vertices, ())
+}
+
+pub fn load_image_resource_view<F, R>(factory: &mut F, rgba_image: &image::RgbaImage) -> gfx::handle::ShaderResourceView<R, [f32; 4]>
+    where F: gfx::Factory<R>, R: gfx::Resources
+{
+    let (width, height) = rgba_image.dimensions();
+    let kind = gfx::texture::Kind::D2(width as u16, height as u16, gfx::texture::AaMode::Single);
+	let mipmap = gfx::texture::Mipmap::Provided;
+	let (_, view) = factory.create_texture_immutable_u8::<ColorFormat>(kind, mipmap, &[&rgba_image]).unwrap();
+    view
+}
+
+pub fn full_screen_rect(width: u16, height: u16) -> gfx::Rect {
+	gfx::Rect { x: 0, y: 0, w: width, h: height }
+}
+
+// ? Screen rectangle covering the light's range, None when it is entirely off screen.
+// ? Lights without a range and lights whose range contains the camera cover the whole screen.
+pub fn light_scissor(light: &LightSource, camera: &Camera, width: u16, height: u16) -> Option<gfx::Rect> {
+	let range = match light.range() {
+		Some(range) => range,
+		None => return Some(full_screen_rect(width, height)),
+	};
+	let view_projection = camera.vp_matrix();
+	let (mut min_x, mut min_y) = (f32::INFINITY, f32::INFINITY);
+	let (mut max_x, mut max_y) = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+	for &x in &[-range, range] {
+		for &y in &[-range, range] {
+			for &z in &[-range, range] {
+				let corner = light.translation + vec3(x, y, z);
+				let clip: Vector4<f32> = view_projection * corner.extend(1.0);
+				if clip.w <= camera.near {
+					return Some(full_screen_rect(width, height));
+				}
+				min_x = min_x.min(clip.x / clip.w);
+				min_y = min_y.min(clip.y / clip.w);
+				max_x = max_x.max(clip.x / clip.w);
+				max_y = max_y.max(clip.y / clip.w);
+			}
+		}
+	}
+	let (min_x, min_y) = (min_x.max(-1.0), min_y.max(-1.0));
+	let (max_x, max_y) = (max_x.min(1.0), max_y.min(1.0));
+	if min_x >= max_x || min_y >= max_y {
+		return None;
+	}
+	let x = ((min_x * 0.5 + 0.5) * width as f32).floor() as u16;
+	let y = ((min_y * 0.5 + 0.5) * height as f32).floor() as u16;
+	let right = ((max_x * 0.5 + 0.5) * width as f32).ceil() as u16;
+	let top = ((max_y * 0.5 + 0.5) * height as f32).ceil() as u16;
+	Some(gfx::Rect { x, y, w: right.min(width) - x, h: top.min(height) - y })
+}
+
+/*
+.######..######..##..##..######..##..##..#####...######...####..
+...##....##.......####.....##....##..##..##..##..##......##.....
+...##....####......##......##....##..##..#####...####.....####..
+...##....##.......####.....##....##..##..##..##..##..........##.
+...##....######..##..##....##.....####...##..##..######...####..
+................................................................
+*/
+
+pub struct MaterialTextures<R: gfx::Resources> {
+	pub ambient: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub diffuse: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub specular: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub normal: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub dissolve: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub metallic: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub roughness: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub emissive: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub occlusion: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+}
+
+impl<R: gfx::Resources> MaterialTextures<R> {
+	// ? Points the material samplers of `data` at these textures and uploads the material's locals, before drawing an object
+	pub fn bind<C, D>(self, encoder: &mut gfx::Encoder<R, C>, data: &mut D, material: Option<&Material>)
+		where C: gfx::CommandBuffer<R>, D: MaterialPipelineData<R>
+	{
+		let locals = material.map_or(MaterialLocals::new_default(), |material| material.locals());
+		encoder.update_buffer(data.material_locals(), &[locals], 0).unwrap();
+		let views = [self.ambient, self.diffuse, self.specular, self.normal, self.dissolve, self.metallic, self.roughness, self.emissive, self.occlusion];
+		for (slot, view) in data.material_textures().iter_mut().zip(views.iter()) {
+			**slot = view.clone();
+		}
+	}
+}
+
+// ? Pipeline data with the samplers and locals of material_150.glsl, see MaterialTextures::bind
+pub trait MaterialPipelineData<R: gfx::Resources> {
+	// ? In the order of the MaterialTextures fields
+	fn material_textures(&mut self) -> [&mut gfx::handle::ShaderResourceView<R, [f32; 4]>; 9];
+	fn material_locals(&self) -> &gfx::handle::Buffer<R, MaterialLocals>;
+}
+
+// ? The material fields have the same names in every pipeline drawing objects
+macro_rules! impl_material_pipeline_data {
+	($data:ty) => {
+		impl<R: gfx::Resources> MaterialPipelineData<R> for $data {
+			fn material_textures(&mut self) -> [&mut gfx::handle::ShaderResourceView<R, [f32; 4]>; 9] {
+				[
+					&mut self.ambient_texture.0,
+					&mut self.diffuse_texture.0,
+					&mut self.specular_texture.0,
+					&mut self.normal_texture.0,
+					&mut self.dissolve_texture.0,
+					&mut self.metallic_texture.0,
+					&mut self.roughness_texture.0,
+					&mut self.emissive_texture.0,
+					&mut self.occlusion_texture.0,
+				]
+			}
+
+			fn material_locals(&self) -> &gfx::handle::Buffer<R, MaterialLocals> {
+				&self.material_locals
+			}
+		}
+	};
+}
+
+impl_material_pipeline_data!(pipe::Data<R>);
+impl_material_pipeline_data!(deferred::gbuffer_pipe::Data<R>);
+impl_material_pipeline_data!(transparency::oit_pipe::Data<R>);
+
+// ? Every texture of the scene, uploaded once, with defaults for the maps a material doesn't have
+pub struct TextureLibrary<R: gfx::Resources> {
+	views: HashMap<String, gfx::handle::ShaderResourceView<R, [f32; 4]>>,
+	no_texture: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	// ? White leaves the material's factor untouched
+	white_texture: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	// ? A flat tangent-space normal, used when the material has no normal map
+	flat_normal_texture: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub sampler: gfx::handle::Sampler<R>,
+}
+
+impl<R: gfx::Resources> TextureLibrary<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, texture_map: &HashMap<String, image::RgbaImage>) -> Self {
+		let no_texture_rgba_image = image::open("data/NO_TEXTURE.png").unwrap().to_rgba();
+		let views = texture_map.iter().map(
+			|(name, rgba_image)| (name.clone(), load_image_resource_view(factory, rgba_image))
+		).collect();
+		TextureLibrary {
+			views,
+			no_texture: load_image_resource_view(factory, &no_texture_rgba_image),
+			white_texture: load_image_resource_view(factory, &image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]))),
+			flat_normal_texture: load_image_resource_view(factory, &image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 255, 255]))),
+			sampler: factory.create_sampler_linear(),
+		}
+	}
+
+	fn view_or(&self, name: &String, default: &gfx::handle::ShaderResourceView<R, [f32; 4]>) -> gfx::handle::ShaderResourceView<R, [f32; 4]> {
+		self.views.get(name).unwrap_or(default).clone()
+	}
+
+	pub fn material_textures(&self, material: Option<&Material>) -> MaterialTextures<R> {
+		let mut textures = MaterialTextures {
+			ambient: self.white_texture.clone(),
+			diffuse: self.no_texture.clone(),
+			specular: self.white_texture.clone(),
+			normal: self.flat_normal_texture.clone(),
+			dissolve: self.white_texture.clone(),
+			metallic: self.white_texture.clone(),
+			roughness: self.white_texture.clone(),
+			emissive: self.white_texture.clone(),
+			occlusion: self.white_texture.clone(),
+		};
+		if let Some(material) = material {
+			textures.ambient = self.view_or(&material.ambient_texture, &self.white_texture);
+			textures.diffuse = self.view_or(&material.diffuse_texture, &self.no_texture);
+			textures.specular = self.view_or(&material.specular_texture, &self.white_texture);
+			textures.normal = self.view_or(&material.normal_texture, &self.flat_normal_texture);
+			textures.dissolve = self.view_or(&material.dissolve_texture, &self.white_texture);
+			if let Some(ref pbr) = material.pbr {
+				textures.metallic = self.view_or(&pbr.metallic_texture, &self.white_texture);
+				textures.roughness = self.view_or(&pbr.roughness_texture, &self.white_texture);
+				textures.emissive = self.view_or(&pbr.emissive_texture, &self.white_texture);
+				textures.occlusion = self.view_or(&pbr.occlusion_texture, &self.white_texture);
+			}
+		}
+		textures
+	}
+}
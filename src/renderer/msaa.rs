@@ -0,0 +1,108 @@
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use renderer::{create_screen_triangle, shader_source, ScreenVertex};
+
+/*
+.##...##...####....####....####..
+.###.###..##......##......##..##.
+.##.#.##...####....####...######.
+.##...##......##......##..##..##.
+.##...##...####....####...##..##.
+.................................
+*/
+
+pub const DEFAULT_SAMPLE_COUNT: u16 = 4;
+
+// ? Reads `--msaa <1|2|4|8>`, other arguments are ignored
+pub fn sample_count_from_args<I: Iterator<Item = String>>(args: I) -> u16 {
+	let args: Vec<String> = args.collect();
+	let mut sample_count = DEFAULT_SAMPLE_COUNT;
+	for pair in args.windows(2) {
+		if pair[0] == "--msaa" {
+			match pair[1].parse::<u16>() {
+				Ok(count) if count == 1 || count == 2 || count == 4 || count == 8 => sample_count = count,
+				_ => println!("Invalid MSAA sample count: {}, expected 1, 2, 4 or 8", pair[1]),
+			}
+		}
+	}
+	sample_count
+}
+
+// ? Rasterizer state for pipelines drawing into multisampled targets
+pub fn rasterizer(sample_count: u16) -> gfx::state::Rasterizer {
+	let mut rasterizer = gfx::state::Rasterizer::new_fill();
+	if sample_count > 1 {
+		rasterizer.samples = Some(gfx::state::MultiSample);
+	}
+	rasterizer
+}
+
+gfx_defines!{
+	pipeline resolve_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		source: gfx::TextureSampler<[f32; 4]> = "u_Source",
+		sample_count: gfx::Global<i32> = "u_SampleCount",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+	}
+}
+
+// ? Multisampled HDR color and depth the scene is rendered into, resolved into a single sampled target
+pub struct MultisampleTarget<R: gfx::Resources> {
+	pub color: gfx::handle::RenderTargetView<R, HdrColorFormat>,
+	pub depth: gfx::handle::DepthStencilView<R, DepthFormat>,
+	pipeline_state: gfx::PipelineState<R, resolve_pipe::Meta>,
+	data: resolve_pipe::Data<R>,
+	slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> MultisampleTarget<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, sample_count: u16, resolve_target: gfx::handle::RenderTargetView<R, HdrColorFormat>) -> Self {
+		let (width, height, ..) = resolve_target.get_dimensions();
+		let kind = gfx::texture::Kind::D2(width, height, gfx::texture::AaMode::Multi(sample_count as gfx::texture::NumSamples));
+
+		let color_texture = factory.create_texture::<gfx::format::R16_G16_B16_A16>(
+			kind, 1,
+			gfx::memory::Bind::RENDER_TARGET | gfx::memory::Bind::SHADER_RESOURCE,
+			gfx::memory::Usage::Data,
+			Some(gfx::format::ChannelType::Float)
+		).unwrap();
+		let color = factory.view_texture_as_render_target::<HdrColorFormat>(&color_texture, 0, None).unwrap();
+		let color_view = factory.view_texture_as_shader_resource::<HdrColorFormat>(&color_texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+		let depth_texture = factory.create_texture::<gfx::format::D24_S8>(
+			kind, 1,
+			gfx::memory::Bind::DEPTH_STENCIL,
+			gfx::memory::Usage::Data,
+			Some(gfx::format::ChannelType::Unorm)
+		).unwrap();
+		let depth = factory.view_texture_as_depth_stencil_trivial(&depth_texture).unwrap();
+
+		let pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/resolve_150.glslf")]),
+			resolve_pipe::new()
+		).unwrap();
+		let (vbuf, slice) = create_screen_triangle(factory);
+		let sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+
+		MultisampleTarget {
+			color,
+			depth,
+			pipeline_state,
+			data: resolve_pipe::Data {
+				vbuf,
+				source: (color_view, sampler),
+				sample_count: sample_count as i32,
+				out: resolve_target,
+			},
+			slice,
+		}
+	}
+
+	pub fn resolve<C: gfx::CommandBuffer<R>>(&self, encoder: &mut gfx::Encoder<R, C>) {
+		encoder.draw(&self.slice, &self.pipeline_state, &self.data);
+	}
+}
@@ -0,0 +1,301 @@
+use std::f32::consts::PI;
+
+use cgmath::{vec3, EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Transform, Vector3};
+use gfx;
+use gfx::traits::FactoryExt;
+use glutin;
+
+use common::*;
+use renderer::{create_screen_triangle, msaa, shader_source, ScreenVertex};
+use renderer::debug_view::{line_pipe, LineVertex};
+use scene::Scene;
+use scene::camera::Camera;
+use scene::light_source::{LightKind, LightSource};
+
+/*
+..####...##..##..######..#####...##.......####...##..##.
+.##..##..##..##..##......##..##..##......##..##...####..
+.##..##..##..##..####....#####...##......######....##...
+.##..##...####...##......##..##..##......##..##....##...
+..####.....##....######..##..##..######..##..##....##...
+........................................................
+*/
+
+// ? Lines past this are dropped for the frame
+const MAX_OVERLAY_VERTICES: usize = 1 << 16;
+const CIRCLE_SEGMENTS: usize = 32;
+const AXIS_LENGTH: f32 = 1.0;
+const LIGHT_ICON_RADIUS: f32 = 0.2;
+const DIRECTIONAL_ARROW_LENGTH: f32 = 1.0;
+// ? Spot cones without a range are drawn this long
+const SPOT_CONE_LENGTH: f32 = 2.0;
+const GRID_SPACING: f32 = 1.0;
+const GRID_FADE_DISTANCE: f32 = 50.0;
+
+const BOUNDING_BOX_COLOR: [f32; 3] = [1.0, 0.6, 0.0];
+const CAMERA_COLOR: [f32; 3] = [0.8, 0.8, 0.8];
+
+#[derive(Copy, Clone, Debug)]
+pub struct OverlaySettings {
+	pub grid: bool,
+	pub axes: bool,
+	pub lights: bool,
+	pub cameras: bool,
+	pub bounding_boxes: bool,
+}
+
+impl Default for OverlaySettings {
+	fn default() -> Self {
+		OverlaySettings {
+			grid: true,
+			axes: true,
+			lights: true,
+			cameras: false,
+			bounding_boxes: false,
+		}
+	}
+}
+
+gfx_defines!{
+	// ? Ground plane intersected per pixel, the grid has no edge
+	pipeline grid_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		inverse_view_projection: gfx::Global<[[f32; 4]; 4]> = "u_InverseViewProjection",
+		view_projection: gfx::Global<[[f32; 4]; 4]> = "u_ViewProjection",
+		eye_position: gfx::Global<[f32; 4]> = "u_EyePosition",
+		params: gfx::Global<[f32; 4]> = "u_GridParams",
+		out: gfx::BlendTarget<HdrColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
+	}
+}
+
+// ? World space lines collected for one frame
+struct LineBatch {
+	vertices: Vec<LineVertex>,
+}
+
+impl LineBatch {
+	fn line(&mut self, a: Point3<f32>, b: Point3<f32>, color: [f32; 3]) {
+		self.vertices.push(LineVertex { pos: [a.x, a.y, a.z, 1.0], color });
+		self.vertices.push(LineVertex { pos: [b.x, b.y, b.z, 1.0], color });
+	}
+
+	// ? Circle in the plane spanned by the unit vectors u and v
+	fn circle(&mut self, center: Point3<f32>, u: Vector3<f32>, v: Vector3<f32>, radius: f32, color: [f32; 3]) {
+		let point = |i: usize| {
+			let angle = 2.0 * PI * i as f32 / CIRCLE_SEGMENTS as f32;
+			center + (u * angle.cos() + v * angle.sin()) * radius
+		};
+		for i in 0..CIRCLE_SEGMENTS {
+			self.line(point(i), point(i + 1), color);
+		}
+	}
+
+	fn wire_sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 3]) {
+		let (x, y, z) = (vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0));
+		self.circle(center, x, y, radius, color);
+		self.circle(center, y, z, radius, color);
+		self.circle(center, z, x, radius, color);
+	}
+
+	// ? Two quads given in the same winding and the edges joining them, as for boxes and frustums
+	fn prism(&mut self, corners: &[Point3<f32>], color: [f32; 3]) {
+		for i in 0..4 {
+			self.line(corners[i], corners[(i + 1) % 4], color);
+			self.line(corners[i + 4], corners[(i + 1) % 4 + 4], color);
+			self.line(corners[i], corners[i + 4], color);
+		}
+	}
+
+	fn axes(&mut self) {
+		let origin = Point3::new(0.0, 0.0, 0.0);
+		self.line(origin, Point3::new(AXIS_LENGTH, 0.0, 0.0), [1.0, 0.0, 0.0]);
+		self.line(origin, Point3::new(0.0, AXIS_LENGTH, 0.0), [0.0, 1.0, 0.0]);
+		self.line(origin, Point3::new(0.0, 0.0, AXIS_LENGTH), [0.0, 0.0, 1.0]);
+	}
+
+	fn light(&mut self, light: &LightSource) {
+		let position = Point3::from_vec(light.translation);
+		let direction = light.direction();
+		// ? Any unit vectors orthogonal to the direction
+		let up = if direction.y.abs() < 0.99 { vec3(0.0, 1.0, 0.0) } else { vec3(1.0, 0.0, 0.0) };
+		let u = direction.cross(up).normalize();
+		let v = direction.cross(u);
+		match light.kind {
+			LightKind::Directional => {
+				let tip = position + direction * DIRECTIONAL_ARROW_LENGTH;
+				self.circle(position, u, v, LIGHT_ICON_RADIUS, light.color);
+				self.line(position, tip, light.color);
+				for &side in &[u, -u, v, -v] {
+					self.line(tip, tip - direction * LIGHT_ICON_RADIUS + side * LIGHT_ICON_RADIUS * 0.5, light.color);
+				}
+			},
+			LightKind::Point { .. } => {
+				self.wire_sphere(position, LIGHT_ICON_RADIUS, light.color);
+				if let Some(range) = light.range() {
+					let dimmed = [light.color[0] * 0.3, light.color[1] * 0.3, light.color[2] * 0.3];
+					self.wire_sphere(position, range, dimmed);
+				}
+			},
+			LightKind::Spot { outer_cone, .. } => {
+				self.wire_sphere(position, LIGHT_ICON_RADIUS, light.color);
+				let length = light.range().unwrap_or(SPOT_CONE_LENGTH);
+				let base = position + direction * length;
+				let radius = length * Rad::from(outer_cone).0.tan();
+				self.circle(base, u, v, radius, light.color);
+				for &side in &[u, -u, v, -v] {
+					self.line(position, base + side * radius, light.color);
+				}
+			},
+		}
+	}
+
+	fn camera(&mut self, camera: &Camera) {
+		let corners = camera.frustum_corners(camera.near, camera.far);
+		self.prism(&corners, CAMERA_COLOR);
+	}
+
+	// ? The object space bounds, transformed with the object
+	fn bounding_box(&mut self, (min, max): (Vector3<f32>, Vector3<f32>), model: Matrix4<f32>) {
+		let mut corners = Vec::with_capacity(8);
+		for &z in &[min.z, max.z] {
+			for &(x, y) in &[(min.x, min.y), (max.x, min.y), (max.x, max.y), (min.x, max.y)] {
+				corners.push(model.transform_point(Point3::new(x, y, z)));
+			}
+		}
+		self.prism(&corners, BOUNDING_BOX_COLOR);
+	}
+}
+
+pub struct Overlay<R: gfx::Resources> {
+	pub settings: OverlaySettings,
+	grid_pipeline_state: gfx::PipelineState<R, grid_pipe::Meta>,
+	line_pipeline_state: gfx::PipelineState<R, line_pipe::Meta>,
+	grid_data: grid_pipe::Data<R>,
+	line_data: line_pipe::Data<R>,
+	screen_slice: gfx::Slice<R>,
+	// ? Object geometry doesn't change, its bounds are computed once
+	object_bounds: Vec<(Vector3<f32>, Vector3<f32>)>,
+}
+
+impl<R: gfx::Resources> Overlay<R> {
+	pub fn new<F: gfx::Factory<R>>(
+		factory: &mut F,
+		scene: &Scene,
+		out: gfx::handle::RenderTargetView<R, HdrColorFormat>,
+		out_depth: gfx::handle::DepthStencilView<R, DepthFormat>,
+		sample_count: u16
+	) -> Self {
+		let rasterizer = msaa::rasterizer(sample_count);
+		let grid_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/grid_150.glslf")])
+		).unwrap();
+		let grid_pipeline_state = factory.create_pipeline_state(&grid_shader_set, gfx::Primitive::TriangleList, rasterizer, grid_pipe::new()).unwrap();
+		let line_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/line_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/line_150.glslf")])
+		).unwrap();
+		let line_pipeline_state = factory.create_pipeline_state(&line_shader_set, gfx::Primitive::LineList, rasterizer, line_pipe::new()).unwrap();
+		let line_buffer = factory.create_buffer(
+			MAX_OVERLAY_VERTICES,
+			gfx::buffer::Role::Vertex,
+			gfx::memory::Usage::Dynamic,
+			gfx::memory::Bind::empty()
+		).unwrap();
+		let (screen_buffer, screen_slice) = create_screen_triangle(factory);
+
+		Overlay {
+			settings: OverlaySettings::default(),
+			grid_pipeline_state,
+			line_pipeline_state,
+			grid_data: grid_pipe::Data {
+				vbuf: screen_buffer,
+				inverse_view_projection: Matrix4::identity().into(),
+				view_projection: Matrix4::identity().into(),
+				eye_position: [0.0; 4],
+				params: [GRID_SPACING, GRID_FADE_DISTANCE, 0.0, 0.0],
+				out: out.clone(),
+				out_depth: out_depth.clone(),
+			},
+			line_data: line_pipe::Data {
+				vbuf: line_buffer,
+				mvp: Matrix4::identity().into(),
+				out,
+				out_depth,
+			},
+			screen_slice,
+			object_bounds: scene.objects.iter().map(|object| object.bounds()).collect(),
+		}
+	}
+
+	pub fn update_geometry(&mut self, scene: &Scene) {
+		self.object_bounds = scene.objects.iter().map(|object| object.bounds()).collect();
+	}
+
+	// ? G: grid, X: axes, L: lights, V: camera frustums, B: bounding boxes
+	pub fn handle_event(&mut self, event: &glutin::Event) {
+		if let glutin::Event::WindowEvent { event: glutin::WindowEvent::KeyboardInput { input, .. }, .. } = *event {
+			if input.state != glutin::ElementState::Pressed {
+				return;
+			}
+			let (toggle, name) = match input.virtual_keycode {
+				Some(glutin::VirtualKeyCode::G) => (&mut self.settings.grid, "Grid"),
+				Some(glutin::VirtualKeyCode::X) => (&mut self.settings.axes, "Axes"),
+				Some(glutin::VirtualKeyCode::L) => (&mut self.settings.lights, "Light gizmos"),
+				Some(glutin::VirtualKeyCode::V) => (&mut self.settings.cameras, "Camera frustums"),
+				Some(glutin::VirtualKeyCode::B) => (&mut self.settings.bounding_boxes, "Bounding boxes"),
+				_ => return,
+			};
+			*toggle = !*toggle;
+			println!("{}: {}", name, if *toggle { "on" } else { "off" });
+		}
+	}
+
+	// ? Drawn over the scene, depth tested against it
+	pub fn render<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, scene: &Scene) {
+		let view_projection = scene.camera.vp_matrix();
+		let mut batch = LineBatch { vertices: vec![] };
+		if self.settings.axes {
+			batch.axes();
+		}
+		if self.settings.lights {
+			for light in &scene.light_sources {
+				batch.light(light);
+			}
+		}
+		if self.settings.cameras {
+			for camera in &scene.cameras {
+				batch.camera(camera);
+			}
+		}
+		if self.settings.bounding_boxes {
+			for (object, &bounds) in scene.objects.iter().zip(&self.object_bounds) {
+				batch.bounding_box(bounds, object.model_matrix());
+			}
+		}
+
+		if !batch.vertices.is_empty() {
+			batch.vertices.truncate(MAX_OVERLAY_VERTICES);
+			encoder.update_buffer(&self.line_data.vbuf, &batch.vertices, 0).unwrap();
+			self.line_data.mvp = view_projection.into();
+			let slice = gfx::Slice {
+				start: 0,
+				end: batch.vertices.len() as u32,
+				base_vertex: 0,
+				instances: None,
+				buffer: gfx::IndexBuffer::Auto,
+			};
+			encoder.draw(&slice, &self.line_pipeline_state, &self.line_data);
+		}
+
+		// ? Last, the grid blends over the lines below the ground
+		if self.settings.grid {
+			let eye_position = scene.camera.position();
+			self.grid_data.inverse_view_projection = view_projection.invert().unwrap_or(Matrix4::identity()).into();
+			self.grid_data.view_projection = view_projection.into();
+			self.grid_data.eye_position = [eye_position.x, eye_position.y, eye_position.z, 1.0];
+			encoder.draw(&self.screen_slice, &self.grid_pipeline_state, &self.grid_data);
+		}
+	}
+}
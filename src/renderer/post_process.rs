@@ -0,0 +1,380 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use gfx;
+use gfx::traits::FactoryExt;
+use glutin;
+
+use common::*;
+use renderer::{create_screen_triangle, shader_source, ScreenVertex};
+use renderer::tone_mapping::ToneMapping;
+
+/*
+.#####....####....####...######..........#####...#####....####....####...######...####....####..
+.##..##..##..##..##........##............##..##..##..##..##..##..##..##..##......##......##.....
+.#####...##..##...####.....##............#####...#####...##..##..##......####.....####....####..
+.##......##..##......##....##............##......##..##..##..##..##..##..##..........##......##.
+.##.......####....####.....##............##......##..##...####....####...######...####....####..
+.................................................................................................
+*/
+
+pub const POST_PROCESS_GLSL: &[u8] = include_bytes!("../shaders/post_process_150.glsl");
+
+// ? Size of the identity LUT used until a .cube file is loaded
+const IDENTITY_LUT_SIZE: u16 = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BloomSettings {
+	// ? Luminance above which pixels bleed
+	pub threshold: f32,
+	pub intensity: f32,
+	// ? Each pass is a horizontal and a vertical blur at half resolution
+	pub blur_passes: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ColorGradingSettings {
+	// ? Blend between the input and the graded color
+	pub intensity: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VignetteSettings {
+	pub intensity: f32,
+	// ? Distance from the center, relative to the half diagonal, where darkening starts
+	pub radius: f32,
+	pub softness: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum PostEffectKind {
+	// ? Works on linear HDR color, goes before the tone mapping
+	Bloom(BloomSettings),
+	// ? Settings come from the scene, see Scene::tone_mapping
+	ToneMapping,
+	// ? The effects below expect display referred color
+	ColorGrading(ColorGradingSettings),
+	Vignette(VignetteSettings),
+	Fxaa,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PostEffect {
+	pub enabled: bool,
+	pub kind: PostEffectKind,
+}
+
+impl PostEffect {
+	pub fn new(kind: PostEffectKind) -> Self {
+		PostEffect {
+			enabled: true,
+			kind,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self.kind {
+			PostEffectKind::Bloom(_) => "bloom",
+			PostEffectKind::ToneMapping => "tone mapping",
+			PostEffectKind::ColorGrading(_) => "color grading",
+			PostEffectKind::Vignette(_) => "vignette",
+			PostEffectKind::Fxaa => "FXAA",
+		}
+	}
+}
+
+pub fn default_effects() -> Vec<PostEffect> {
+	let mut color_grading = PostEffect::new(PostEffectKind::ColorGrading(ColorGradingSettings { intensity: 1.0 }));
+	// ? The identity LUT is a no-op, enabled once a LUT is loaded
+	color_grading.enabled = false;
+	vec![
+		PostEffect::new(PostEffectKind::Bloom(BloomSettings { threshold: 1.0, intensity: 0.5, blur_passes: 2 })),
+		PostEffect::new(PostEffectKind::ToneMapping),
+		color_grading,
+		PostEffect::new(PostEffectKind::Vignette(VignetteSettings { intensity: 0.35, radius: 0.75, softness: 0.45 })),
+		PostEffect::new(PostEffectKind::Fxaa),
+	]
+}
+
+// ? Reads an Adobe .cube 3D LUT, returns its size and RGBA8 texels with red varying fastest
+pub fn load_cube_lut(path: &Path) -> Result<(u16, Vec<u8>), String> {
+	let file = File::open(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+	let mut size = 0;
+	let mut texels = vec![];
+	for line in BufReader::new(file).lines() {
+		let line = line.map_err(|error| error.to_string())?;
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let mut tokens = line.split_whitespace();
+		let first = tokens.next().unwrap_or("");
+		if first == "LUT_3D_SIZE" {
+			size = tokens.next().and_then(|token| token.parse::<u16>().ok()).ok_or("Invalid LUT_3D_SIZE")?;
+		} else if let Ok(red) = first.parse::<f32>() {
+			let components: Vec<f32> = tokens.filter_map(|token| token.parse::<f32>().ok()).collect();
+			if components.len() != 2 {
+				return Err(format!("Invalid LUT entry: {}", line));
+			}
+			for &value in &[red, components[0], components[1]] {
+				texels.push((value.max(0.0).min(1.0) * 255.0).round() as u8);
+			}
+			texels.push(255);
+		}
+		// ? TITLE, DOMAIN_MIN and DOMAIN_MAX are ignored, the domain is assumed to be [0, 1]
+	}
+	let size_usize = size as usize;
+	if size == 0 || texels.len() != 4 * size_usize * size_usize * size_usize {
+		return Err(format!("{}: expected {} LUT entries", path.display(), size_usize * size_usize * size_usize));
+	}
+	Ok((size, texels))
+}
+
+fn identity_lut(size: u16) -> Vec<u8> {
+	let mut texels = vec![];
+	let scale = 255.0 / (size - 1) as f32;
+	for b in 0..size {
+		for g in 0..size {
+			for r in 0..size {
+				texels.extend_from_slice(&[(r as f32 * scale) as u8, (g as f32 * scale) as u8, (b as f32 * scale) as u8, 255]);
+			}
+		}
+	}
+	texels
+}
+
+gfx_defines!{
+	constant PostLocals {
+		params: [f32; 4] = "u_Params",
+		texel_size: [f32; 4] = "u_TexelSize",
+	}
+
+	pipeline post_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		source: gfx::TextureSampler<[f32; 4]> = "u_Source",
+		secondary: gfx::TextureSampler<[f32; 4]> = "u_Secondary",
+		lut: gfx::TextureSampler<[f32; 4]> = "u_Lut",
+		locals: gfx::ConstantBuffer<PostLocals> = "PostLocals",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+	}
+
+	pipeline present_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		source: gfx::TextureSampler<[f32; 4]> = "u_Source",
+		out: gfx::RenderTarget<ColorFormat> = "Target0",
+	}
+}
+
+#[derive(Clone)]
+struct PostTarget<R: gfx::Resources> {
+	view: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	target: gfx::handle::RenderTargetView<R, HdrColorFormat>,
+	width: u16,
+	height: u16,
+}
+
+impl<R: gfx::Resources> PostTarget<R> {
+	fn new<F: gfx::Factory<R>>(factory: &mut F, width: u16, height: u16) -> Self {
+		let (_, view, target) = factory.create_render_target::<HdrColorFormat>(width, height).unwrap();
+		PostTarget { view, target, width, height }
+	}
+}
+
+fn create_post_pipeline<R, F>(factory: &mut F, fragment: &[u8]) -> gfx::PipelineState<R, post_pipe::Meta>
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	factory.create_pipeline_simple(
+		include_bytes!("../shaders/screen_150.glslv"),
+		&shader_source(&[POST_PROCESS_GLSL, fragment]),
+		post_pipe::new()
+	).unwrap()
+}
+
+// ? One full-screen pass from `source` into `out`
+fn draw_pass<R, C>(
+	encoder: &mut gfx::Encoder<R, C>,
+	pipeline_state: &gfx::PipelineState<R, post_pipe::Meta>,
+	data: &mut post_pipe::Data<R>,
+	slice: &gfx::Slice<R>,
+	source: &PostTarget<R>,
+	out: &PostTarget<R>,
+	params: [f32; 4]
+) where R: gfx::Resources, C: gfx::CommandBuffer<R> {
+	let locals = PostLocals {
+		params,
+		texel_size: [1.0 / source.width as f32, 1.0 / source.height as f32, source.width as f32, source.height as f32],
+	};
+	data.source.0 = source.view.clone();
+	data.out = out.target.clone();
+	encoder.update_buffer(&data.locals, &[locals], 0).unwrap();
+	encoder.draw(slice, pipeline_state, data);
+}
+
+// ? Ordered full-screen effects between the scene's HDR target and the window
+pub struct PostProcessStack<R: gfx::Resources> {
+	pub effects: Vec<PostEffect>,
+	bloom_extract_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	bloom_blur_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	bloom_combine_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	tone_map_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	color_grading_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	vignette_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	fxaa_pipeline_state: gfx::PipelineState<R, post_pipe::Meta>,
+	present_pipeline_state: gfx::PipelineState<R, present_pipe::Meta>,
+	// ? Ping-pong pair at full resolution, the scene is rendered into the first one
+	targets: [PostTarget<R>; 2],
+	bloom_targets: [PostTarget<R>; 2],
+	lut_size: u16,
+	data: post_pipe::Data<R>,
+	present_data: present_pipe::Data<R>,
+	slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> PostProcessStack<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, out: gfx::handle::RenderTargetView<R, ColorFormat>) -> Self {
+		let (width, height, ..) = out.get_dimensions();
+		let targets = [PostTarget::new(factory, width, height), PostTarget::new(factory, width, height)];
+		let (bloom_width, bloom_height) = ((width / 2).max(1), (height / 2).max(1));
+		let bloom_targets = [PostTarget::new(factory, bloom_width, bloom_height), PostTarget::new(factory, bloom_width, bloom_height)];
+
+		let sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Bilinear, gfx::texture::WrapMode::Clamp)
+		);
+		let lut_kind = gfx::texture::Kind::D3(IDENTITY_LUT_SIZE, IDENTITY_LUT_SIZE, IDENTITY_LUT_SIZE);
+		let (_, lut) = factory.create_texture_immutable_u8::<ColorFormat>(lut_kind, gfx::texture::Mipmap::Provided, &[&identity_lut(IDENTITY_LUT_SIZE)[..]]).unwrap();
+		let (vbuf, slice) = create_screen_triangle(factory);
+
+		let data = post_pipe::Data {
+			vbuf: vbuf.clone(),
+			source: (targets[0].view.clone(), sampler.clone()),
+			secondary: (bloom_targets[0].view.clone(), sampler.clone()),
+			lut: (lut, sampler.clone()),
+			locals: factory.create_constant_buffer(1),
+			out: targets[1].target.clone(),
+		};
+		let present_data = present_pipe::Data {
+			vbuf,
+			source: (targets[0].view.clone(), sampler),
+			out,
+		};
+
+		PostProcessStack {
+			effects: default_effects(),
+			bloom_extract_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/bloom_extract_150.glslf")),
+			bloom_blur_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/bloom_blur_150.glslf")),
+			bloom_combine_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/bloom_combine_150.glslf")),
+			tone_map_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/tone_map_150.glslf")),
+			color_grading_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/color_grading_150.glslf")),
+			vignette_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/vignette_150.glslf")),
+			fxaa_pipeline_state: create_post_pipeline(factory, include_bytes!("../shaders/fxaa_150.glslf")),
+			present_pipeline_state: factory.create_pipeline_simple(
+				include_bytes!("../shaders/screen_150.glslv"),
+				&shader_source(&[include_bytes!("../shaders/present_150.glslf")]),
+				present_pipe::new()
+			).unwrap(),
+			targets,
+			bloom_targets,
+			lut_size: IDENTITY_LUT_SIZE,
+			data,
+			present_data,
+			slice,
+		}
+	}
+
+	// ? HDR target the scene has to be rendered into
+	pub fn scene_target(&self) -> gfx::handle::RenderTargetView<R, HdrColorFormat> {
+		self.targets[0].target.clone()
+	}
+
+	pub fn load_lut<F: gfx::Factory<R>>(&mut self, factory: &mut F, path: &Path) -> Result<(), String> {
+		let (size, texels) = load_cube_lut(path)?;
+		let kind = gfx::texture::Kind::D3(size, size, size);
+		let (_, lut) = factory.create_texture_immutable_u8::<ColorFormat>(kind, gfx::texture::Mipmap::Provided, &[&texels[..]])
+			.map_err(|error| format!("{:?}", error))?;
+		self.data.lut.0 = lut;
+		self.lut_size = size;
+		for effect in self.effects.iter_mut() {
+			if let PostEffectKind::ColorGrading(_) = effect.kind {
+				effect.enabled = true;
+			}
+		}
+		Ok(())
+	}
+
+	// ? Reads `--lut <file.cube>`, other arguments are ignored
+	pub fn apply_args<F: gfx::Factory<R>, I: Iterator<Item = String>>(&mut self, factory: &mut F, args: I) {
+		let args: Vec<String> = args.collect();
+		for pair in args.windows(2) {
+			if pair[0] == "--lut" {
+				if let Err(error) = self.load_lut(factory, Path::new(&pair[1])) {
+					println!("Failed to load the color grading LUT: {}", error);
+				}
+			}
+		}
+	}
+
+	// ? Number keys toggle the effects in list order
+	pub fn handle_event(&mut self, event: &glutin::Event) {
+		if let glutin::Event::WindowEvent { event: glutin::WindowEvent::KeyboardInput { input, .. }, .. } = *event {
+			if input.state != glutin::ElementState::Pressed {
+				return;
+			}
+			let index = match input.virtual_keycode {
+				Some(glutin::VirtualKeyCode::Key1) => 0,
+				Some(glutin::VirtualKeyCode::Key2) => 1,
+				Some(glutin::VirtualKeyCode::Key3) => 2,
+				Some(glutin::VirtualKeyCode::Key4) => 3,
+				Some(glutin::VirtualKeyCode::Key5) => 4,
+				_ => return,
+			};
+			if let Some(effect) = self.effects.get_mut(index) {
+				effect.enabled = !effect.enabled;
+				println!("Post effect {}: {}", effect.name(), if effect.enabled { "on" } else { "off" });
+			}
+		}
+	}
+
+	pub fn render<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, tone_mapping: &ToneMapping) {
+		let mut current = 0;
+		for effect in self.effects.iter().filter(|effect| effect.enabled) {
+			let (source, out) = (&self.targets[current], &self.targets[1 - current]);
+			match effect.kind {
+				PostEffectKind::Bloom(ref settings) => {
+					let bloom = &self.bloom_targets;
+					draw_pass(encoder, &self.bloom_extract_pipeline_state, &mut self.data, &self.slice, source, &bloom[0], [settings.threshold, 0.0, 0.0, 0.0]);
+					for _ in 0..settings.blur_passes {
+						draw_pass(encoder, &self.bloom_blur_pipeline_state, &mut self.data, &self.slice, &bloom[0], &bloom[1], [1.0, 0.0, 0.0, 0.0]);
+						draw_pass(encoder, &self.bloom_blur_pipeline_state, &mut self.data, &self.slice, &bloom[1], &bloom[0], [0.0, 1.0, 0.0, 0.0]);
+					}
+					self.data.secondary.0 = bloom[0].view.clone();
+					draw_pass(encoder, &self.bloom_combine_pipeline_state, &mut self.data, &self.slice, source, out, [settings.intensity, 0.0, 0.0, 0.0]);
+				},
+				PostEffectKind::ToneMapping => {
+					let params = [tone_mapping.exposure, tone_mapping.operator.shader_id() as f32, 0.0, 0.0];
+					draw_pass(encoder, &self.tone_map_pipeline_state, &mut self.data, &self.slice, source, out, params);
+				},
+				PostEffectKind::ColorGrading(ref settings) => {
+					let params = [settings.intensity, self.lut_size as f32, 0.0, 0.0];
+					draw_pass(encoder, &self.color_grading_pipeline_state, &mut self.data, &self.slice, source, out, params);
+				},
+				PostEffectKind::Vignette(ref settings) => {
+					let params = [settings.intensity, settings.radius, settings.softness, 0.0];
+					draw_pass(encoder, &self.vignette_pipeline_state, &mut self.data, &self.slice, source, out, params);
+				},
+				PostEffectKind::Fxaa => {
+					draw_pass(encoder, &self.fxaa_pipeline_state, &mut self.data, &self.slice, source, out, [0.0; 4]);
+				},
+			}
+			current = 1 - current;
+		}
+		self.present_data.source.0 = self.targets[current].view.clone();
+		encoder.draw(&self.slice, &self.present_pipeline_state, &self.present_data);
+	}
+
+	// ? Copies the scene target to the window untouched, debug views show raw values
+	pub fn present_unprocessed<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>) {
+		self.present_data.source.0 = self.targets[0].view.clone();
+		encoder.draw(&self.slice, &self.present_pipeline_state, &self.present_data);
+	}
+}
@@ -0,0 +1,284 @@
+use std::cmp::Ordering;
+
+use cgmath::{ortho, perspective, Deg, Matrix4, Point3, vec3, vec4, Vector3};
+use cgmath::{EuclideanSpace, InnerSpace, MetricSpace};
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use scene::camera::Camera;
+use scene::light_source::{LightKind, LightSource};
+use scene::Scene;
+
+/*
+..####...##..##...####...#####....####...##...##.
+.##......##..##..##..##..##..##..##..##..##...##.
+..####...######..######..##..##..##..##..##.#.##.
+.....##..##..##..##..##..##..##..##..##..#######.
+..####...##..##..##..##..#####....####....##.##..
+.................................................
+*/
+
+pub const SHADOW_MAP_SIZE: u16 = 2048;
+pub const MAX_SHADOW_MAPS: usize = 8; // = MAX_SHADOW_MAPS in the fragment shader
+pub const POINT_SHADOW_MAP_SIZE: u16 = 1024;
+pub const MAX_POINT_SHADOW_CASTERS: usize = 4; // = MAX_POINT_SHADOW_CASTERS in the fragment shader
+
+// ? Blend between logarithmic (1.0) and uniform (0.0) cascade splits
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+const SPOT_NEAR_PLANE: f32 = 0.1;
+const POINT_NEAR_PLANE: f32 = 0.05;
+// ? Far plane of point lights without a range
+const POINT_DEFAULT_FAR_PLANE: f32 = 50.0;
+
+pub type ShadowFormat = gfx::format::Depth;
+
+gfx_defines!{
+	pipeline shadow_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		light_mvp: gfx::Global<[[f32; 4]; 4]> = "u_LightMVP",
+		out_depth: gfx::DepthTarget<ShadowFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+	}
+}
+
+// ? View-projection of one shadow map layer and the camera distance it covers
+pub struct ShadowView {
+	pub view_projection: Matrix4<f32>,
+	pub split_distance: f32,
+	pub texel_size: f32,
+	pub is_perspective: bool,
+}
+
+impl ShadowView {
+	fn info(&self) -> ShadowMapInfo {
+		ShadowMapInfo::new(self.view_projection.into(), self.split_distance, self.texel_size, self.is_perspective)
+	}
+}
+
+// ? Layers filled by a shadow pass, to upload in `b_shadow_maps` and `b_point_shadow_maps`
+pub struct ShadowFrame {
+	pub shadow_maps_info: Vec<ShadowMapInfo>,
+	pub point_shadow_maps_info: Vec<ShadowMapInfo>,
+}
+
+pub struct ShadowMaps<R: gfx::Resources> {
+	pipeline_state: gfx::PipelineState<R, shadow_pipe::Meta>,
+	layers: Vec<gfx::handle::DepthStencilView<R, ShadowFormat>>,
+	// ? Six layers per point light, in the +X, -X, +Y, -Y, +Z, -Z order of cube map faces.
+	// ? GLSL 1.50 has no cube map arrays, so the faces are stored in a 2D array and selected in the shader.
+	point_layers: Vec<gfx::handle::DepthStencilView<R, ShadowFormat>>,
+	pub resource: (gfx::handle::ShaderResourceView<R, f32>, gfx::handle::Sampler<R>),
+	pub point_resource: (gfx::handle::ShaderResourceView<R, f32>, gfx::handle::Sampler<R>),
+	// ? Point lights closest to the camera get the cube maps first
+	pub max_point_shadow_casters: usize,
+}
+
+impl<R: gfx::Resources> ShadowMaps<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F) -> Self {
+		let shaders = factory.create_shader_set(
+			include_bytes!("../shaders/shadow_150.glslv"),
+			include_bytes!("../shaders/shadow_150.glslf"),
+		).unwrap();
+		// ? Slope scaled depth offset, complements the per-light constant and normal biases
+		let rasterizer = gfx::state::Rasterizer::new_fill().with_offset(1.5, 1);
+		let pipeline_state = factory.create_pipeline_state(
+			&shaders,
+			gfx::Primitive::TriangleList,
+			rasterizer,
+			shadow_pipe::new()
+		).unwrap();
+
+		let mut sampler_info = gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Bilinear, gfx::texture::WrapMode::Clamp);
+		sampler_info.comparison = Some(gfx::state::Comparison::LessEqual);
+		let sampler = factory.create_sampler(sampler_info);
+
+		let (layers, view) = Self::create_layers(factory, SHADOW_MAP_SIZE, MAX_SHADOW_MAPS);
+		let (point_layers, point_view) = Self::create_layers(factory, POINT_SHADOW_MAP_SIZE, 6 * MAX_POINT_SHADOW_CASTERS);
+
+		ShadowMaps {
+			pipeline_state,
+			layers,
+			point_layers,
+			resource: (view, sampler.clone()),
+			point_resource: (point_view, sampler),
+			max_point_shadow_casters: MAX_POINT_SHADOW_CASTERS,
+		}
+	}
+
+	fn create_layers<F: gfx::Factory<R>>(factory: &mut F, size: u16, count: usize) -> (Vec<gfx::handle::DepthStencilView<R, ShadowFormat>>, gfx::handle::ShaderResourceView<R, f32>) {
+		let kind = gfx::texture::Kind::D2Array(size, size, count as gfx::texture::Layer, gfx::texture::AaMode::Single);
+		let bind = gfx::memory::Bind::SHADER_RESOURCE | gfx::memory::Bind::DEPTH_STENCIL;
+		let texture = factory.create_texture(kind, 1, bind, gfx::memory::Usage::Data, Some(gfx::format::ChannelType::Unorm)).unwrap();
+		let layers = (0..count).map(|layer| {
+			factory.view_texture_as_depth_stencil(&texture, 0, Some(layer as gfx::texture::Layer), gfx::texture::DepthStencilFlags::empty()).unwrap()
+		}).collect();
+		let view = factory.view_texture_as_shader_resource::<ShadowFormat>(&texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+		(layers, view)
+	}
+
+	// ? Renders the depth of every object from each shadow casting light, assigning layers in light order.
+	// ? Lights that don't fit in the remaining layers are left unshadowed.
+	pub fn render<C: gfx::CommandBuffer<R>>(
+		&self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)],
+		light_infos: &mut Vec<LightSourceInfo>
+	) -> ShadowFrame {
+		let mut shadow_maps_info = vec![];
+		let mut point_shadow_casters = vec![];
+		for (light_index, light) in scene.light_sources.iter().enumerate() {
+			if !light.shadow.casts_shadows {
+				continue;
+			}
+			if let LightKind::Point { .. } = light.kind {
+				point_shadow_casters.push(light_index);
+				continue;
+			}
+			let shadow_views = shadow_views(light, &scene.camera);
+			let first_layer = shadow_maps_info.len();
+			if shadow_views.is_empty() || first_layer + shadow_views.len() > MAX_SHADOW_MAPS {
+				continue;
+			}
+			for (layer, shadow_view) in shadow_views.iter().enumerate() {
+				self.render_layer(encoder, &self.layers[first_layer + layer], shadow_view, scene, object_buffers);
+				shadow_maps_info.push(shadow_view.info());
+			}
+			light_infos[light_index].set_shadow(first_layer, shadow_views.len(), light.shadow.bias, light.shadow.normal_bias);
+		}
+
+		let camera_position = scene.camera.position();
+		point_shadow_casters.sort_by(|a, b| {
+			let distance_a = camera_position.distance2(Point3::from_vec(scene.light_sources[*a].translation));
+			let distance_b = camera_position.distance2(Point3::from_vec(scene.light_sources[*b].translation));
+			distance_a.partial_cmp(&distance_b).unwrap_or(Ordering::Equal)
+		});
+		point_shadow_casters.truncate(self.max_point_shadow_casters.min(MAX_POINT_SHADOW_CASTERS));
+
+		let mut point_shadow_maps_info = vec![];
+		for light_index in point_shadow_casters {
+			let light = &scene.light_sources[light_index];
+			let first_layer = point_shadow_maps_info.len();
+			for (face, shadow_view) in point_shadow_views(light).iter().enumerate() {
+				self.render_layer(encoder, &self.point_layers[first_layer + face], shadow_view, scene, object_buffers);
+				point_shadow_maps_info.push(shadow_view.info());
+			}
+			light_infos[light_index].set_shadow(first_layer, 6, light.shadow.bias, light.shadow.normal_bias);
+		}
+
+		ShadowFrame {
+			shadow_maps_info,
+			point_shadow_maps_info,
+		}
+	}
+
+	fn render_layer<C: gfx::CommandBuffer<R>>(
+		&self,
+		encoder: &mut gfx::Encoder<R, C>,
+		out_depth: &gfx::handle::DepthStencilView<R, ShadowFormat>,
+		shadow_view: &ShadowView,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)]
+	) {
+		encoder.clear_depth(out_depth, 1.0);
+		for (object, &(ref vertex_buffer, ref slice)) in scene.objects.iter().zip(object_buffers) {
+			let data = shadow_pipe::Data {
+				vbuf: vertex_buffer.clone(),
+				light_mvp: (shadow_view.view_projection * object.model_matrix()).into(),
+				out_depth: out_depth.clone(),
+			};
+			encoder.draw(slice, &self.pipeline_state, &data);
+		}
+	}
+}
+
+pub fn shadow_views(light: &LightSource, camera: &Camera) -> Vec<ShadowView> {
+	match light.kind {
+		LightKind::Directional => cascade_views(light, camera),
+		LightKind::Spot { outer_cone, .. } => {
+			let direction = light.direction();
+			let view = Matrix4::look_at_dir(Point3::from_vec(light.translation), direction, up_vector(direction));
+			let far = light.range().unwrap_or(camera.far);
+			let fovy = Deg((outer_cone.0 * 2.0).min(170.0));
+			let projection = perspective(fovy, 1.0, SPOT_NEAR_PLANE, far);
+			vec![ShadowView {
+				view_projection: projection * view,
+				split_distance: camera.far,
+				texel_size: 2.0 * (fovy.0.to_radians() / 2.0).tan() / SHADOW_MAP_SIZE as f32,
+				is_perspective: true,
+			}]
+		},
+		// ? Point lights need cube maps
+		LightKind::Point { .. } => vec![],
+	}
+}
+
+// ? Cascaded shadow maps: the camera frustum is split along its depth and each slice gets
+// ? an orthographic projection fitted around its bounding sphere.
+fn cascade_views(light: &LightSource, camera: &Camera) -> Vec<ShadowView> {
+	let direction = light.direction();
+	let cascade_count = light.shadow.cascade_count.max(1).min(MAX_SHADOW_MAPS);
+	let mut views = vec![];
+	let mut near = camera.near;
+	for cascade in 1..cascade_count + 1 {
+		let ratio = cascade as f32 / cascade_count as f32;
+		let logarithmic_split = camera.near * (camera.far / camera.near).powf(ratio);
+		let uniform_split = camera.near + (camera.far - camera.near) * ratio;
+		let far = CASCADE_SPLIT_LAMBDA * logarithmic_split + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform_split;
+
+		let corners = camera.frustum_corners(near, far);
+		let center = Point3::centroid(&corners);
+		let radius = corners.iter().map(|corner| corner.distance(center)).fold(0.0, f32::max);
+
+		// ? Pull the eye back so casters between the light and the slice are kept
+		let eye = center - direction * radius * 2.0;
+		let view = Matrix4::look_at_dir(eye, direction, up_vector(direction));
+		let mut projection = ortho(-radius, radius, -radius, radius, 0.0, radius * 4.0);
+
+		// ? Snap the projection to whole texels so the shadows don't shimmer when the camera moves
+		let half_size = SHADOW_MAP_SIZE as f32 / 2.0;
+		let origin = (projection * view) * vec4(0.0, 0.0, 0.0, 1.0);
+		let offset_x = ((origin.x * half_size).round() - origin.x * half_size) / half_size;
+		let offset_y = ((origin.y * half_size).round() - origin.y * half_size) / half_size;
+		projection.w.x += offset_x;
+		projection.w.y += offset_y;
+
+		views.push(ShadowView {
+			view_projection: projection * view,
+			split_distance: far,
+			texel_size: 2.0 * radius / SHADOW_MAP_SIZE as f32,
+			is_perspective: false,
+		});
+		near = far;
+	}
+	views
+}
+
+// ? One 90 degrees frustum per cube map face
+pub fn point_shadow_views(light: &LightSource) -> Vec<ShadowView> {
+	let eye = Point3::from_vec(light.translation);
+	let far = light.range().unwrap_or(POINT_DEFAULT_FAR_PLANE);
+	let projection = perspective(Deg(90.0), 1.0, POINT_NEAR_PLANE, far);
+	let faces = [
+		(vec3(1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+		(vec3(-1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+		(vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0)),
+		(vec3(0.0, -1.0, 0.0), vec3(0.0, 0.0, -1.0)),
+		(vec3(0.0, 0.0, 1.0), vec3(0.0, -1.0, 0.0)),
+		(vec3(0.0, 0.0, -1.0), vec3(0.0, -1.0, 0.0)),
+	];
+	faces.iter().map(|&(direction, up)| ShadowView {
+		view_projection: projection * Matrix4::look_at_dir(eye, direction, up),
+		split_distance: far,
+		texel_size: 2.0 / POINT_SHADOW_MAP_SIZE as f32,
+		is_perspective: true,
+	}).collect()
+}
+
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+	if direction.normalize().y.abs() > 0.99 {
+		vec3(0.0, 0.0, 1.0)
+	} else {
+		vec3(0.0, 1.0, 0.0)
+	}
+}
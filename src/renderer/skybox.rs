@@ -0,0 +1,161 @@
+use cgmath::{vec4, Matrix4, SquareMatrix};
+use gfx;
+use gfx::traits::FactoryExt;
+
+use common::*;
+use renderer::{create_screen_triangle, shader_source, ScreenVertex};
+use renderer::ibl::{self, IblMaps};
+use renderer::tone_mapping::srgb_to_linear;
+use scene::camera::Camera;
+use scene::environment::Environment;
+
+/*
+..####...##..##..##..##..#####....####...##..##.
+.##......##.##....####...##..##..##..##...####..
+..####...####......##....#####...##..##....##...
+.....##..##.##.....##....##..##..##..##...####..
+..####...##..##....##....#####....####...##..##.
+................................................
+*/
+
+type EnvironmentSurface = gfx::format::R32_G32_B32_A32;
+pub type EnvironmentFormat = gfx::format::Rgba32F;
+
+gfx_defines!{
+	pipeline skybox_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		environment_map: gfx::TextureSampler<[f32; 4]> = "u_EnvironmentMap",
+		inverse_view_projection: gfx::Global<[[f32; 4]; 4]> = "u_InverseViewProjection",
+		out: gfx::RenderTarget<HdrColorFormat> = "Target0",
+	}
+}
+
+// ? Uploads cube faces given as RGBA texels in +X, -X, +Y, -Y, +Z, -Z order
+pub fn create_cube_map<R, F>(factory: &mut F, face_size: u16, faces: &[Vec<[f32; 4]>]) -> gfx::handle::ShaderResourceView<R, [f32; 4]>
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	let kind = gfx::texture::Kind::Cube(face_size);
+	let data: Vec<&[[u32; 4]]> = faces.iter().map(|face| gfx::memory::cast_slice(face)).collect();
+	let (_, view) = factory.create_texture_immutable::<EnvironmentFormat>(kind, gfx::texture::Mipmap::Provided, &data).unwrap();
+	view
+}
+
+// ? Every mip level of a cube, the largest first, each halving the face size
+fn create_cube_map_mips<R, F>(factory: &mut F, levels: &[&Environment]) -> gfx::handle::ShaderResourceView<R, [f32; 4]>
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	let kind = gfx::texture::Kind::Cube(levels[0].face_size as u16);
+	let level_faces: Vec<Vec<Vec<[f32; 4]>>> = levels.iter().map(|level| rgba_faces(level)).collect();
+	// ? Face major, the mip levels of a face are contiguous
+	let mut data: Vec<&[[u32; 4]]> = vec![];
+	for face in 0..6 {
+		for faces in &level_faces {
+			data.push(gfx::memory::cast_slice(&faces[face]));
+		}
+	}
+	let (_, view) = factory.create_texture_immutable::<EnvironmentFormat>(kind, gfx::texture::Mipmap::Provided, &data).unwrap();
+	view
+}
+
+fn rgba_faces(environment: &Environment) -> Vec<Vec<[f32; 4]>> {
+	environment.faces.iter().map(|face| {
+		face.iter().map(|texel| [texel[0], texel[1], texel[2], 1.0]).collect()
+	}).collect()
+}
+
+fn create_brdf_lut<R, F>(factory: &mut F) -> gfx::handle::ShaderResourceView<R, [f32; 4]>
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	let texels: Vec<[f32; 4]> = ibl::load_or_compute_brdf_lut().iter().map(|texel| [texel[0], texel[1], 0.0, 1.0]).collect();
+	let kind = gfx::texture::Kind::D2(ibl::BRDF_LUT_SIZE as u16, ibl::BRDF_LUT_SIZE as u16, gfx::texture::AaMode::Single);
+	let (_, view) = factory.create_texture_immutable::<EnvironmentFormat>(kind, gfx::texture::Mipmap::Provided, &[gfx::memory::cast_slice(&texels)]).unwrap();
+	view
+}
+
+// ? The scene's environment on the GPU, a single texel of the clear color when there is none
+pub struct EnvironmentMap<R: gfx::Resources> {
+	pub view: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub sampler: gfx::handle::Sampler<R>,
+	// ? Image-based lighting, black without an environment. The irradiance is the last mip level of `prefiltered`.
+	pub prefiltered: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub brdf_lut: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+	pub lut_sampler: gfx::handle::Sampler<R>,
+	has_skybox: bool,
+	pipeline_state: gfx::PipelineState<R, skybox_pipe::Meta>,
+	data: skybox_pipe::Data<R>,
+	slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> EnvironmentMap<R> {
+	pub fn new<F: gfx::Factory<R>>(
+		factory: &mut F,
+		environment: Option<&Environment>,
+		clear_color: [f32; 4],
+		out: gfx::handle::RenderTargetView<R, HdrColorFormat>,
+		rasterizer: gfx::state::Rasterizer
+	) -> Self {
+		let (view, prefiltered) = match environment {
+			Some(environment) => {
+				let maps = IblMaps::load_or_compute(environment);
+				let levels: Vec<&Environment> = maps.prefiltered.iter().chain(Some(&maps.irradiance)).collect();
+				(
+					create_cube_map(factory, environment.face_size as u16, &rgba_faces(environment)),
+					create_cube_map_mips(factory, &levels),
+				)
+			},
+			None => {
+				let linear_clear_color = srgb_to_linear(clear_color);
+				(
+					create_cube_map(factory, 1, &vec![vec![linear_clear_color]; 6]),
+					create_cube_map(factory, 1, &vec![vec![[0.0, 0.0, 0.0, 1.0]]; 6]),
+				)
+			},
+		};
+		let brdf_lut = create_brdf_lut(factory);
+		let sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Trilinear, gfx::texture::WrapMode::Clamp)
+		);
+		let lut_sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Bilinear, gfx::texture::WrapMode::Clamp)
+		);
+		let shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/skybox_150.glslf")])
+		).unwrap();
+		let pipeline_state = factory.create_pipeline_state(&shader_set, gfx::Primitive::TriangleList, rasterizer, skybox_pipe::new()).unwrap();
+		let (vbuf, slice) = create_screen_triangle(factory);
+		EnvironmentMap {
+			view: view.clone(),
+			sampler: sampler.clone(),
+			prefiltered,
+			brdf_lut,
+			lut_sampler,
+			has_skybox: environment.is_some(),
+			pipeline_state,
+			data: skybox_pipe::Data {
+				vbuf,
+				environment_map: (view, sampler),
+				inverse_view_projection: Matrix4::identity().into(),
+				out,
+			},
+			slice,
+		}
+	}
+
+	pub fn has_environment(&self) -> bool {
+		self.has_skybox
+	}
+
+	// ? Fills the background, meant to be drawn right after clearing, before any geometry
+	pub fn render_skybox<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, camera: &Camera) {
+		if !self.has_skybox {
+			return;
+		}
+		// ? Only the rotation of the view matters, the sky is infinitely far
+		let mut view_rotation = camera.view;
+		view_rotation.w = vec4(0.0, 0.0, 0.0, 1.0);
+		let inverse_view_projection = (camera.projection * view_rotation).invert().unwrap_or(Matrix4::identity());
+		self.data.inverse_view_projection = inverse_view_projection.into();
+		encoder.draw(&self.slice, &self.pipeline_state, &self.data);
+	}
+}
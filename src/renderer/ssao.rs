@@ -0,0 +1,289 @@
+use cgmath::Rad;
+use gfx;
+use gfx::traits::FactoryExt;
+use glutin;
+
+use common::*;
+use renderer::{create_screen_triangle, shader_source, ScreenVertex};
+use scene::Scene;
+
+/*
+..####....####....####....####..
+.##......##......##..##..##..##.
+..####....####...######..##..##.
+.....##......##..##..##..##..##.
+..####....####...##..##...####..
+................................
+*/
+
+// ? Keep in sync with ssao_150.glslf
+pub const MAX_SSAO_SAMPLES: usize = 64;
+const NOISE_SIZE: u16 = 4;
+
+pub type AmbientOcclusionFormat = gfx::format::Rgba16F;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SsaoSettings {
+	pub enabled: bool,
+	// ? View space radius of the sampled hemisphere
+	pub radius: f32,
+	pub intensity: f32,
+	pub sample_count: usize,
+	// ? Depth difference ignored to avoid self occlusion
+	pub bias: f32,
+}
+
+impl Default for SsaoSettings {
+	fn default() -> Self {
+		SsaoSettings {
+			enabled: true,
+			radius: 0.5,
+			intensity: 1.0,
+			sample_count: 16,
+			bias: 0.025,
+		}
+	}
+}
+
+gfx_defines!{
+	constant SsaoLocals {
+		projection: [[f32; 4]; 4] = "u_Projection",
+		view: [[f32; 4]; 4] = "u_View",
+		params: [f32; 4] = "u_SsaoParams",
+		frustum_scale: [f32; 4] = "u_FrustumScale",
+	}
+
+	constant SsaoSample {
+		offset: [f32; 4] = "offset",
+	}
+
+	// ? World normal and view depth of the nearest surface, the forward path has no G-buffer
+	pipeline ssao_prepass_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
+		out: gfx::RenderTarget<AmbientOcclusionFormat> = "Target0",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+	}
+
+	pipeline ssao_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		normal_depth: gfx::TextureSampler<[f32; 4]> = "u_NormalDepth",
+		noise: gfx::TextureSampler<[f32; 4]> = "u_Noise",
+		locals: gfx::ConstantBuffer<SsaoLocals> = "SsaoLocals",
+		kernel: gfx::ConstantBuffer<SsaoSample> = "b_ssao_kernel",
+		out: gfx::RenderTarget<AmbientOcclusionFormat> = "Target0",
+	}
+
+	// ? Depth aware blur, hides the noise pattern without bleeding across silhouettes
+	pipeline ssao_blur_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		occlusion: gfx::TextureSampler<[f32; 4]> = "u_Occlusion",
+		normal_depth: gfx::TextureSampler<[f32; 4]> = "u_NormalDepth",
+		out: gfx::RenderTarget<AmbientOcclusionFormat> = "Target0",
+	}
+}
+
+// ? Small deterministic generator, the kernel and noise only need to look random
+struct Lcg(u32);
+
+impl Lcg {
+	fn next(&mut self) -> f32 {
+		self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+		(self.0 >> 8) as f32 / (1 << 24) as f32
+	}
+}
+
+// ? Hemisphere samples around +Z, denser close to the origin
+fn create_kernel() -> Vec<SsaoSample> {
+	let mut random = Lcg(0x5eed);
+	(0..MAX_SSAO_SAMPLES).map(|i| {
+		let mut offset = [random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0, random.next(), 0.0];
+		let length = (offset[0] * offset[0] + offset[1] * offset[1] + offset[2] * offset[2]).sqrt().max(1e-4);
+		let t = i as f32 / MAX_SSAO_SAMPLES as f32;
+		let scale = random.next() * (0.1 + 0.9 * t * t) / length;
+		for component in offset.iter_mut().take(3) {
+			*component *= scale;
+		}
+		SsaoSample { offset }
+	}).collect()
+}
+
+// ? Random rotations around the normal, tiled over the screen
+fn create_noise() -> Vec<u8> {
+	let mut random = Lcg(0xa0);
+	let mut texels = vec![];
+	for _ in 0..NOISE_SIZE * NOISE_SIZE {
+		texels.extend_from_slice(&[(random.next() * 255.0) as u8, (random.next() * 255.0) as u8, 0, 255]);
+	}
+	texels
+}
+
+pub struct Ssao<R: gfx::Resources> {
+	pub settings: SsaoSettings,
+	prepass_pipeline_state: gfx::PipelineState<R, ssao_prepass_pipe::Meta>,
+	ssao_pipeline_state: gfx::PipelineState<R, ssao_pipe::Meta>,
+	blur_pipeline_state: gfx::PipelineState<R, ssao_blur_pipe::Meta>,
+	prepass_data: ssao_prepass_pipe::Data<R>,
+	ssao_data: ssao_pipe::Data<R>,
+	blur_data: ssao_blur_pipe::Data<R>,
+	slice: gfx::Slice<R>,
+	// ? Blurred occlusion in the red channel, read by the ambient term of the scene shaders
+	pub ambient_occlusion: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+}
+
+impl<R: gfx::Resources> Ssao<R> {
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, width: u16, height: u16) -> Self {
+		let prepass_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/basic_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/ssao_prepass_150.glslf")]),
+			ssao_prepass_pipe::new()
+		).unwrap();
+		let ssao_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/ssao_150.glslf")]),
+			ssao_pipe::new()
+		).unwrap();
+		let blur_pipeline_state = factory.create_pipeline_simple(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[include_bytes!("../shaders/ssao_blur_150.glslf")]),
+			ssao_blur_pipe::new()
+		).unwrap();
+
+		let (_, normal_depth_view, normal_depth_target) = factory.create_render_target::<AmbientOcclusionFormat>(width, height).unwrap();
+		let (_, occlusion_view, occlusion_target) = factory.create_render_target::<AmbientOcclusionFormat>(width, height).unwrap();
+		let (_, ambient_occlusion, ambient_occlusion_target) = factory.create_render_target::<AmbientOcclusionFormat>(width, height).unwrap();
+		let depth = factory.create_depth_stencil_view_only::<DepthFormat>(width, height).unwrap();
+
+		let noise_kind = gfx::texture::Kind::D2(NOISE_SIZE, NOISE_SIZE, gfx::texture::AaMode::Single);
+		let (_, noise) = factory.create_texture_immutable_u8::<ColorFormat>(noise_kind, gfx::texture::Mipmap::Provided, &[&create_noise()[..]]).unwrap();
+		let noise_sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Tile)
+		);
+		let sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+		// ? The kernel never changes
+		let kernel = factory.create_buffer_immutable(&create_kernel(), gfx::buffer::Role::Constant, gfx::memory::Bind::empty()).unwrap();
+		let (screen_buffer, slice) = create_screen_triangle(factory);
+		let empty_vertices: Vec<Vertex> = vec![];
+		let empty_buffer = factory.create_vertex_buffer(&empty_vertices);
+
+		Ssao {
+			settings: SsaoSettings::default(),
+			prepass_pipeline_state,
+			ssao_pipeline_state,
+			blur_pipeline_state,
+			prepass_data: ssao_prepass_pipe::Data {
+				vbuf: empty_buffer,
+				mvp: [[0.0; 4]; 4],
+				view_model: [[0.0; 4]; 4],
+				model: [[0.0; 4]; 4],
+				out: normal_depth_target,
+				out_depth: depth,
+			},
+			ssao_data: ssao_pipe::Data {
+				vbuf: screen_buffer.clone(),
+				normal_depth: (normal_depth_view.clone(), sampler.clone()),
+				noise: (noise, noise_sampler),
+				locals: factory.create_constant_buffer(1),
+				kernel,
+				out: occlusion_target,
+			},
+			blur_data: ssao_blur_pipe::Data {
+				vbuf: screen_buffer,
+				occlusion: (occlusion_view, sampler.clone()),
+				normal_depth: (normal_depth_view, sampler),
+				out: ambient_occlusion_target,
+			},
+			slice,
+			ambient_occlusion,
+		}
+	}
+
+	// ? Reads `--ssao-radius <view space radius>`, `--ssao-intensity <scale>` and `--ssao-samples <1 to MAX_SSAO_SAMPLES>`,
+	// ? other arguments are ignored
+	pub fn apply_args<I: Iterator<Item = String>>(&mut self, args: I) {
+		let args: Vec<String> = args.collect();
+		for pair in args.windows(2) {
+			if pair[0] == "--ssao-radius" {
+				match pair[1].parse::<f32>() {
+					Ok(radius) if radius > 0.0 => self.settings.radius = radius,
+					_ => println!("Invalid SSAO radius: {}", pair[1]),
+				}
+			} else if pair[0] == "--ssao-intensity" {
+				match pair[1].parse::<f32>() {
+					Ok(intensity) if intensity >= 0.0 => self.settings.intensity = intensity,
+					_ => println!("Invalid SSAO intensity: {}", pair[1]),
+				}
+			} else if pair[0] == "--ssao-samples" {
+				match pair[1].parse::<usize>() {
+					Ok(count) if count >= 1 && count <= MAX_SSAO_SAMPLES => self.settings.sample_count = count,
+					_ => println!("Invalid SSAO sample count: {}, expected 1 to {}", pair[1], MAX_SSAO_SAMPLES),
+				}
+			}
+		}
+	}
+
+	// ? O toggles the pass
+	pub fn handle_event(&mut self, event: &glutin::Event) {
+		if let glutin::Event::WindowEvent { event: glutin::WindowEvent::KeyboardInput { input, .. }, .. } = *event {
+			if input.state == glutin::ElementState::Pressed && input.virtual_keycode == Some(glutin::VirtualKeyCode::O) {
+				self.settings.enabled = !self.settings.enabled;
+				println!("SSAO: {}", if self.settings.enabled { "on" } else { "off" });
+			}
+		}
+	}
+
+	pub fn render<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)]
+	) {
+		if !self.settings.enabled {
+			encoder.clear(&self.blur_data.out, [1.0; 4]);
+			return;
+		}
+
+		// ? Normals and depth
+		encoder.clear(&self.prepass_data.out, [0.0; 4]);
+		encoder.clear_depth(&self.prepass_data.out_depth, 1.0);
+		for (object, &(ref vertex_buffer, ref slice)) in scene.objects.iter().zip(object_buffers) {
+			// ? What is seen through transparent objects is occluded by its own surroundings, not by them
+			if scene.is_transparent(object) {
+				continue;
+			}
+			let view_model_matrix = scene.camera.view * object.model_matrix();
+			self.prepass_data.vbuf = vertex_buffer.clone();
+			self.prepass_data.mvp = (scene.camera.projection * view_model_matrix).into();
+			self.prepass_data.view_model = view_model_matrix.into();
+			self.prepass_data.model = object.model_matrix().into();
+			encoder.draw(slice, &self.prepass_pipeline_state, &self.prepass_data);
+		}
+
+		// ? Hemisphere sampling, then the blur writes the final occlusion
+		let (width, height, ..) = self.prepass_data.out.get_dimensions();
+		let tan_half_fovy = (Rad::from(scene.camera.fovy).0 / 2.0).tan();
+		let locals = SsaoLocals {
+			projection: scene.camera.projection.into(),
+			view: scene.camera.view.into(),
+			params: [
+				self.settings.radius,
+				self.settings.intensity,
+				self.settings.sample_count.min(MAX_SSAO_SAMPLES) as f32,
+				self.settings.bias,
+			],
+			frustum_scale: [
+				tan_half_fovy * scene.camera.aspect_ratio,
+				tan_half_fovy,
+				width as f32 / NOISE_SIZE as f32,
+				height as f32 / NOISE_SIZE as f32,
+			],
+		};
+		encoder.update_buffer(&self.ssao_data.locals, &[locals], 0).unwrap();
+		encoder.draw(&self.slice, &self.ssao_pipeline_state, &self.ssao_data);
+		encoder.draw(&self.slice, &self.blur_pipeline_state, &self.blur_data);
+	}
+}
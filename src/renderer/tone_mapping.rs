@@ -0,0 +1,86 @@
+/*
+.######...####...##..##..######..........##...##...####...#####...#####...######..##..##...####..
+...##....##..##..###.##..##..............###.###..##..##..##..##..##..##....##....###.##..##.....
+...##....##..##..##.###..####............##.#.##..######..#####...#####.....##....##.###..##.###.
+...##....##..##..##..##..##..............##...##..##..##..##......##........##....##..##..##..##.
+...##.....####...##..##..######..........##...##..##..##..##......##......######..##..##...####..
+.................................................................................................
+*/
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ToneMappingOperator {
+	// ? Exposure only, anything above 1 clips
+	Clamp,
+	Reinhard,
+	// ? Krzysztof Narkowicz's fit of the ACES filmic curve
+	AcesFilmic,
+}
+
+impl ToneMappingOperator {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_lowercase().as_str() {
+			"clamp" | "none" => Some(ToneMappingOperator::Clamp),
+			"reinhard" => Some(ToneMappingOperator::Reinhard),
+			"aces" | "filmic" => Some(ToneMappingOperator::AcesFilmic),
+			_ => None,
+		}
+	}
+
+	pub fn shader_id(&self) -> i32 {
+		match *self {
+			ToneMappingOperator::Clamp => 0,
+			ToneMappingOperator::Reinhard => 1,
+			ToneMappingOperator::AcesFilmic => 2,
+		}
+	}
+
+	pub fn next(&self) -> Self {
+		match *self {
+			ToneMappingOperator::Clamp => ToneMappingOperator::Reinhard,
+			ToneMappingOperator::Reinhard => ToneMappingOperator::AcesFilmic,
+			ToneMappingOperator::AcesFilmic => ToneMappingOperator::Clamp,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ToneMapping {
+	pub operator: ToneMappingOperator,
+	// ? Linear scale applied before the operator
+	pub exposure: f32,
+}
+
+impl Default for ToneMapping {
+	fn default() -> Self {
+		ToneMapping {
+			operator: ToneMappingOperator::AcesFilmic,
+			exposure: 1.0,
+		}
+	}
+}
+
+impl ToneMapping {
+	// ? Reads `--tone-mapping <clamp|reinhard|aces>` and `--exposure <value>`, other arguments are ignored
+	pub fn apply_args<I: Iterator<Item = String>>(&mut self, args: I) {
+		let args: Vec<String> = args.collect();
+		for pair in args.windows(2) {
+			match pair[0].as_str() {
+				"--tone-mapping" => match ToneMappingOperator::from_name(&pair[1]) {
+					Some(operator) => self.operator = operator,
+					None => println!("Unknown tone mapping operator: {}", pair[1]),
+				},
+				"--exposure" => match pair[1].parse::<f32>() {
+					Ok(exposure) if exposure > 0.0 => self.exposure = exposure,
+					_ => println!("Invalid exposure: {}", pair[1]),
+				},
+				_ => {}
+			}
+		}
+	}
+}
+
+// ? The scene's clear color is given in sRGB, the HDR target holds linear values
+pub fn srgb_to_linear(color: [f32; 4]) -> [f32; 4] {
+	let decode = |c: f32| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+	[decode(color[0]), decode(color[1]), decode(color[2]), color[3]]
+}
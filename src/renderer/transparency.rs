@@ -0,0 +1,316 @@
+use cgmath::{EuclideanSpace, Point3, Transform};
+use gfx;
+use gfx::traits::FactoryExt;
+use glutin;
+
+use common::*;
+use renderer::{create_screen_triangle, msaa, shader_source, ScreenVertex, TextureLibrary};
+use renderer::{CLUSTERS_GLSL, FORWARD_GLSL, FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL};
+use scene::Scene;
+
+/*
+.######..#####....####...##..##...####...#####....####...#####...######..##..##...####...##..##.
+...##....##..##..##..##..###.##..##......##..##..##..##..##..##..##......###.##..##..##...####..
+...##....#####...######..##.###...####...#####...######..#####...####....##.###..##.......##...
+...##....##..##..##..##..##..##......##..##......##..##..##..##..##......##..##..##..##...##...
+...##....##..##..##..##..##..##...####...##......##..##..##..##..######..##..##...####....##...
+................................................................................................
+*/
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransparencyMode {
+	// ? Objects blended back to front by the view depth of their center, exact unless objects intersect
+	Sorted,
+	// ? Weighted blended order-independent transparency, see: http://jcgt.org/published/0002/02/09/
+	WeightedBlended,
+}
+
+impl TransparencyMode {
+	pub fn toggled(&self) -> Self {
+		match *self {
+			TransparencyMode::Sorted => TransparencyMode::WeightedBlended,
+			TransparencyMode::WeightedBlended => TransparencyMode::Sorted,
+		}
+	}
+}
+
+pub type OitFormat = gfx::format::Rgba16F;
+
+gfx_defines!{
+	// ? Same inputs as the forward pipe, writing weighted color and revealage instead of blending into the scene
+	pipeline oit_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		ambient_texture: gfx::TextureSampler<[f32; 4]> = "u_AmbientTexture",
+		diffuse_texture: gfx::TextureSampler<[f32; 4]> = "u_DiffuseTexture",
+		specular_texture: gfx::TextureSampler<[f32; 4]> = "u_SpecularTexture",
+		normal_texture: gfx::TextureSampler<[f32; 4]> = "u_NormalTexture",
+		dissolve_texture: gfx::TextureSampler<[f32; 4]> = "u_DissolveTexture",
+		metallic_texture: gfx::TextureSampler<[f32; 4]> = "u_MetallicTexture",
+		roughness_texture: gfx::TextureSampler<[f32; 4]> = "u_RoughnessTexture",
+		emissive_texture: gfx::TextureSampler<[f32; 4]> = "u_EmissiveTexture",
+		occlusion_texture: gfx::TextureSampler<[f32; 4]> = "u_OcclusionTexture",
+		ps_locals: gfx::ConstantBuffer<ForwardLocals> = "Locals",
+		material_locals: gfx::ConstantBuffer<MaterialLocals> = "MaterialLocals",
+		ambient_occlusion: gfx::TextureSampler<[f32; 4]> = "u_AmbientOcclusion",
+		environment_map: gfx::TextureSampler<[f32; 4]> = "u_EnvironmentMap",
+		prefiltered_map: gfx::TextureSampler<[f32; 4]> = "u_PrefilteredMap",
+		brdf_lut: gfx::TextureSampler<[f32; 4]> = "u_BrdfLut",
+		cluster_data: gfx::TextureSampler<[f32; 4]> = "u_ClusterData",
+		shadow_maps: gfx::TextureSampler<f32> = "u_ShadowMaps",
+		shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_shadow_maps",
+		point_shadow_maps: gfx::TextureSampler<f32> = "u_PointShadowMaps",
+		point_shadow_maps_info: gfx::ConstantBuffer<ShadowMapInfo> = "b_point_shadow_maps",
+		accumulation: gfx::BlendTarget<OitFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::state::Blend::new(
+			gfx::state::Equation::Add,
+			gfx::state::Factor::One,
+			gfx::state::Factor::One
+		)),
+		revealage: gfx::BlendTarget<OitFormat> = ("Target1", gfx::state::ColorMask::all(), gfx::state::Blend::new(
+			gfx::state::Equation::Add,
+			gfx::state::Factor::Zero,
+			gfx::state::Factor::OneMinus(gfx::state::BlendValue::SourceColor)
+		)),
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_TEST,
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_MVP",
+		view_model: gfx::Global<[[f32; 4]; 4]> = "u_ViewModel",
+		model: gfx::Global<[[f32; 4]; 4]> = "u_Model",
+	}
+
+	// ? Blends the averaged transparent color over the scene, weighted by how much of it is still visible
+	pipeline oit_composite_pipe {
+		vbuf: gfx::VertexBuffer<ScreenVertex> = (),
+		accumulation: gfx::TextureSampler<[f32; 4]> = "u_Accumulation",
+		revealage: gfx::TextureSampler<[f32; 4]> = "u_Revealage",
+		sample_count: gfx::Global<i32> = "u_SampleCount",
+		out: gfx::BlendTarget<HdrColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::state::Blend::new(
+			gfx::state::Equation::Add,
+			gfx::state::Factor::OneMinus(gfx::state::BlendValue::SourceAlpha),
+			gfx::state::Factor::ZeroPlus(gfx::state::BlendValue::SourceAlpha)
+		)),
+	}
+
+	// ? Opaque depth for the deferred path, whose G-buffer has a depth buffer of its own
+	pipeline depth_pipe {
+		vbuf: gfx::VertexBuffer<Vertex> = (),
+		mvp: gfx::Global<[[f32; 4]; 4]> = "u_LightMVP",
+		out_depth: gfx::DepthTarget<DepthFormat> = gfx::preset::depth::LESS_EQUAL_WRITE,
+	}
+}
+
+// ? Floating point target matching the scene's sample count, readable by the composite pass
+fn create_oit_target<R, F>(factory: &mut F, width: u16, height: u16, sample_count: u16)
+	-> (gfx::handle::ShaderResourceView<R, [f32; 4]>, gfx::handle::RenderTargetView<R, OitFormat>)
+	where F: gfx::Factory<R>, R: gfx::Resources
+{
+	let aa_mode = if sample_count > 1 {
+		gfx::texture::AaMode::Multi(sample_count as gfx::texture::NumSamples)
+	} else {
+		gfx::texture::AaMode::Single
+	};
+	let texture = factory.create_texture::<gfx::format::R16_G16_B16_A16>(
+		gfx::texture::Kind::D2(width, height, aa_mode), 1,
+		gfx::memory::Bind::RENDER_TARGET | gfx::memory::Bind::SHADER_RESOURCE,
+		gfx::memory::Usage::Data,
+		Some(gfx::format::ChannelType::Float)
+	).unwrap();
+	let target = factory.view_texture_as_render_target::<OitFormat>(&texture, 0, None).unwrap();
+	let view = factory.view_texture_as_shader_resource::<OitFormat>(&texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+	(view, target)
+}
+
+pub struct TransparencyRenderer<R: gfx::Resources> {
+	pub mode: TransparencyMode,
+	sorted_pipeline_state: gfx::PipelineState<R, pipe::Meta>,
+	oit_pipeline_state: gfx::PipelineState<R, oit_pipe::Meta>,
+	composite_pipeline_state: gfx::PipelineState<R, oit_composite_pipe::Meta>,
+	depth_pipeline_state: gfx::PipelineState<R, depth_pipe::Meta>,
+	oit_data: oit_pipe::Data<R>,
+	composite_data: oit_composite_pipe::Data<R>,
+	depth_data: depth_pipe::Data<R>,
+	screen_slice: gfx::Slice<R>,
+}
+
+impl<R: gfx::Resources> TransparencyRenderer<R> {
+	// ? Shares the per-frame locals, lighting and shadow resources of the forward pipeline
+	pub fn new<F: gfx::Factory<R>>(factory: &mut F, forward_data: &pipe::Data<R>, sample_count: u16) -> Self {
+		let rasterizer = msaa::rasterizer(sample_count);
+		let sorted_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/basic_150.glslv"),
+			&shader_source(&[FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL, CLUSTERS_GLSL, FORWARD_GLSL, include_bytes!("../shaders/basic_150.glslf")])
+		).unwrap();
+		// ? Blended over the opaque scene, tested against its depth without writing to it
+		let sorted_init = pipe::Init {
+			out_depth: gfx::preset::depth::LESS_EQUAL_TEST,
+			.. pipe::new()
+		};
+		let sorted_pipeline_state = factory.create_pipeline_state(&sorted_shader_set, gfx::Primitive::TriangleList, rasterizer, sorted_init).unwrap();
+		let oit_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/basic_150.glslv"),
+			&shader_source(&[FRAME_GLSL, LIGHTING_GLSL, MATERIAL_GLSL, CLUSTERS_GLSL, FORWARD_GLSL, include_bytes!("../shaders/oit_accumulate_150.glslf")])
+		).unwrap();
+		let oit_pipeline_state = factory.create_pipeline_state(&oit_shader_set, gfx::Primitive::TriangleList, rasterizer, oit_pipe::new()).unwrap();
+		let multisample_define: &[u8] = if sample_count > 1 { b"#define MULTISAMPLED" } else { b"" };
+		let composite_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/screen_150.glslv"),
+			&shader_source(&[multisample_define, include_bytes!("../shaders/oit_composite_150.glslf")])
+		).unwrap();
+		let composite_pipeline_state = factory.create_pipeline_state(&composite_shader_set, gfx::Primitive::TriangleList, rasterizer, oit_composite_pipe::new()).unwrap();
+		let depth_shader_set = factory.create_shader_set(
+			include_bytes!("../shaders/shadow_150.glslv"),
+			include_bytes!("../shaders/shadow_150.glslf")
+		).unwrap();
+		let depth_pipeline_state = factory.create_pipeline_state(&depth_shader_set, gfx::Primitive::TriangleList, rasterizer, depth_pipe::new()).unwrap();
+
+		let (width, height, ..) = forward_data.out.get_dimensions();
+		let (accumulation_view, accumulation_target) = create_oit_target(factory, width, height, sample_count);
+		let (revealage_view, revealage_target) = create_oit_target(factory, width, height, sample_count);
+		let point_sampler = factory.create_sampler(
+			gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp)
+		);
+		let (screen_buffer, screen_slice) = create_screen_triangle(factory);
+
+		TransparencyRenderer {
+			mode: TransparencyMode::Sorted,
+			sorted_pipeline_state,
+			oit_pipeline_state,
+			composite_pipeline_state,
+			depth_pipeline_state,
+			oit_data: oit_pipe::Data {
+				vbuf: forward_data.vbuf.clone(),
+				ambient_texture: forward_data.ambient_texture.clone(),
+				diffuse_texture: forward_data.diffuse_texture.clone(),
+				specular_texture: forward_data.specular_texture.clone(),
+				normal_texture: forward_data.normal_texture.clone(),
+				dissolve_texture: forward_data.dissolve_texture.clone(),
+				metallic_texture: forward_data.metallic_texture.clone(),
+				roughness_texture: forward_data.roughness_texture.clone(),
+				emissive_texture: forward_data.emissive_texture.clone(),
+				occlusion_texture: forward_data.occlusion_texture.clone(),
+				ps_locals: forward_data.ps_locals.clone(),
+				material_locals: forward_data.material_locals.clone(),
+				ambient_occlusion: forward_data.ambient_occlusion.clone(),
+				environment_map: forward_data.environment_map.clone(),
+				prefiltered_map: forward_data.prefiltered_map.clone(),
+				brdf_lut: forward_data.brdf_lut.clone(),
+				cluster_data: forward_data.cluster_data.clone(),
+				shadow_maps: forward_data.shadow_maps.clone(),
+				shadow_maps_info: forward_data.shadow_maps_info.clone(),
+				point_shadow_maps: forward_data.point_shadow_maps.clone(),
+				point_shadow_maps_info: forward_data.point_shadow_maps_info.clone(),
+				accumulation: accumulation_target,
+				revealage: revealage_target,
+				out_depth: forward_data.out_depth.clone(),
+				mvp: forward_data.mvp,
+				view_model: forward_data.view_model,
+				model: forward_data.model,
+			},
+			composite_data: oit_composite_pipe::Data {
+				vbuf: screen_buffer,
+				accumulation: (accumulation_view, point_sampler.clone()),
+				revealage: (revealage_view, point_sampler),
+				sample_count: sample_count.max(1) as i32,
+				out: forward_data.out.clone(),
+			},
+			depth_data: depth_pipe::Data {
+				vbuf: forward_data.vbuf.clone(),
+				mvp: forward_data.mvp,
+				out_depth: forward_data.out_depth.clone(),
+			},
+			screen_slice,
+		}
+	}
+
+	// ? Reads `--oit`, other arguments are ignored
+	pub fn apply_args<I: Iterator<Item = String>>(&mut self, args: I) {
+		for arg in args {
+			if arg == "--oit" {
+				self.mode = TransparencyMode::WeightedBlended;
+			}
+		}
+	}
+
+	// ? F2 switches between sorted and order-independent transparency
+	pub fn handle_event(&mut self, event: &glutin::Event) {
+		if let glutin::Event::WindowEvent { event: glutin::WindowEvent::KeyboardInput { input, .. }, .. } = *event {
+			if input.state == glutin::ElementState::Pressed && input.virtual_keycode == Some(glutin::VirtualKeyCode::F2) {
+				self.mode = self.mode.toggled();
+				println!("Transparency: {:?}", self.mode);
+			}
+		}
+	}
+
+	// ? Writes the depth of the opaque objects into the forward depth target, for paths that didn't rasterize them there
+	pub fn render_opaque_depth<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)]
+	) {
+		for (object, &(ref vertex_buffer, ref slice)) in scene.objects.iter().zip(object_buffers) {
+			if scene.is_transparent(object) {
+				continue;
+			}
+			self.depth_data.vbuf = vertex_buffer.clone();
+			self.depth_data.mvp = (scene.camera.vp_matrix() * object.model_matrix()).into();
+			encoder.draw(slice, &self.depth_pipeline_state, &self.depth_data);
+		}
+	}
+
+	// ? Draws the transparent objects over the opaque scene, expects the forward data to be up to date for this frame
+	pub fn render<C: gfx::CommandBuffer<R>>(
+		&mut self,
+		encoder: &mut gfx::Encoder<R, C>,
+		scene: &Scene,
+		object_buffers: &[(gfx::handle::Buffer<R, Vertex>, gfx::Slice<R>)],
+		texture_library: &TextureLibrary<R>,
+		forward_data: &mut pipe::Data<R>
+	) {
+		let mut transparent_objects: Vec<(usize, f32)> = scene.objects.iter().enumerate()
+			.filter(|&(_, object)| scene.is_transparent(object))
+			.map(|(index, object)| {
+				let (min, max) = object.bounds();
+				let center = Point3::from_vec((min + max) * 0.5);
+				let view_center = (scene.camera.view * object.model_matrix()).transform_point(center);
+				(index, -view_center.z)
+			})
+			.collect();
+		if transparent_objects.is_empty() {
+			return;
+		}
+
+		if self.mode == TransparencyMode::WeightedBlended {
+			encoder.clear(&self.oit_data.accumulation, [0.0; 4]);
+			encoder.clear(&self.oit_data.revealage, [1.0; 4]);
+		}
+		// ? Farthest first, only matters for the sorted mode
+		transparent_objects.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+		for &(index, _) in &transparent_objects {
+			let object = &scene.objects[index];
+			let (ref vertex_buffer, ref slice) = object_buffers[index];
+			let view_model_matrix = scene.camera.view * object.model_matrix();
+			let material = scene.object_material(object);
+			let textures = texture_library.material_textures(material);
+			match self.mode {
+				TransparencyMode::Sorted => {
+					forward_data.vbuf = vertex_buffer.clone();
+					forward_data.mvp = (scene.camera.projection * view_model_matrix).into();
+					forward_data.view_model = view_model_matrix.into();
+					forward_data.model = object.model_matrix().into();
+					textures.bind(encoder, forward_data, material);
+					encoder.draw(slice, &self.sorted_pipeline_state, forward_data);
+				},
+				TransparencyMode::WeightedBlended => {
+					self.oit_data.vbuf = vertex_buffer.clone();
+					self.oit_data.mvp = (scene.camera.projection * view_model_matrix).into();
+					self.oit_data.view_model = view_model_matrix.into();
+					self.oit_data.model = object.model_matrix().into();
+					textures.bind(encoder, &mut self.oit_data, material);
+					encoder.draw(slice, &self.oit_pipeline_state, &self.oit_data);
+				},
+			}
+		}
+		if self.mode == TransparencyMode::WeightedBlended {
+			encoder.draw(&self.screen_slice, &self.composite_pipeline_state, &self.composite_data);
+		}
+	}
+}
@@ -1,5 +1,6 @@
 
-use cgmath::{perspective, Deg, Matrix4, Point3, vec3, Rad, Vector3};
+use cgmath::{perspective, Deg, Matrix4, Point3, vec3, vec4, Rad, Vector3};
+use cgmath::{EuclideanSpace, SquareMatrix};
 
 use scene::entity::Entity3D;
 
@@ -12,24 +13,58 @@ use scene::entity::Entity3D;
 .................................................
 */
 
+#[derive(Clone)]
 pub struct Camera {
 	pub eye: Point3<f32>,
 	pub center: Point3<f32>,
 	pub up: Vector3<f32>,
 	pub view: Matrix4<f32>,
 	pub projection: Matrix4<f32>,
+	pub fovy: Deg<f32>,
+	pub aspect_ratio: f32,
+	pub near: f32,
+	pub far: f32,
 }
 impl Camera {
 	pub fn new(eye: Point3<f32>, center: Point3<f32>, up: Vector3<f32>, aspect_ratio: f32) -> Self {
+		let fovy = Deg(45.0);
+		let near = 0.1;
+		let far = 100.0;
 		Camera {
 			eye,
 			center,
 			up,
 			view: Matrix4::look_at(eye, center, up),
-			projection: perspective(Deg(45.0), aspect_ratio, 0.1, 100.0),
+			projection: perspective(fovy, aspect_ratio, near, far),
+			fovy,
+			aspect_ratio,
+			near,
+			far,
 		}
 	}
 
+	// ? `eye` is only the initial position, translations and rotations are applied to the view matrix
+	pub fn position(&self) -> Point3<f32> {
+		let inverse_view = self.view.invert().unwrap_or(Matrix4::identity());
+		Point3::from_vec((inverse_view * vec4(0.0, 0.0, 0.0, 1.0)).truncate())
+	}
+
+	// ? World space corners of the frustum slice between the `near` and `far` view distances
+	pub fn frustum_corners(&self, near: f32, far: f32) -> Vec<Point3<f32>> {
+		let inverse_view = self.view.invert().unwrap_or(Matrix4::identity());
+		let tan_half_fovy = (Rad::from(self.fovy).0 / 2.0).tan();
+		let mut corners = vec![];
+		for distance in &[near, far] {
+			let half_height = distance * tan_half_fovy;
+			let half_width = half_height * self.aspect_ratio;
+			for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+				let corner = inverse_view * vec4(x * half_width, y * half_height, -distance, 1.0);
+				corners.push(Point3::from_vec(corner.truncate()));
+			}
+		}
+		corners
+	}
+
 	pub fn vp_matrix(&self) -> Matrix4<f32> {
 		self.projection * self.view
 	}
@@ -0,0 +1,184 @@
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use cgmath::{vec3, InnerSpace, Vector3};
+use image;
+
+/*
+.######..##..##..##..##..######..#####....####...##..##..##...##..######..##..##..######.
+.##......###.##..##..##....##....##..##..##..##..###.##..###.###..##......###.##....##...
+.####....##.###..##..##....##....#####...##..##..##.###..##.#.##..####....##.###....##...
+.##......##..##...####.....##....##..##..##..##..##..##..##...##..##......##..##....##...
+.######..##..##....##....######..##..##...####...##..##..##...##..######..##..##....##...
+.........................................................................................
+*/
+
+// ? Face file names of a skybox directory, in +X, -X, +Y, -Y, +Z, -Z order
+const FACE_NAMES: [&str; 6] = ["right", "left", "top", "bottom", "front", "back"];
+const FACE_EXTENSIONS: [&str; 4] = ["hdr", "png", "jpg", "jpeg"];
+
+// ? Surroundings of the scene as a cube map, used for the skybox, reflections and image-based lighting
+pub struct Environment {
+	pub face_size: u32,
+	// ? Linear RGB texels of the six faces in +X, -X, +Y, -Y, +Z, -Z order, first row at the top
+	pub faces: Vec<Vec<[f32; 3]>>,
+}
+
+impl Environment {
+	// ? A directory holds six face images, anything else is an equirectangular panorama
+	pub fn load(path: &Path) -> Result<Self, String> {
+		if path.is_dir() {
+			let mut face_paths = vec![];
+			for name in FACE_NAMES.iter() {
+				let face_path = FACE_EXTENSIONS.iter()
+					.map(|extension| path.join(format!("{}.{}", name, extension)))
+					.find(|face_path| face_path.exists())
+					.ok_or(format!("{}: missing the {} face", path.display(), name))?;
+				face_paths.push(face_path);
+			}
+			let faces = face_paths.iter().map(|face_path| load_linear_image(face_path)).collect::<Result<Vec<_>, _>>()?;
+			Self::from_faces(faces)
+		} else {
+			Self::from_equirectangular(load_linear_image(path)?)
+		}
+	}
+
+	pub fn from_faces(faces: Vec<(u32, u32, Vec<[f32; 3]>)>) -> Result<Self, String> {
+		let face_size = faces.first().map_or(0, |face| face.0);
+		if faces.len() != 6 || face_size == 0 || faces.iter().any(|&(width, height, _)| width != face_size || height != face_size) {
+			return Err("Cube map faces must be six square images of the same size".to_string());
+		}
+		Ok(Environment {
+			face_size,
+			faces: faces.into_iter().map(|(_, _, texels)| texels).collect(),
+		})
+	}
+
+	pub fn from_equirectangular((width, height, texels): (u32, u32, Vec<[f32; 3]>)) -> Result<Self, String> {
+		if width == 0 || height == 0 {
+			return Err("Empty panorama".to_string());
+		}
+		let face_size = (width / 4).max(1);
+		let faces = (0..6).map(|face| {
+			let mut face_texels = Vec::with_capacity((face_size * face_size) as usize);
+			for y in 0..face_size {
+				for x in 0..face_size {
+					let direction = texel_direction(face, x, y, face_size);
+					let u = 0.5 + direction.x.atan2(-direction.z) / (2.0 * PI);
+					let v = direction.y.max(-1.0).min(1.0).acos() / PI;
+					face_texels.push(sample_bilinear(&texels, width, height, u * width as f32, v * height as f32));
+				}
+			}
+			face_texels
+		}).collect();
+		Ok(Environment { face_size, faces })
+	}
+
+	// ? Nearest texel in the given direction
+	pub fn sample(&self, direction: Vector3<f32>) -> [f32; 3] {
+		let (face, s, t) = direction_to_face(direction);
+		let size = self.face_size as f32;
+		let x = ((s * 0.5 + 0.5) * size).max(0.0).min(size - 1.0) as u32;
+		let y = ((t * 0.5 + 0.5) * size).max(0.0).min(size - 1.0) as u32;
+		self.faces[face][(y * self.face_size + x) as usize]
+	}
+
+	// ? Box filtered copy with faces no larger than max_face_size, keeps convolutions cheap and unaliased
+	pub fn downsampled(&self, max_face_size: u32) -> Self {
+		let factor = ((self.face_size + max_face_size - 1) / max_face_size.max(1)).max(1);
+		let face_size = (self.face_size / factor).max(1);
+		let faces = self.faces.iter().map(|texels| {
+			let mut face_texels = Vec::with_capacity((face_size * face_size) as usize);
+			for y in 0..face_size {
+				for x in 0..face_size {
+					let mut color = [0.0; 3];
+					for sy in (y * factor)..((y + 1) * factor).min(self.face_size) {
+						for sx in (x * factor)..((x + 1) * factor).min(self.face_size) {
+							let texel = texels[(sy * self.face_size + sx) as usize];
+							for c in 0..3 {
+								color[c] += texel[c];
+							}
+						}
+					}
+					let count = (factor * factor) as f32;
+					face_texels.push([color[0] / count, color[1] / count, color[2] / count]);
+				}
+			}
+			face_texels
+		}).collect();
+		Environment { face_size, faces }
+	}
+}
+
+// ? OpenGL cube map convention, (s, t) in [-1, 1] with t growing downwards in the face image
+pub fn face_direction(face: usize, s: f32, t: f32) -> Vector3<f32> {
+	match face {
+		0 => vec3(1.0, -t, -s),
+		1 => vec3(-1.0, -t, s),
+		2 => vec3(s, 1.0, t),
+		3 => vec3(s, -1.0, -t),
+		4 => vec3(s, -t, 1.0),
+		_ => vec3(-s, -t, -1.0),
+	}.normalize()
+}
+
+pub fn texel_direction(face: usize, x: u32, y: u32, face_size: u32) -> Vector3<f32> {
+	let s = 2.0 * (x as f32 + 0.5) / face_size as f32 - 1.0;
+	let t = 2.0 * (y as f32 + 0.5) / face_size as f32 - 1.0;
+	face_direction(face, s, t)
+}
+
+// ? Inverse of face_direction
+pub fn direction_to_face(direction: Vector3<f32>) -> (usize, f32, f32) {
+	let (x, y, z) = (direction.x, direction.y, direction.z);
+	let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+	if ax >= ay && ax >= az {
+		if x > 0.0 { (0, -z / ax, -y / ax) } else { (1, z / ax, -y / ax) }
+	} else if ay >= az {
+		if y > 0.0 { (2, x / ay, z / ay) } else { (3, x / ay, -z / ay) }
+	} else {
+		if z > 0.0 { (4, x / az, -y / az) } else { (5, -x / az, -y / az) }
+	}
+}
+
+// ? Wraps horizontally, clamps vertically
+fn sample_bilinear(texels: &[[f32; 3]], width: u32, height: u32, x: f32, y: f32) -> [f32; 3] {
+	let x = x - 0.5;
+	let y = (y - 0.5).max(0.0).min(height as f32 - 1.0);
+	let (x0, y0) = (x.floor(), y.floor());
+	let (fx, fy) = (x - x0, y - y0);
+	let texel = |tx: i64, ty: i64| {
+		let tx = ((tx % width as i64 + width as i64) % width as i64) as u32;
+		let ty = ty.max(0).min(height as i64 - 1) as u32;
+		texels[(ty * width + tx) as usize]
+	};
+	let (x0, y0) = (x0 as i64, y0 as i64);
+	let (a, b, c, d) = (texel(x0, y0), texel(x0 + 1, y0), texel(x0, y0 + 1), texel(x0 + 1, y0 + 1));
+	let mut color = [0.0; 3];
+	for i in 0..3 {
+		color[i] = (a[i] * (1.0 - fx) + b[i] * fx) * (1.0 - fy) + (c[i] * (1.0 - fx) + d[i] * fx) * fy;
+	}
+	color
+}
+
+// ? Radiance .hdr files are already linear, other formats are decoded from sRGB
+fn load_linear_image(path: &Path) -> Result<(u32, u32, Vec<[f32; 3]>), String> {
+	let is_hdr = path.extension().map_or(false, |extension| extension.to_string_lossy().to_lowercase() == "hdr");
+	if is_hdr {
+		let file = File::open(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+		let decoder = image::hdr::HDRDecoder::new(BufReader::new(file)).map_err(|error| format!("{}: {}", path.display(), error))?;
+		let metadata = decoder.metadata();
+		let pixels = decoder.read_image_hdr().map_err(|error| format!("{}: {}", path.display(), error))?;
+		Ok((metadata.width, metadata.height, pixels.iter().map(|pixel| pixel.data).collect()))
+	} else {
+		let rgb_image = image::open(path).map_err(|error| format!("{}: {}", path.display(), error))?.to_rgb();
+		let (width, height) = rgb_image.dimensions();
+		let decode = |c: u8| {
+			let c = c as f32 / 255.0;
+			if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+		};
+		Ok((width, height, rgb_image.pixels().map(|pixel| [decode(pixel.data[0]), decode(pixel.data[1]), decode(pixel.data[2])]).collect()))
+	}
+}
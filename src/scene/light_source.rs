@@ -1,8 +1,10 @@
 
-use cgmath::{perspective, Deg, Matrix4, Point3, vec3, Rad, Vector3};
+use cgmath::{perspective, Deg, Matrix4, Point3, vec3, vec4, Rad, Vector3};
+use cgmath::InnerSpace;
 use gfx::shade::ToUniform;
 use gfx::shade::core::UniformValue;
 
+use common::LightSourceInfo;
 use scene::entity::Entity3D;
 
 /*
@@ -14,27 +16,97 @@ use scene::entity::Entity3D;
 ........................................................................................
 */
 
-// ? WIP
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Attenuation {
+	// ? 1 / (constant + linear * d + quadratic * d^2)
+	Coefficients { constant: f32, linear: f32, quadratic: f32 },
+	// ? Inverse square falloff smoothly windowed to reach zero at `range`
+	Range(f32),
+}
+
+impl Attenuation {
+	// ? Packed as (constant, linear, quadratic, range), a range of 0 selects the coefficients
+	pub fn to_array(&self) -> [f32; 4] {
+		match *self {
+			Attenuation::Coefficients { constant, linear, quadratic } => [constant, linear, quadratic, 0.0],
+			Attenuation::Range(range) => [0.0, 0.0, 0.0, range],
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+	// ? Shines along the light's rotation, its translation is ignored
+	Directional,
+	Point { attenuation: Attenuation },
+	// ? Cone around the light's rotation, angles are measured from its axis
+	Spot { attenuation: Attenuation, inner_cone: Deg<f32>, outer_cone: Deg<f32> },
+}
+
+impl LightKind {
+	// ? Value of `Light.pos.w` in the fragment shader
+	pub fn shader_id(&self) -> i32 {
+		match *self {
+			LightKind::Directional => 0,
+			LightKind::Point { .. } => 1,
+			LightKind::Spot { .. } => 2,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+	pub casts_shadows: bool,
+	// ? Constant depth bias, in the light's normalized depth
+	pub bias: f32,
+	// ? World space offset of the receiver along its normal, scaled by the shadow map texel size
+	pub normal_bias: f32,
+	// ? Number of cascades splitting the camera frustum, only used by directional lights
+	pub cascade_count: usize,
+}
+
+impl Default for ShadowSettings {
+	fn default() -> Self {
+		ShadowSettings {
+			casts_shadows: false,
+			bias: 0.0005,
+			normal_bias: 1.5,
+			cascade_count: 4,
+		}
+	}
+}
+
 pub struct LightSource {
+	pub kind: LightKind,
 	pub color: [f32; 3],
+	pub intensity: f32,
 	pub translation: Vector3<f32>,
 	pub rotation: Matrix4<f32>,
 	pub scale: f32,
+	pub shadow: ShadowSettings,
 }
 
 impl LightSource {
-	pub fn new(color: [f32; 3], translation: Vector3<f32>, rotation: Matrix4<f32>, scale: f32) -> Self {
+	pub fn new(kind: LightKind, color: [f32; 3], intensity: f32, translation: Vector3<f32>, rotation: Matrix4<f32>, scale: f32) -> Self {
 		LightSource {
+			kind,
 			color,
+			intensity,
 			translation,
 			rotation,
 			scale,
+			shadow: ShadowSettings::default(),
 		}
 	}
 
+	// ? Point light using the falloff the shader used to hard-code
 	pub fn new_translated(translation: Vector3<f32>) -> Self {
 		LightSource::new(
+			LightKind::Point {
+				attenuation: Attenuation::Coefficients { constant: 1.0, linear: 0.0, quadratic: 0.05 },
+			},
 			[1.0, 1.0, 1.0],
+			1.0,
 			translation,
 			Matrix4::from_axis_angle(vec3(1.0, 0.0, 0.0),
 			Deg(0.0)),
@@ -42,9 +114,58 @@ impl LightSource {
 		)
 	}
 
+	pub fn new_directional(rotation: Matrix4<f32>, color: [f32; 3], intensity: f32) -> Self {
+		LightSource::new(LightKind::Directional, color, intensity, vec3(0.0, 0.0, 0.0), rotation, 1.0)
+	}
+
+	pub fn new_spot(translation: Vector3<f32>, rotation: Matrix4<f32>, range: f32, inner_cone: Deg<f32>, outer_cone: Deg<f32>) -> Self {
+		LightSource::new(
+			LightKind::Spot { attenuation: Attenuation::Range(range), inner_cone, outer_cone },
+			[1.0, 1.0, 1.0],
+			1.0,
+			translation,
+			rotation,
+			1.0
+		)
+	}
+
 	pub fn model_matrix(&self) -> Matrix4<f32> {
 		Matrix4::from_translation(self.translation) * self.rotation * Matrix4::from_scale(self.scale)
 	}
+
+	// ? Lights point down -Z before being rotated
+	pub fn direction(&self) -> Vector3<f32> {
+		(self.rotation * vec4(0.0, 0.0, -1.0, 0.0)).truncate().normalize()
+	}
+
+	// ? Distance past which the light has no effect, None when it never fully fades out
+	pub fn range(&self) -> Option<f32> {
+		match self.kind {
+			LightKind::Point { attenuation: Attenuation::Range(range) } => Some(range),
+			LightKind::Spot { attenuation: Attenuation::Range(range), .. } => Some(range),
+			_ => None,
+		}
+	}
+
+	pub fn info(&self) -> LightSourceInfo {
+		let (attenuation, cone) = match self.kind {
+			LightKind::Directional => ([1.0, 0.0, 0.0, 0.0], [-1.0, -1.0]),
+			LightKind::Point { attenuation } => (attenuation.to_array(), [-1.0, -1.0]),
+			LightKind::Spot { attenuation, inner_cone, outer_cone } => (
+				attenuation.to_array(),
+				[Rad::from(inner_cone).0.cos(), Rad::from(outer_cone).0.cos()]
+			),
+		};
+		LightSourceInfo::new(
+			self.kind.shader_id(),
+			self.translation.into(),
+			self.color,
+			self.intensity,
+			self.direction().into(),
+			attenuation,
+			cone,
+		)
+	}
 }
 
 impl Entity3D for LightSource {
@@ -0,0 +1,77 @@
+use cgmath::{Point3, Transform};
+use cgmath::{EuclideanSpace, InnerSpace};
+
+use common::*;
+use scene::camera::Camera;
+use scene::object::Object3D;
+use scene::simplify::simplify;
+
+/*
+.##.......####...#####..
+.##......##..##..##..##.
+.##......##..##..##..##.
+.##......##..##..##..##.
+.######...####...#####..
+........................
+*/
+
+// ? Each level has half the triangles of the previous one, objects below this count don't get levels
+pub const MIN_LOD_TRIANGLES: usize = 512;
+pub const MAX_LOD_LEVELS: usize = 4;
+// ? The full mesh is drawn while the object's bounding sphere covers this much of the screen's height,
+// ? every halving of its size after that drops one level
+pub const FULL_DETAIL_SCREEN_SIZE: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LodSettings {
+	pub enabled: bool,
+	// ? Multiplies the screen sizes, above 1 keeps the detailed levels longer
+	pub bias: f32,
+}
+impl Default for LodSettings {
+	fn default() -> Self {
+		LodSettings {
+			enabled: true,
+			bias: 1.0,
+		}
+	}
+}
+
+// ? Simplified versions of a triangle list, most detailed first, not including the list itself
+pub fn generate_chain(vertices: &[Vertex]) -> Vec<Vec<Vertex>> {
+	let mut levels: Vec<Vec<Vertex>> = vec![];
+	let mut triangles = vertices.len() / 3;
+	while levels.len() < MAX_LOD_LEVELS && triangles / 2 >= MIN_LOD_TRIANGLES {
+		let level = simplify(levels.last().map_or(vertices, |level| level.as_slice()), triangles / 2);
+		// ? Boundaries and seams can keep the simplification from getting anywhere, stop once it stalls
+		if level.len() / 3 > triangles * 3 / 4 {
+			break;
+		}
+		triangles = level.len() / 3;
+		levels.push(level);
+	}
+	levels
+}
+
+// ? Fraction of the screen's height covered by the object's bounding sphere
+pub fn screen_size(object: &Object3D, camera: &Camera) -> f32 {
+	let (min, max) = object.bounds();
+	let center = Point3::from_vec((min + max) * 0.5);
+	let radius = (max - min).magnitude() * 0.5 * object.scale;
+	let view_center = (camera.view * object.model_matrix()).transform_point(center);
+	let distance = -view_center.z;
+	if distance <= radius {
+		return 1.0;
+	}
+	radius * camera.projection[1][1] / distance
+}
+
+// ? 0 is the full mesh, `level_count` includes it
+pub fn select_level(screen_size: f32, settings: &LodSettings, level_count: usize) -> usize {
+	let size = screen_size * settings.bias;
+	if !settings.enabled || size >= FULL_DETAIL_SCREEN_SIZE || size <= 0.0 {
+		return 0;
+	}
+	let level = (FULL_DETAIL_SCREEN_SIZE / size).log2().floor() as usize + 1;
+	level.min(level_count - 1)
+}
@@ -19,6 +19,7 @@ use tobj::Material as TObjMaterial;
 */
 
 // ? See: http://paulbourke.net/dataformats/mtl/
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum IlluminationModel {
     ConstantColor = 0, // color = Kd
     Model1 = 1, // color = KaIa + Kd { SUM j=1..ls, (N * Lj)Ij }
@@ -33,6 +34,114 @@ pub enum IlluminationModel {
     Model10 = 10, // ...
 }
 
+impl IlluminationModel {
+    pub fn from_illum(illum: u8) -> Option<Self> {
+        match illum {
+            0 => Some(IlluminationModel::ConstantColor),
+            1 => Some(IlluminationModel::Model1),
+            2 => Some(IlluminationModel::Model2),
+            3 => Some(IlluminationModel::Model3),
+            4 => Some(IlluminationModel::Model4),
+            5 => Some(IlluminationModel::Model5),
+            6 => Some(IlluminationModel::Model6),
+            7 => Some(IlluminationModel::Model7),
+            8 => Some(IlluminationModel::Model8),
+            9 => Some(IlluminationModel::Model9),
+            10 => Some(IlluminationModel::Model10),
+            _ => None,
+        }
+    }
+
+    // ? Value of `u_IlluminationModel` in the fragment shader
+    pub fn shader_id(&self) -> i32 {
+        *self as i32
+    }
+
+    pub fn has_transparency(&self) -> bool {
+        match *self {
+            IlluminationModel::Model4 | IlluminationModel::Model6 | IlluminationModel::Model7 | IlluminationModel::Model9 => true,
+            _ => false,
+        }
+    }
+}
+
+impl Default for IlluminationModel {
+    // ? Materials without `illum` are shaded with Blinn-Phong
+    fn default() -> Self {
+        IlluminationModel::Model2
+    }
+}
+
+/*
+.#####...#####...#####..
+.##..##..##..##..##..##.
+.#####...#####...#####..
+.##......##..##..##..##.
+.##......#####...##..##.
+........................
+*/
+
+// ? Metallic-roughness parameters, base color comes from the material's diffuse (Kd / map_Kd)
+// ? See: http://exocortex.com/blog/extending_wavefront_mtl_to_support_pbr
+pub struct PbrMaterial {
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: [f32; 4],
+    pub occlusion_strength: f32,
+    pub metallic_texture: String,
+    pub roughness_texture: String,
+    pub emissive_texture: String,
+    pub occlusion_texture: String,
+}
+
+impl PbrMaterial {
+    pub fn new(metallic: f32, roughness: f32, emissive: [f32; 3], occlusion_strength: f32, metallic_texture: String, roughness_texture: String, emissive_texture: String, occlusion_texture: String) -> Self {
+        PbrMaterial {
+            metallic,
+            roughness,
+            emissive: [emissive[0], emissive[1], emissive[2], 1.0],
+            occlusion_strength,
+            metallic_texture,
+            roughness_texture,
+            emissive_texture,
+            occlusion_texture,
+        }
+    }
+
+    // ? Only materials using at least one of the PBR statements (`Pr`, `Pm`, `map_Pr`, `map_Pm`) are PBR
+    pub fn from_tobj_material(tobj_material: &TObjMaterial) -> Option<Self> {
+        let params = &tobj_material.unknown_param;
+        let is_pbr = ["Pr", "Pm", "map_Pr", "map_Pm"].iter().any(|key| params.contains_key(*key));
+        if !is_pbr {
+            return None;
+        }
+        let float_param = |key: &str, default: f32| {
+            params.get(key).and_then(|value| value.trim().parse::<f32>().ok()).unwrap_or(default)
+        };
+        let texture_param = |key: &str| {
+            params.get(key).map(|value| Material::texture_file_name(value)).unwrap_or_default()
+        };
+        let emissive = params.get("Ke").map(|value| {
+            let components: Vec<f32> = value.split_whitespace().filter_map(|component| component.parse().ok()).collect();
+            match components.len() {
+                0 => [0.0; 3],
+                1 | 2 => [components[0]; 3],
+                _ => [components[0], components[1], components[2]],
+            }
+        }).unwrap_or([0.0; 3]);
+        Some(PbrMaterial::new(
+            float_param("Pm", 0.0),
+            float_param("Pr", 1.0),
+            emissive,
+            1.0,
+            texture_param("map_Pm"),
+            texture_param("map_Pr"),
+            texture_param("map_Ke"),
+            texture_param("map_AO"),
+        ))
+    }
+}
+
 /*
 .##...##...####...######..######..#####...######...####...##.....
 .###.###..##..##....##....##......##..##....##....##..##..##.....
@@ -55,7 +164,8 @@ pub struct Material {
     pub specular_texture: String,
     pub normal_texture: String,
     pub dissolve_texture: String,
-    pub illumination_model: Option<u8>,
+    pub illumination_model: Option<IlluminationModel>,
+    pub pbr: Option<PbrMaterial>,
 }
 
 impl Material {
@@ -74,11 +184,12 @@ impl Material {
             normal_texture,
             dissolve_texture,
             illumination_model: None,
+            pbr: None,
         }
     }
 
     pub fn from_tobj_material(tobj_material: &TObjMaterial) -> Self {
-        Material::new(
+        let mut material = Material::new(
             tobj_material.name.clone(),
             tobj_material.ambient,
             tobj_material.diffuse,
@@ -91,7 +202,75 @@ impl Material {
             tobj_material.specular_texture.clone(),
             tobj_material.normal_texture.clone(),
             tobj_material.dissolve_texture.clone(),
-        )
+        );
+        material.illumination_model = tobj_material.illumination_model.and_then(IlluminationModel::from_illum);
+
+        // ? tobj only maps `map_Bump` to the normal texture, `norm` and `bump` end up as unknown params
+        if material.normal_texture.is_empty() {
+            for key in &["norm", "bump", "map_bump"] {
+                if let Some(value) = tobj_material.unknown_param.get(*key) {
+                    material.normal_texture = value.clone();
+                    break;
+                }
+            }
+        }
+        material.ambient_texture = Material::texture_file_name(&material.ambient_texture);
+        material.diffuse_texture = Material::texture_file_name(&material.diffuse_texture);
+        material.specular_texture = Material::texture_file_name(&material.specular_texture);
+        material.normal_texture = Material::texture_file_name(&material.normal_texture);
+        material.dissolve_texture = Material::texture_file_name(&material.dissolve_texture);
+        material.pbr = PbrMaterial::from_tobj_material(tobj_material);
+        material
+    }
+
+    pub fn texture_names(&self) -> Vec<&String> {
+        let mut texture_names = vec![
+            &self.ambient_texture,
+            &self.diffuse_texture,
+            &self.specular_texture,
+            &self.normal_texture,
+            &self.dissolve_texture,
+        ];
+        if let Some(ref pbr) = self.pbr {
+            texture_names.push(&pbr.metallic_texture);
+            texture_names.push(&pbr.roughness_texture);
+            texture_names.push(&pbr.emissive_texture);
+            texture_names.push(&pbr.occlusion_texture);
+        }
+        texture_names.into_iter().filter(|texture_name| !texture_name.is_empty()).collect()
+    }
+
+    // ? Texture statements can carry options before the file name (ex: `map_Bump -bm 0.5 normal.png`)
+    fn texture_file_name(statement: &str) -> String {
+        statement.split_whitespace().last().unwrap_or("").to_string()
+    }
+
+    // ? Any `d` below 1 blends the object over what is behind it, map_d alone only cuts out texels
+    pub fn is_transparent(&self) -> bool {
+        self.dissolve < 1.0
+    }
+
+    pub fn shading_model(&self) -> IlluminationModel {
+        self.illumination_model.unwrap_or_default()
+    }
+
+    pub fn locals(&self) -> MaterialLocals {
+        let mut locals = MaterialLocals::new_default();
+        locals.ambient = self.ambient;
+        locals.diffuse = self.diffuse;
+        locals.specular = self.specular;
+        locals.shininess = self.shininess;
+        locals.dissolve = self.dissolve;
+        locals.optical_density = self.optical_density;
+        locals.illumination_model = self.shading_model().shader_id();
+        if let Some(ref pbr) = self.pbr {
+            locals.emissive = pbr.emissive;
+            locals.metallic = pbr.metallic;
+            locals.roughness = pbr.roughness;
+            locals.occlusion_strength = pbr.occlusion_strength;
+            locals.pbr = 1;
+        }
+        locals
     }
 
     pub fn from_tobj_materials(tobj_materials: &Vec<TObjMaterial>) -> Vec<Self> {
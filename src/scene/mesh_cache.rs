@@ -0,0 +1,489 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use cgmath::vec3;
+use image;
+
+use common::*;
+use common::cache::{cache_path, read_cache, write_cache, CacheReader, CacheWriter};
+use scene::material::{IlluminationModel, Material, PbrMaterial};
+use scene::object::Object3D;
+use scene::optimize;
+
+/*
+.##...##..######...####...##..##...........####....####....####...##..##..######.
+.###.###..##......##......##..##..........##..##..##..##..##..##..##..##..##.....
+.##.#.##..####.....####...######..........##......######..##......######..####...
+.##...##..##..........##..##..##..........##..##..##..##..##..##..##..##..##.....
+.##...##..######...####...##..##...........####...##..##...####...##..##..######.
+.................................................................................
+*/
+
+const CACHE_MAGIC: &[u8; 4] = b"MSHC";
+// ? Bump when the layout or the import preprocessing changes. Together with the crate version it is part of
+// ? the cache file name, so a new release never reads the objects an older Object3D::new produced.
+const CACHE_VERSION: u32 = 3;
+
+const FLOATS_PER_VERTEX: usize = 15;
+const NO_ILLUMINATION_MODEL: u8 = 255;
+
+// ? What an OBJ import produces once its objects are prepared for drawing (see Scene::prepare_objects),
+// ? material ids are relative to `materials`
+pub struct CachedImport {
+	pub objects: Vec<Object3D>,
+	pub materials: Vec<Material>,
+	pub textures: HashMap<String, image::RgbaImage>,
+}
+
+// ? A file the import read. It is unchanged when its size and modification time are,
+// ? otherwise its content is hashed so that touching a file doesn't throw the cache away.
+struct FileStamp {
+	path: PathBuf,
+	size: u64,
+	modified: (u64, u32),
+	hash: u64,
+}
+
+impl FileStamp {
+	fn new(path: &Path) -> Option<Self> {
+		let (size, modified) = file_metadata(path)?;
+		let hash = content_hash(path)?;
+		Some(FileStamp { path: path.to_path_buf(), size, modified, hash })
+	}
+
+	fn is_current(&self) -> bool {
+		match file_metadata(&self.path) {
+			Some((size, modified)) if size == self.size && modified == self.modified => true,
+			Some((size, _)) if size == self.size => content_hash(&self.path) == Some(self.hash),
+			_ => false,
+		}
+	}
+
+	fn write(&self, writer: &mut CacheWriter) {
+		writer.string(&self.path.to_string_lossy());
+		writer.u64(self.size);
+		writer.u64(self.modified.0);
+		writer.u32(self.modified.1);
+		writer.u64(self.hash);
+	}
+
+	fn read(reader: &mut CacheReader) -> Option<Self> {
+		Some(FileStamp {
+			path: PathBuf::from(reader.string()?),
+			size: reader.u64()?,
+			modified: (reader.u64()?, reader.u32()?),
+			hash: reader.u64()?,
+		})
+	}
+}
+
+fn file_metadata(path: &Path) -> Option<(u64, (u64, u32))> {
+	let metadata = fs::metadata(path).ok()?;
+	let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+	Some((metadata.len(), (modified.as_secs(), modified.subsec_nanos())))
+}
+
+fn content_hash(path: &Path) -> Option<u64> {
+	let bytes = fs::read(path).ok()?;
+	let mut hasher = DefaultHasher::new();
+	bytes.hash(&mut hasher);
+	Some(hasher.finish())
+}
+
+// ? One cache file per OBJ path, version of the import and `preparation`, which describes the options the objects
+// ? were prepared with, so that switching between them doesn't overwrite the files of the others
+fn cache_file(path: &Path, preparation: &str) -> PathBuf {
+	let mut hasher = DefaultHasher::new();
+	env!("CARGO_PKG_VERSION").hash(&mut hasher);
+	CACHE_VERSION.hash(&mut hasher);
+	path.to_string_lossy().hash(&mut hasher);
+	preparation.hash(&mut hasher);
+	cache_path(&format!("mesh_{:016x}.bin", hasher.finish()))
+}
+
+// ? The OBJ itself, its `mtllib` files (resolved next to it, like tobj does) and the textures of its materials
+fn dependencies(path: &Path, materials: &[Material]) -> Vec<PathBuf> {
+	let mut paths = vec![path.to_path_buf()];
+	let directory = path.parent().unwrap_or(Path::new(""));
+	if let Ok(obj) = fs::read_to_string(path) {
+		for line in obj.lines() {
+			let mut words = line.split_whitespace();
+			if words.next() == Some("mtllib") {
+				paths.extend(words.map(|name| directory.join(name)));
+			}
+		}
+	}
+	for material in materials {
+		for texture_name in material.texture_names() {
+			let texture_path = PathBuf::from(format!("./data/{}", texture_name));
+			if !paths.contains(&texture_path) {
+				paths.push(texture_path);
+			}
+		}
+	}
+	paths
+}
+
+// ? None when there is no cache for `path` prepared that way or when any file it was made from changed.
+// ? Read in one go rather than mapped, every vertex and texel is copied into the objects and images anyway.
+pub fn load(path: &Path, preparation: &str) -> Option<CachedImport> {
+	read_cache(&cache_file(path, preparation)).and_then(|bytes| decode(&bytes))
+}
+
+// ? Called once the imported objects are prepared, before the scene offsets the material ids
+pub fn store(path: &Path, preparation: &str, objects: &[Object3D], materials: &[Material], texture_map: &HashMap<String, image::RgbaImage>) {
+	// ? A file that can't be read can't be checked either, the import is then simply not cached
+	if let Some(bytes) = encode(path, objects, materials, texture_map) {
+		write_cache(&cache_file(path, preparation), &bytes);
+	}
+}
+
+fn decode(bytes: &[u8]) -> Option<CachedImport> {
+	let mut reader = CacheReader::new(bytes, CACHE_MAGIC, CACHE_VERSION)?;
+
+	let stamp_count = reader.u32()? as usize;
+	for _ in 0..stamp_count {
+		if !FileStamp::read(&mut reader)?.is_current() {
+			return None;
+		}
+	}
+
+	let material_count = reader.u32()? as usize;
+	let materials = (0..material_count).map(|_| read_material(&mut reader)).collect::<Option<Vec<_>>>()?;
+
+	let texture_count = reader.u32()? as usize;
+	let mut textures = HashMap::new();
+	for _ in 0..texture_count {
+		let name = reader.string()?;
+		let (width, height) = (reader.u32()?, reader.u32()?);
+		let texels = reader.bytes()?.to_vec();
+		textures.insert(name, image::RgbaImage::from_raw(width, height, texels)?);
+	}
+
+	let object_count = reader.u32()? as usize;
+	let objects = (0..object_count).map(|_| read_object(&mut reader)).collect::<Option<Vec<_>>>()?;
+	Some(CachedImport { objects, materials, textures })
+}
+
+fn encode(path: &Path, objects: &[Object3D], materials: &[Material], texture_map: &HashMap<String, image::RgbaImage>) -> Option<Vec<u8>> {
+	let mut writer = CacheWriter::new(CACHE_MAGIC, CACHE_VERSION);
+	let stamps = dependencies(path, materials).iter().map(|path| FileStamp::new(path)).collect::<Option<Vec<_>>>()?;
+	writer.u32(stamps.len() as u32);
+	for stamp in &stamps {
+		stamp.write(&mut writer);
+	}
+
+	writer.u32(materials.len() as u32);
+	for material in materials {
+		write_material(&mut writer, material);
+	}
+
+	let mut texture_names: Vec<&String> = materials.iter().flat_map(Material::texture_names).collect();
+	texture_names.sort();
+	texture_names.dedup();
+	let textures: Vec<(&String, &image::RgbaImage)> = texture_names.into_iter()
+		.filter_map(|name| texture_map.get(name).map(|texture| (name, texture)))
+		.collect();
+	writer.u32(textures.len() as u32);
+	for (name, texture) in textures {
+		writer.string(name);
+		writer.u32(texture.width());
+		writer.u32(texture.height());
+		writer.bytes(texture);
+	}
+
+	writer.u32(objects.len() as u32);
+	for object in objects {
+		write_object(&mut writer, object);
+	}
+	Some(writer.bytes)
+}
+
+// ? Along with the levels of detail and the control vertices, each in the triangle order they were left in
+fn write_object(writer: &mut CacheWriter, object: &Object3D) {
+	writer.string(&object.name);
+	match object.material_id {
+		Some(material_id) => {
+			writer.u8(1);
+			writer.u32(material_id as u32);
+		},
+		None => writer.u8(0),
+	}
+	writer.u32(object.invalid_indices as u32);
+	let (min, max) = object.bounds();
+	writer.f32s(&[min.x, min.y, min.z, max.x, max.y, max.z]);
+
+	write_triangles(writer, &object.vertices);
+	writer.u32(object.lods.len() as u32);
+	for level in &object.lods {
+		write_triangles(writer, level);
+	}
+	match object.control_vertices {
+		Some(ref control_vertices) => {
+			writer.u8(1);
+			write_triangles(writer, control_vertices);
+		},
+		None => writer.u8(0),
+	}
+}
+
+// ? Stored indexed, the triangle list is rebuilt from the indices when loading
+fn write_triangles(writer: &mut CacheWriter, triangles: &[Vertex]) {
+	let (vertices, indices) = optimize::index(triangles);
+	writer.u32(vertices.len() as u32);
+	for vertex in &vertices {
+		writer.f32s(&vertex.position());
+		writer.f32s(&vertex.color());
+		writer.f32s(&vertex.normal());
+		writer.f32s(&vertex.tangent());
+		writer.f32s(&vertex.uv());
+	}
+	writer.u32(indices.len() as u32);
+	for index in indices {
+		writer.u32(index);
+	}
+}
+
+fn read_object(reader: &mut CacheReader) -> Option<Object3D> {
+	let name = reader.string()?;
+	let material_id = match reader.u8()? {
+		0 => None,
+		_ => Some(reader.u32()? as usize),
+	};
+	let invalid_indices = reader.u32()? as usize;
+	let mut bounds = [0.0; 6];
+	reader.f32s(&mut bounds)?;
+
+	let corners = read_triangles(reader)?;
+	let level_count = reader.u32()? as usize;
+	let lods = (0..level_count).map(|_| read_triangles(reader)).collect::<Option<Vec<_>>>()?;
+	let control_vertices = match reader.u8()? {
+		0 => None,
+		_ => Some(read_triangles(reader)?),
+	};
+
+	let bounds = (vec3(bounds[0], bounds[1], bounds[2]), vec3(bounds[3], bounds[4], bounds[5]));
+	let mut object = Object3D::with_bounds(name, corners, material_id, bounds);
+	object.lods = lods;
+	object.control_vertices = control_vertices;
+	object.invalid_indices = invalid_indices;
+	Some(object)
+}
+
+fn read_triangles(reader: &mut CacheReader) -> Option<Vec<Vertex>> {
+	let vertex_count = reader.u32()? as usize;
+	if vertex_count.checked_mul(FLOATS_PER_VERTEX * 4)? > reader.remaining() {
+		return None;
+	}
+	let mut vertices = Vec::with_capacity(vertex_count);
+	for _ in 0..vertex_count {
+		let mut floats = [0.0; FLOATS_PER_VERTEX];
+		reader.f32s(&mut floats)?;
+		vertices.push(Vertex::new(
+			[floats[0], floats[1], floats[2]],
+			[floats[3], floats[4], floats[5]],
+			[floats[6], floats[7], floats[8]],
+			[floats[9], floats[10], floats[11], floats[12]],
+			[floats[13], floats[14]],
+		));
+	}
+	let index_count = reader.u32()? as usize;
+	if index_count.checked_mul(4)? > reader.remaining() {
+		return None;
+	}
+	(0..index_count).map(|_| {
+		reader.u32().and_then(|index| vertices.get(index as usize).cloned())
+	}).collect()
+}
+
+fn write_material(writer: &mut CacheWriter, material: &Material) {
+	writer.string(&material.name);
+	writer.f32s(&material.ambient);
+	writer.f32s(&material.diffuse);
+	writer.f32s(&material.specular);
+	writer.f32(material.shininess);
+	writer.f32(material.dissolve);
+	writer.f32(material.optical_density);
+	writer.string(&material.ambient_texture);
+	writer.string(&material.diffuse_texture);
+	writer.string(&material.specular_texture);
+	writer.string(&material.normal_texture);
+	writer.string(&material.dissolve_texture);
+	writer.u8(material.illumination_model.map_or(NO_ILLUMINATION_MODEL, |model| model.shader_id() as u8));
+	match material.pbr {
+		Some(ref pbr) => {
+			writer.u8(1);
+			writer.f32(pbr.metallic);
+			writer.f32(pbr.roughness);
+			writer.f32s(&pbr.emissive);
+			writer.f32(pbr.occlusion_strength);
+			writer.string(&pbr.metallic_texture);
+			writer.string(&pbr.roughness_texture);
+			writer.string(&pbr.emissive_texture);
+			writer.string(&pbr.occlusion_texture);
+		},
+		None => writer.u8(0),
+	}
+}
+
+fn read_material(reader: &mut CacheReader) -> Option<Material> {
+	let name = reader.string()?;
+	let mut colors = [[0.0; 4]; 3];
+	for color in colors.iter_mut() {
+		reader.f32s(color)?;
+	}
+	let (shininess, dissolve, optical_density) = (reader.f32()?, reader.f32()?, reader.f32()?);
+	let mut material = Material::new(
+		name,
+		[0.0; 3],
+		[0.0; 3],
+		[0.0; 3],
+		shininess,
+		dissolve,
+		optical_density,
+		reader.string()?,
+		reader.string()?,
+		reader.string()?,
+		reader.string()?,
+		reader.string()?,
+	);
+	material.ambient = colors[0];
+	material.diffuse = colors[1];
+	material.specular = colors[2];
+	material.illumination_model = IlluminationModel::from_illum(reader.u8()?);
+	if reader.u8()? != 0 {
+		let (metallic, roughness) = (reader.f32()?, reader.f32()?);
+		let mut emissive = [0.0; 4];
+		reader.f32s(&mut emissive)?;
+		let occlusion_strength = reader.f32()?;
+		let mut pbr = PbrMaterial::new(
+			metallic,
+			roughness,
+			[0.0; 3],
+			occlusion_strength,
+			reader.string()?,
+			reader.string()?,
+			reader.string()?,
+			reader.string()?,
+		);
+		pbr.emissive = emissive;
+		material.pbr = Some(pbr);
+	}
+	Some(material)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// ? A source file of its own for each test and run, removed when the test ends
+	struct SourceFile(PathBuf);
+
+	impl SourceFile {
+		fn new(name: &str, content: &str) -> Self {
+			let path = ::std::env::temp_dir().join(format!("mesh_cache_{}_{}.obj", name, ::std::process::id()));
+			fs::write(&path, content).unwrap();
+			SourceFile(path)
+		}
+	}
+
+	impl Drop for SourceFile {
+		fn drop(&mut self) {
+			let _ = fs::remove_file(&self.0);
+		}
+	}
+
+	fn assert_same_triangles(loaded: &[Vertex], expected: &[Vertex]) {
+		assert_eq!(loaded.len(), expected.len());
+		for (loaded, expected) in loaded.iter().zip(expected) {
+			assert_eq!(loaded.position(), expected.position());
+			assert_eq!(loaded.color(), expected.color());
+			assert_eq!(loaded.normal(), expected.normal());
+			assert_eq!(loaded.tangent(), expected.tangent());
+			assert_eq!(loaded.uv(), expected.uv());
+		}
+	}
+
+	#[test]
+	fn round_trip_preserves_the_import() {
+		let source = SourceFile::new("round_trip", "v 0 0 0\n");
+		let mut object = Object3D::new_cube();
+		object.material_id = Some(0);
+		object.invalid_indices = 3;
+		// ? Stand-ins for a prepared object, whose triangles are no longer in their imported order
+		object.keep_control_vertices();
+		object.vertices.reverse();
+		object.lods = vec![object.vertices[..18].to_vec(), object.vertices[..6].to_vec()];
+		let mut material = Material::new(
+			"painted".to_string(), [0.1, 0.2, 0.3], [0.4, 0.5, 0.6], [0.7, 0.8, 0.9], 32.0, 0.75, 1.5,
+			String::new(), "NO_TEXTURE.png".to_string(), String::new(), String::new(), String::new()
+		);
+		material.illumination_model = Some(IlluminationModel::Model3);
+		material.pbr = Some(PbrMaterial::new(0.25, 0.5, [1.0, 0.5, 0.0], 0.8, String::new(), String::new(), String::new(), String::new()));
+		let mut texture_map = HashMap::new();
+		texture_map.insert("NO_TEXTURE.png".to_string(), image::RgbaImage::from_pixel(2, 3, image::Rgba([10, 20, 30, 40])));
+		texture_map.insert("unused.png".to_string(), image::RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 0])));
+
+		let bytes = encode(&source.0, ::std::slice::from_ref(&object), &[material], &texture_map).unwrap();
+		let cached = decode(&bytes).unwrap();
+
+		assert_eq!(cached.objects.len(), 1);
+		let loaded = &cached.objects[0];
+		assert_eq!(loaded.name, object.name);
+		assert_eq!(loaded.material_id, Some(0));
+		assert_eq!(loaded.invalid_indices, 3);
+		assert_eq!(loaded.bounds(), object.bounds());
+		assert_same_triangles(&loaded.vertices, &object.vertices);
+		assert_eq!(loaded.lods.len(), 2);
+		for (loaded, expected) in loaded.lods.iter().zip(&object.lods) {
+			assert_same_triangles(loaded, expected);
+		}
+		assert_same_triangles(loaded.control_vertices.as_ref().unwrap(), &Object3D::new_cube().vertices);
+
+		assert_eq!(cached.materials.len(), 1);
+		let loaded = &cached.materials[0];
+		assert_eq!(loaded.name, "painted");
+		assert_eq!(loaded.ambient, [0.1, 0.2, 0.3, 1.0]);
+		assert_eq!(loaded.diffuse, [0.4, 0.5, 0.6, 1.0]);
+		assert_eq!(loaded.specular, [0.7, 0.8, 0.9, 1.0]);
+		assert_eq!((loaded.shininess, loaded.dissolve, loaded.optical_density), (32.0, 0.75, 1.5));
+		assert_eq!(loaded.diffuse_texture, "NO_TEXTURE.png");
+		assert_eq!(loaded.illumination_model, Some(IlluminationModel::Model3));
+		let pbr = loaded.pbr.as_ref().unwrap();
+		assert_eq!((pbr.metallic, pbr.roughness, pbr.occlusion_strength), (0.25, 0.5, 0.8));
+		assert_eq!(pbr.emissive, [1.0, 0.5, 0.0, 1.0]);
+
+		// ? Only the textures of the imported materials are kept
+		assert_eq!(cached.textures.len(), 1);
+		let texture = &cached.textures["NO_TEXTURE.png"];
+		assert_eq!(texture.dimensions(), (2, 3));
+		assert!(texture.pixels().all(|pixel| *pixel == image::Rgba([10, 20, 30, 40])));
+	}
+
+	#[test]
+	fn changed_source_invalidates_the_cache() {
+		let source = SourceFile::new("invalidation", "v 0 0 0\n");
+		let bytes = encode(&source.0, &[Object3D::new_cube()], &[], &HashMap::new()).unwrap();
+		assert!(decode(&bytes).is_some());
+		fs::write(&source.0, "v 1 0 0\nv 0 1 0\n").unwrap();
+		assert!(decode(&bytes).is_none());
+	}
+
+	#[test]
+	fn truncated_cache_is_rejected() {
+		let source = SourceFile::new("truncated", "v 0 0 0\n");
+		let bytes = encode(&source.0, &[Object3D::new_cube()], &[], &HashMap::new()).unwrap();
+		assert!(decode(&bytes[..bytes.len() - 1]).is_none());
+	}
+
+	#[test]
+	fn preparations_have_their_own_files() {
+		let path = Path::new("./data/venus.obj");
+		assert_eq!(cache_file(path, "lods"), cache_file(path, "lods"));
+		assert_ne!(cache_file(path, "lods"), cache_file(path, "no lods"));
+	}
+}
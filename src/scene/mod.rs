@@ -1,21 +1,36 @@
 
 use std::collections::HashMap;
 use std::path::Path;
-use cgmath::{Point3, vec3};
+use cgmath::{Deg, Matrix4, Point3, vec3};
 use tobj;
 use image;
 
 pub mod camera;
 pub mod entity;
+pub mod environment;
 pub mod light_source;
+pub mod lod;
 pub mod material;
+pub mod mesh_cache;
+pub mod normals;
 pub mod object;
+pub mod optimize;
+pub mod primitives;
+pub mod simplify;
+pub mod subdivision;
+pub mod validation;
 
 use self::camera::Camera;
 use self::object::Object3D;
 use self::light_source::LightSource;
 use self::material::Material;
 use self::entity::Entity3D;
+use self::environment::Environment;
+use self::primitives::Primitive;
+use self::normals::NormalOptions;
+use self::lod::LodSettings;
+use self::subdivision::SubdivisionOptions;
+use renderer::tone_mapping::ToneMapping;
 
 /*
 ..####....####...######..##..##..######.
@@ -28,25 +43,159 @@ use self::entity::Entity3D;
 
 pub struct Scene {
 	pub clear_color: [f32; 4],
+	pub ambient_color: [f32; 4],
 	pub objects: Vec<Object3D>,
 	pub camera: Camera,
+	// ? Other viewpoints, only drawn as frustums by the overlay
+	pub cameras: Vec<Camera>,
 	pub light_sources: Vec<LightSource>,
 	pub materials: Vec<Material>,
 	pub texture_map: HashMap<String, image::RgbaImage>,
+	pub tone_mapping: ToneMapping,
+	// ? Skybox, reflections and image-based lighting, the background is clear_color without it
+	pub environment: Option<Environment>,
+	// ? Scale of the light coming from the environment
+	pub ibl_intensity: f32,
+	pub lod: LodSettings,
+	pub subdivision: SubdivisionOptions,
+	pub preprocessing: Preprocessing,
 }
+
+// ? What prepare_objects does to new objects besides subdividing them and generating their levels of detail
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preprocessing {
+	pub repair: bool,
+	pub validate: bool,
+	pub normals: Option<NormalOptions>,
+	pub print_stats: bool,
+}
+
 impl Scene {
 	pub fn new(clear_color: [f32; 4], objects: Vec<Object3D>, camera: Camera, light_sources: Vec<LightSource>, materials: Vec<Material>) -> Self {
 		Scene {
 			clear_color,
+			ambient_color: [0.0, 0.0, 0.0, 1.0],
 			objects,
 			camera,
+			cameras: vec![],
 			light_sources,
 			materials,
-			texture_map: HashMap::new()
+			texture_map: HashMap::new(),
+			tone_mapping: ToneMapping::default(),
+			environment: None,
+			ibl_intensity: 1.0,
+			lod: LodSettings::default(),
+			subdivision: SubdivisionOptions::default(),
+			preprocessing: Preprocessing::default(),
+		}
+	}
+
+	// ? Reads `--environment <panorama or skybox directory>`, `--ibl-intensity <scale>`, `--primitive <spec>` (see Primitive::from_spec),
+	// ? `--repair` to fix every object's geometry, `--validate` to print a report for each of them afterwards,
+	// ? `--normals <spec>` to regenerate the normals of every object (see NormalOptions::from_spec), `--subdivide <spec>`
+	// ? (see SubdivisionOptions::from_spec), `--no-lod`, `--lod-bias <scale>`, `--mesh-stats` and the tone mapping options.
+	// ? The geometry options apply to the objects added from then on, so this comes before the imports.
+	pub fn apply_args<I: Iterator<Item = String>>(&mut self, args: I) {
+		let args: Vec<String> = args.collect();
+		self.tone_mapping.apply_args(args.iter().cloned());
+		self.lod.enabled = !args.iter().any(|arg| arg == "--no-lod");
+		self.preprocessing.repair = args.iter().any(|arg| arg == "--repair");
+		self.preprocessing.validate = args.iter().any(|arg| arg == "--validate");
+		self.preprocessing.print_stats = args.iter().any(|arg| arg == "--mesh-stats");
+		let mut primitives = vec![];
+		for pair in args.windows(2) {
+			if pair[0] == "--environment" {
+				match Environment::load(Path::new(&pair[1])) {
+					Ok(environment) => self.environment = Some(environment),
+					Err(error) => println!("Failed to load the environment: {}", error),
+				}
+			} else if pair[0] == "--primitive" {
+				match Primitive::from_spec(&pair[1]) {
+					Ok(primitive) => primitives.push(primitive.object()),
+					Err(error) => println!("{}", error),
+				}
+			} else if pair[0] == "--ibl-intensity" {
+				match pair[1].parse::<f32>() {
+					Ok(intensity) if intensity >= 0.0 => self.ibl_intensity = intensity,
+					_ => println!("Invalid IBL intensity: {}", pair[1]),
+				}
+			} else if pair[0] == "--lod-bias" {
+				match pair[1].parse::<f32>() {
+					Ok(bias) if bias > 0.0 => self.lod.bias = bias,
+					_ => println!("Invalid LOD bias: {}", pair[1]),
+				}
+			} else if pair[0] == "--subdivide" {
+				match SubdivisionOptions::from_spec(&pair[1]) {
+					Ok(options) => self.subdivision = options,
+					Err(error) => println!("{}", error),
+				}
+			} else if pair[0] == "--normals" {
+				match NormalOptions::from_spec(&pair[1]) {
+					Ok(options) => self.preprocessing.normals = Some(options),
+					Err(error) => println!("{}", error),
+				}
+			}
+		}
+		// ? After the loop so that the primitives are prepared with all of the options
+		self.prepare_objects(&mut primitives);
+		self.objects.append(&mut primitives);
+	}
+
+	// ? Repairs, validates and recomputes the normals of new objects as the arguments asked, then subdivides them.
+	// ? Levels of detail are generated last, from the final geometry, then every object is optimized for drawing.
+	pub fn prepare_objects(&self, objects: &mut [Object3D]) {
+		let preprocessing = self.preprocessing;
+		for object in objects.iter_mut() {
+			if preprocessing.repair {
+				let summary = object.repair();
+				println!("Repaired {}: {}", object.name, summary);
+			}
+			if preprocessing.validate {
+				println!("{}: {}", object.name, object.validate());
+			}
+			if let Some(options) = preprocessing.normals {
+				object.recompute_normals(options);
+			}
+			// ? Also without --subdivide, the viewer can change the level later on
+			object.keep_control_vertices();
+			if self.subdivision.levels > 0 {
+				object.subdivide(&self.subdivision);
+			}
+			if self.lod.enabled {
+				object.generate_lods();
+			}
+			let acmr_before = if preprocessing.print_stats { object.acmr() } else { 0.0 };
+			object.optimize();
+			if preprocessing.print_stats {
+				println!("{}: {} triangles, ACMR {:.3} -> {:.3}", object.name, object.vertices.len() / 3, acmr_before, object.acmr());
+			}
+		}
+	}
+
+	// ? Tells the mesh cache how imported objects were prepared, None when the repair, the validation or the
+	// ? statistics were asked for: they are printed as the steps run, which a cached import would skip
+	fn preparation(&self) -> Option<String> {
+		let preprocessing = self.preprocessing;
+		if preprocessing.repair || preprocessing.validate || preprocessing.print_stats {
+			return None;
 		}
+		Some(format!("normals {:?}, subdivision {:?}, lods {}", preprocessing.normals, self.subdivision, self.lod.enabled))
 	}
 
-	pub fn new_test_scene() -> Self {
+	// ? Subdivides every object again from its control vertices, the renderer's buffers have to be rebuilt afterwards.
+	// ? Called from the frame loop, so no levels of detail are generated: simplifying the subdivided meshes would
+	// ? stall the viewer for seconds on every key press, the objects are drawn in full until the next start.
+	pub fn set_subdivision_levels(&mut self, levels: u32) {
+		self.subdivision.levels = levels;
+		let options = self.subdivision;
+		for object in self.objects.iter_mut() {
+			object.subdivide(&options);
+			object.optimize();
+		}
+	}
+
+	// ? See apply_args for `args`
+	pub fn new_test_scene<I: Iterator<Item = String>>(args: I) -> Self {
 		let mut scene = Scene::new(
 			[0.0, 0.0, 0.0, 1.0],
 			vec![],
@@ -54,61 +203,75 @@ impl Scene {
 			vec![],
 			vec![],
 		);
+		scene.ambient_color = [0.05, 0.05, 0.05, 1.0];
+		scene.apply_args(args);
 		scene.import_obj(Path::new("./data/venus.obj"));
 		scene.light_sources.push(
 			LightSource::new_translated(
 				vec3(0.0, 0.5, 0.0)
 			)
 		);
-		scene.light_sources.push(
-			LightSource::new_translated(
-				vec3(0.0, 10.0, -10.0)
-			)
+		let mut top_light = LightSource::new_translated(vec3(0.0, 10.0, -10.0));
+		top_light.shadow.casts_shadows = true;
+		scene.light_sources.push(top_light);
+		let mut sun = LightSource::new_directional(
+			Matrix4::from_angle_x(Deg(-60.0)) * Matrix4::from_angle_y(Deg(30.0)),
+			[1.0, 0.95, 0.85],
+			0.5
 		);
+		sun.shadow.casts_shadows = true;
+		scene.light_sources.push(sun);
 		scene
 	}
 
+	pub fn object_material(&self, object: &Object3D) -> Option<&Material> {
+		object.material_id.and_then(|material_id| self.materials.get(material_id))
+	}
+
+	// ? Transparent objects are left out of the opaque passes and blended afterwards
+	pub fn is_transparent(&self, object: &Object3D) -> bool {
+		self.object_material(object).map_or(false, Material::is_transparent)
+	}
+
 	pub fn load_and_add_textures(&mut self, material: &Material) {
-		if !material.ambient_texture.is_empty() && !self.texture_map.contains_key(&material.ambient_texture) {
-			self.texture_map.insert(
-				material.ambient_texture.clone(),
-				image::open(&format!("./data/{}", material.ambient_texture)).unwrap().to_rgba()
-			);
-		}
-		if !material.diffuse_texture.is_empty() && !self.texture_map.contains_key(&material.diffuse_texture) {
-			self.texture_map.insert(
-				material.diffuse_texture.clone(),
-				image::open(&format!("./data/{}", material.diffuse_texture)).unwrap().to_rgba()
-			);
-		}
-		if !material.specular_texture.is_empty() && !self.texture_map.contains_key(&material.specular_texture) {
-			self.texture_map.insert(
-				material.specular_texture.clone(),
-				image::open(&format!("./data/{}", material.specular_texture)).unwrap().to_rgba()
-			);
-		}
-		if !material.normal_texture.is_empty() && !self.texture_map.contains_key(&material.normal_texture) {
-			self.texture_map.insert(
-				material.normal_texture.clone(),
-				image::open(&format!("./data/{}", material.normal_texture)).unwrap().to_rgba()
-			);
-		}
-		if !material.dissolve_texture.is_empty() && !self.texture_map.contains_key(&material.dissolve_texture) {
-			self.texture_map.insert(
-				material.dissolve_texture.clone(),
-				image::open(&format!("./data/{}", material.dissolve_texture)).unwrap().to_rgba()
-			);
+		for texture_name in material.texture_names() {
+			if !self.texture_map.contains_key(texture_name) {
+				self.texture_map.insert(
+					texture_name.clone(),
+					image::open(&format!("./data/{}", texture_name)).unwrap().to_rgba()
+				);
+			}
 		}
 	}
 
+	// ? The objects are prepared (see prepare_objects) and cached that way, later runs with the same options load them
 	pub fn import_obj(&mut self, path: &Path) {
-		let tobj_data = tobj::load_obj(&path);
-		let (tobj_models, tobj_materials) = tobj_data.unwrap();
-		let mut objects = Object3D::from_tobj_models(&tobj_models);
-		let mut materials = Material::from_tobj_materials(&tobj_materials);
+		let preparation = self.preparation();
+		let cached = preparation.as_ref().and_then(|preparation| mesh_cache::load(path, preparation));
+		let (mut objects, mut materials) = match cached {
+			Some(cached) => {
+				for (name, texture) in cached.textures {
+					self.texture_map.entry(name).or_insert(texture);
+				}
+				// ? Only fills in textures the cache didn't hold
+				cached.materials.iter().for_each(|material| self.load_and_add_textures(&material));
+				(cached.objects, cached.materials)
+			},
+			None => {
+				let tobj_data = tobj::load_obj(&path);
+				let (tobj_models, tobj_materials) = tobj_data.unwrap();
+				let mut objects = Object3D::from_tobj_models(&tobj_models);
+				let materials = Material::from_tobj_materials(&tobj_materials);
 
-		// ? Load textures from each materials
-		materials.iter().for_each(|material| self.load_and_add_textures(&material));
+				// ? Load textures from each materials
+				materials.iter().for_each(|material| self.load_and_add_textures(&material));
+				self.prepare_objects(&mut objects);
+				if let Some(preparation) = preparation {
+					mesh_cache::store(path, &preparation, &objects, &materials, &self.texture_map);
+				}
+				(objects, materials)
+			},
+		};
 
 		// ? If self.materials is empty, no need to increment the objects's material ids
 		if !self.materials.is_empty() {
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::f32;
+
+use cgmath::{Deg, Rad, vec3, Vector3};
+use cgmath::InnerSpace;
+
+/*
+.##..##...####...#####...##...##...####...##.......####..
+.###.##..##..##..##..##..###.###..##..##..##......##.....
+.##.###..##..##..#####...##.#.##..######..##.......####..
+.##..##..##..##..##..##..##...##..##..##..##..........##.
+.##..##...####...##..##..##...##..##..##..######...####..
+.........................................................
+*/
+
+// ? Used when an import comes without normals
+pub const DEFAULT_CREASE_ANGLE: f32 = 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+	// ? Every corner takes the normal of its triangle
+	Flat,
+	// ? Every corner takes the average normal of the triangles around its position
+	Smooth,
+	// ? Smooth, except across edges where the triangles meet at more than the angle, which stay hard
+	Creased(Deg<f32>),
+}
+
+// ? How much each triangle around a position counts in its smooth normal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormalWeighting {
+	Area,
+	// ? The triangle's angle at the corner, which doesn't depend on how the surface is tessellated
+	Angle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NormalOptions {
+	pub smoothing: Smoothing,
+	pub weighting: NormalWeighting,
+}
+impl Default for NormalOptions {
+	fn default() -> Self {
+		NormalOptions {
+			smoothing: Smoothing::Creased(Deg(DEFAULT_CREASE_ANGLE)),
+			weighting: NormalWeighting::Angle,
+		}
+	}
+}
+impl NormalOptions {
+	// ? "<flat|smooth|crease angle in degrees>[:area|angle]", e.g. "smooth", "30" or "45:area"
+	pub fn from_spec(spec: &str) -> Result<Self, String> {
+		let mut parts = spec.splitn(2, ':');
+		let smoothing = match parts.next().unwrap_or("") {
+			"flat" => Smoothing::Flat,
+			"smooth" => Smoothing::Smooth,
+			angle => match angle.parse::<f32>() {
+				Ok(angle) if angle >= 0.0 && angle <= 180.0 => Smoothing::Creased(Deg(angle)),
+				_ => return Err(format!("Invalid normal smoothing: {}", spec)),
+			},
+		};
+		let weighting = match parts.next() {
+			None | Some("angle") => NormalWeighting::Angle,
+			Some("area") => NormalWeighting::Area,
+			Some(_) => return Err(format!("Invalid normal weighting: {}", spec)),
+		};
+		Ok(NormalOptions { smoothing, weighting })
+	}
+}
+
+// ? Merges positions that are bit for bit equal, returns the unique positions and an index for each input position.
+// ? Imports usually duplicate positions along uv seams, smoothing has to see through them.
+pub fn weld(positions: &[[f32; 3]]) -> (Vec<[f32; 3]>, Vec<u32>) {
+	let mut unique = vec![];
+	let mut indices = Vec::with_capacity(positions.len());
+	let mut lookup: HashMap<[u32; 3], u32> = HashMap::new();
+	for position in positions {
+		// ? -0.0 and 0.0 are the same position
+		let key = [
+			(position[0] + 0.0).to_bits(),
+			(position[1] + 0.0).to_bits(),
+			(position[2] + 0.0).to_bits(),
+		];
+		let index = *lookup.entry(key).or_insert_with(|| {
+			unique.push(*position);
+			unique.len() as u32 - 1
+		});
+		indices.push(index);
+	}
+	(unique, indices)
+}
+
+// ? Unit normal of a counter-clockwise triangle and its area, a degenerate triangle has a zero normal
+pub fn triangle_normal(p1: Vector3<f32>, p2: Vector3<f32>, p3: Vector3<f32>) -> (Vector3<f32>, f32) {
+	let cross = (p2 - p1).cross(p3 - p1);
+	let length = cross.magnitude();
+	if length > f32::EPSILON {
+		(cross / length, 0.5 * length)
+	} else {
+		(vec3(0.0, 0.0, 0.0), 0.0)
+	}
+}
+
+// ? Normals for a triangle list given as one position per corner, returns one normal per corner
+pub fn corner_normals(corner_positions: &[[f32; 3]], options: NormalOptions) -> Vec<[f32; 3]> {
+	let (positions, indices) = weld(corner_positions);
+	let nb_tris = corner_positions.len() / 3;
+	let nb_corners = 3 * nb_tris;
+
+	// ? Triangle normals and areas, and the angle of each corner
+	let mut face_normals = Vec::with_capacity(nb_tris);
+	let mut face_areas = Vec::with_capacity(nb_tris);
+	let mut corner_angles = vec![0.0; nb_corners];
+	for i in 0..nb_tris {
+		let real_index = 3 * i;
+		let p = [
+			Vector3::from(corner_positions[real_index]),
+			Vector3::from(corner_positions[real_index + 1]),
+			Vector3::from(corner_positions[real_index + 2]),
+		];
+		let (normal, area) = triangle_normal(p[0], p[1], p[2]);
+		face_normals.push(normal);
+		face_areas.push(area);
+		for k in 0..3 {
+			let edge1 = p[(k + 1) % 3] - p[k];
+			let edge2 = p[(k + 2) % 3] - p[k];
+			if edge1.magnitude2() > 0.0 && edge2.magnitude2() > 0.0 {
+				corner_angles[real_index + k] = edge1.angle(edge2).0;
+			}
+		}
+	}
+
+	let fallback = |normal: Vector3<f32>| {
+		if normal.magnitude2() > 0.0 { normal } else { vec3(0.0, 1.0, 0.0) }
+	};
+	let min_cos = match options.smoothing {
+		Smoothing::Flat => {
+			return (0..nb_corners).map(|corner| fallback(face_normals[corner / 3]).into()).collect();
+		},
+		Smoothing::Smooth => -2.0,
+		Smoothing::Creased(angle) => Rad::from(angle).0.cos(),
+	};
+
+	// ? Corners sharing each welded position
+	let mut incident_corners: Vec<Vec<usize>> = vec![vec![]; positions.len()];
+	for corner in 0..nb_corners {
+		incident_corners[indices[corner] as usize].push(corner);
+	}
+
+	// ? Each corner averages the triangles around its position that are within the crease angle of its own triangle,
+	// ? so corners on both sides of a hard edge end up with different normals
+	(0..nb_corners).map(|corner| {
+		let face = corner / 3;
+		let own_normal = face_normals[face];
+		let mut sum = vec3(0.0, 0.0, 0.0);
+		for &other in &incident_corners[indices[corner] as usize] {
+			let other_face = other / 3;
+			let normal = face_normals[other_face];
+			let within_crease = other_face == face
+				|| own_normal.magnitude2() == 0.0
+				|| own_normal.dot(normal) >= min_cos;
+			if !within_crease {
+				continue;
+			}
+			let weight = match options.weighting {
+				NormalWeighting::Area => face_areas[other_face],
+				NormalWeighting::Angle => corner_angles[other],
+			};
+			sum += normal * weight;
+		}
+		if sum.magnitude2() > f32::EPSILON * f32::EPSILON {
+			sum.normalize().into()
+		} else {
+			fallback(own_normal).into()
+		}
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scene::primitives;
+
+	// ? The corners of a 2x2x2 cube, one position per corner like an import
+	fn cube_corners() -> Vec<[f32; 3]> {
+		let mesh = primitives::cube(2.0, 1);
+		mesh.indices.iter().map(|&index| mesh.positions[index as usize]).collect()
+	}
+
+	fn assert_close(a: [f32; 3], b: Vector3<f32>) {
+		assert!((Vector3::from(a) - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+	}
+
+	#[test]
+	fn weld_merges_signed_zeros() {
+		let (unique, indices) = weld(&[[0.0, 1.0, 0.0], [-0.0, 1.0, 0.0], [0.0, 2.0, 0.0]]);
+		assert_eq!(unique.len(), 2);
+		assert_eq!(indices, vec![0, 0, 1]);
+	}
+
+	#[test]
+	fn triangle_normal_and_area() {
+		let (normal, area) = triangle_normal(vec3(0.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0), vec3(0.0, 2.0, 0.0));
+		assert_eq!(normal, vec3(0.0, 0.0, 1.0));
+		assert_eq!(area, 2.0);
+		let (normal, area) = triangle_normal(vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0));
+		assert_eq!(normal, vec3(0.0, 0.0, 0.0));
+		assert_eq!(area, 0.0);
+	}
+
+	#[test]
+	fn flat_and_creased_cube_keeps_face_normals() {
+		let corners = cube_corners();
+		for &smoothing in &[Smoothing::Flat, Smoothing::Creased(Deg(60.0))] {
+			let normals = corner_normals(&corners, NormalOptions { smoothing, weighting: NormalWeighting::Angle });
+			for (triangle, normals) in corners.chunks(3).zip(normals.chunks(3)) {
+				let p: Vec<Vector3<f32>> = triangle.iter().map(|&position| Vector3::from(position)).collect();
+				let (face_normal, _) = triangle_normal(p[0], p[1], p[2]);
+				for &normal in normals {
+					assert_close(normal, face_normal);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn smooth_cube_points_away_from_the_center() {
+		let corners = cube_corners();
+		let normals = corner_normals(&corners, NormalOptions { smoothing: Smoothing::Smooth, weighting: NormalWeighting::Angle });
+		// ? Every face meets a cube corner at a right angle, so all three count the same
+		for (&position, &normal) in corners.iter().zip(normals.iter()) {
+			assert_close(normal, Vector3::from(position).normalize());
+		}
+	}
+
+	#[test]
+	fn from_spec() {
+		assert_eq!(NormalOptions::from_spec("smooth").unwrap().smoothing, Smoothing::Smooth);
+		assert_eq!(NormalOptions::from_spec("45:area"), Ok(NormalOptions {
+			smoothing: Smoothing::Creased(Deg(45.0)),
+			weighting: NormalWeighting::Area,
+		}));
+		assert!(NormalOptions::from_spec("200").is_err());
+		assert!(NormalOptions::from_spec("flat:volume").is_err());
+	}
+}
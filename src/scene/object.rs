@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::f32;
 use std::path::Path;
 
 use cgmath::{Deg, Matrix4, vec3, Vector3, Rad};
@@ -6,6 +8,15 @@ use tobj;
 
 use common::*;
 use scene::entity::Entity3D;
+use scene::lod;
+use scene::optimize;
+use scene::normals;
+use scene::normals::{NormalOptions, NormalWeighting, Smoothing};
+use scene::subdivision;
+use scene::validation;
+use scene::validation::{MeshReport, RepairSummary};
+use scene::subdivision::SubdivisionOptions;
+use scene::primitives::Primitive;
 
 /*
 ..####...#####...######..######...####...######..######..#####..
@@ -19,31 +30,74 @@ use scene::entity::Entity3D;
 pub struct Object3D {
 	pub name: String,
 	pub vertices: Vec<Vertex>,
+	// ? Simplified versions of `vertices` for when the object is far away, see lod::generate_chain
+	pub lods: Vec<Vec<Vertex>>,
+	// ? The vertices in their imported triangle order, kept before optimize() reorders them so that the
+	// ? subdivision can rebuild the polygons, and so that the level can change
+	pub control_vertices: Option<Vec<Vertex>>,
+	// ? Indices that were out of range when the object was created, their triangles were dropped
+	pub invalid_indices: usize,
+	// ? Of `vertices`, kept up to date by the methods replacing them
+	bounds: (Vector3<f32>, Vector3<f32>),
 	pub material_id: Option<usize>,
 	pub translation: Vector3<f32>,
 	pub rotation: Matrix4<f32>,
 	pub scale: f32,
 }
 impl Object3D {
+	// ? `vertices` are indexed by `indices`, `uvs` and `normals` have one entry per index.
+	// ? Triangles with out of range indices are dropped, normals are generated when there isn't one for every corner.
 	pub fn new(name: String, vertices: Vec<[f32; 3]>, indices: Vec<u32>, uvs: Vec<[f32; 2]>, normals: Vec<[f32; 3]>, material_id: Option<usize>) -> Self {
-		let mut object_vertices = vec![];
-		for (n, index) in indices.into_iter().enumerate() {
-			let index = index as usize;
-			let vertex = &vertices[index];
-			let uv = if let Some(uv) = uvs.get(n) {
-				[uv[0], 1.0 - uv[1]]
-			} else {
-				[0.0, 0.0]
-			};
-			let color = [1.0, 1.0, 1.0];
-			let normal = &normals[n];
-			object_vertices.push(
-				Vertex::new(*vertex, color, *normal, uv)
-			);
+		let has_normals = normals.len() == indices.len();
+		let mut corner_positions = vec![];
+		let mut corner_uvs = vec![];
+		let mut corner_normals = vec![];
+		let mut invalid_indices = 0;
+		for (t, triangle) in indices.chunks(3).enumerate() {
+			let out_of_range = triangle.iter().filter(|&&index| index as usize >= vertices.len()).count();
+			if out_of_range > 0 || triangle.len() < 3 {
+				invalid_indices += out_of_range;
+				continue;
+			}
+			for (k, &index) in triangle.iter().enumerate() {
+				let n = 3 * t + k;
+				corner_positions.push(vertices[index as usize]);
+				let uv = if let Some(uv) = uvs.get(n) {
+					[uv[0], 1.0 - uv[1]]
+				} else {
+					[0.0, 0.0]
+				};
+				corner_uvs.push(uv);
+				if has_normals {
+					corner_normals.push(normals[n]);
+				}
+			}
 		}
+		let normals = if has_normals {
+			corner_normals
+		} else {
+			normals::corner_normals(&corner_positions, NormalOptions::default())
+		};
+		let mut object = Object3D::from_vertices(name, Object3D::corner_vertices(&corner_positions, &normals, &corner_uvs), material_id);
+		object.invalid_indices = invalid_indices;
+		object
+	}
+
+	// ? From the corners of a triangle list
+	pub fn from_vertices(name: String, vertices: Vec<Vertex>, material_id: Option<usize>) -> Self {
+		let bounds = Object3D::evaluate_bounds(&vertices);
+		Object3D::with_bounds(name, vertices, material_id, bounds)
+	}
+
+	// ? When the bounds of the vertices are already known, see `bounds`
+	pub fn with_bounds(name: String, vertices: Vec<Vertex>, material_id: Option<usize>, bounds: (Vector3<f32>, Vector3<f32>)) -> Self {
 		Object3D {
 			name,
-			vertices: object_vertices,
+			vertices,
+			lods: vec![],
+			control_vertices: None,
+			invalid_indices: 0,
+			bounds,
 			material_id,
 			translation: vec3(0.0, 0.0, 0.0),
 			rotation: Matrix4::from_axis_angle(vec3(1.0, 0.0, 0.0), Deg(0.0)),
@@ -51,6 +105,106 @@ impl Object3D {
 		}
 	}
 
+	fn set_vertices(&mut self, vertices: Vec<Vertex>) {
+		self.bounds = Object3D::evaluate_bounds(&vertices);
+		self.vertices = vertices;
+	}
+
+	// ? Replaces the normals, and so the tangents, with generated ones. Must happen before the vertex buffers are created.
+	pub fn recompute_normals(&mut self, options: NormalOptions) {
+		let positions: Vec<[f32; 3]> = self.vertices.iter().map(Vertex::position).collect();
+		let uvs: Vec<[f32; 2]> = self.vertices.iter().map(Vertex::uv).collect();
+		let normals = normals::corner_normals(&positions, options);
+		self.set_vertices(Object3D::corner_vertices(&positions, &normals, &uvs));
+		self.lods.clear();
+	}
+
+	// ? Replaces the vertices with the control vertices subdivided `options.levels` times, levels of detail have to be regenerated
+	pub fn subdivide(&mut self, options: &SubdivisionOptions) {
+		self.keep_control_vertices();
+		let control_vertices = self.control_vertices.as_ref().unwrap();
+		let vertices = if options.levels == 0 {
+			control_vertices.clone()
+		} else {
+			let (positions, uvs) = subdivision::subdivide(control_vertices, options);
+			let smoothing = options.crease_angle.map_or(Smoothing::Smooth, Smoothing::Creased);
+			let normals = normals::corner_normals(&positions, NormalOptions { smoothing, weighting: NormalWeighting::Angle });
+			Object3D::corner_vertices(&positions, &normals, &uvs)
+		};
+		self.set_vertices(vertices);
+		self.lods.clear();
+	}
+
+	// ? Has to happen before optimize(), which scatters the triangle pairs the quads are rebuilt from
+	pub fn keep_control_vertices(&mut self) {
+		if self.control_vertices.is_none() {
+			self.control_vertices = Some(self.vertices.clone());
+		}
+	}
+
+	pub fn validate(&self) -> MeshReport {
+		let mut report = validation::validate(&self.vertices);
+		report.out_of_range_indices = self.invalid_indices;
+		report
+	}
+
+	// ? Accounts for the out of range indices dropped on creation, removes degenerate triangles, welds nearby positions,
+	// ? makes the winding consistent and facing outwards, then regenerates the normals that are still invalid. Levels of detail and subdivision start over from the result.
+	pub fn repair(&mut self) -> RepairSummary {
+		let mut summary = RepairSummary::default();
+		// ? Their triangles are already gone, what is left is the mesh without them
+		summary.dropped_indices = self.invalid_indices;
+		self.invalid_indices = 0;
+		summary.removed_triangles = validation::remove_degenerate_triangles(&mut self.vertices);
+		let tolerance = validation::weld_tolerance(&self.vertices);
+		summary.welded_positions = validation::weld(&mut self.vertices, tolerance);
+		summary.removed_triangles += validation::remove_degenerate_triangles(&mut self.vertices);
+		summary.flipped_triangles = validation::fix_winding(&mut self.vertices);
+
+		let invalid_normals = validation::invalid_normal_corners(&self.vertices);
+		summary.recomputed_normals = invalid_normals.iter().filter(|&&invalid| invalid).count();
+		if summary.recomputed_normals > 0 || summary.flipped_triangles > 0 || summary.welded_positions > 0 {
+			// ? Tangents depend on the positions and winding too, the vertices are rebuilt
+			let positions: Vec<[f32; 3]> = self.vertices.iter().map(Vertex::position).collect();
+			let uvs: Vec<[f32; 2]> = self.vertices.iter().map(Vertex::uv).collect();
+			let generated_normals = normals::corner_normals(&positions, NormalOptions::default());
+			let normals: Vec<[f32; 3]> = self.vertices.iter().zip(invalid_normals).zip(generated_normals)
+				.map(|((vertex, invalid), generated)| if invalid { generated } else { vertex.normal() })
+				.collect();
+			self.vertices = Object3D::corner_vertices(&positions, &normals, &uvs);
+		}
+		self.bounds = Object3D::evaluate_bounds(&self.vertices);
+		self.lods.clear();
+		self.control_vertices = None;
+		summary
+	}
+
+	pub fn generate_lods(&mut self) {
+		self.lods = lod::generate_chain(&self.vertices);
+	}
+
+	// ? Reorders the triangles of every level for the vertex cache and against overdraw, see optimize::optimize_triangle_order
+	pub fn optimize(&mut self) {
+		self.vertices = optimize::optimize_triangle_order(&self.vertices);
+		for level in self.lods.iter_mut() {
+			*level = optimize::optimize_triangle_order(level);
+		}
+	}
+
+	// ? Of the full detail level, as it is indexed when uploaded
+	pub fn acmr(&self) -> f32 {
+		let (_, indices) = optimize::index(&self.vertices);
+		optimize::acmr(&indices, optimize::CACHE_SIZE)
+	}
+
+	fn corner_vertices(positions: &Vec<[f32; 3]>, normals: &Vec<[f32; 3]>, uvs: &Vec<[f32; 2]>) -> Vec<Vertex> {
+		let tangents = Object3D::evaluate_vertex_tangents(positions, normals, uvs);
+		let color = [1.0, 1.0, 1.0];
+		positions.iter().enumerate().map(|(n, position)| {
+			Vertex::new(*position, color, normals[n], tangents[n], uvs[n])
+		}).collect()
+	}
+
 	pub fn from_tobj_model(model: &tobj::Model) -> Self {
 		let mesh = &model.mesh;
 		let name = model.name.clone();
@@ -94,80 +248,108 @@ impl Object3D {
 		models.iter().map(|model| Object3D::from_tobj_model(model)).collect()
 	}
 
+	// ? 2 units wide, one quad per face with its own uvs
 	pub fn new_cube() -> Self {
-		let mut vertices = vec![
-			// front
-			[ -1.0, -1.0, 1.0 ],
-			[  1.0, -1.0, 1.0 ],
-			[  1.0,  1.0, 1.0 ],
-			[ -1.0,  1.0, 1.0 ],
-			// back
-			[ -1.0, -1.0, -1.0 ],
-			[  1.0, -1.0, -1.0 ],
-			[  1.0,  1.0, -1.0 ],
-			[ -1.0,  1.0, -1.0 ],
-		];
-		let indices = vec![
-			// front
-			0, 1, 2,
-			2, 3, 0,
-			// top
-			1, 5, 6,
-			6, 2, 1,
-			// back
-			7, 6, 5,
-			5, 4, 7,
-			// bottom
-			4, 0, 3,
-			3, 7, 4,
-			// left
-			4, 5, 1,
-			1, 0, 4,
-			// right
-			3, 2, 6,
-			6, 7, 3
-		];
-		let normals = Object3D::evaluate_vertex_normals(&mut vertices, &indices);
-		Object3D::new(String::from("cube"), vertices, indices, vec![], normals, None)
+		Primitive::Cube { size: 2.0, segments: 1 }.object()
 	}
 
 	pub fn model_matrix(&self) -> Matrix4<f32> {
 		Matrix4::from_translation(self.translation) * self.rotation * Matrix4::from_scale(self.scale)
 	}
 
-	fn evaluate_vertex_normals(vertices: &mut Vec<[f32; 3]>, indices: &Vec<u32>) -> Vec<[f32; 3]> {
-		// ? Init vec to temporarly store normals at vertices positions
-		let mut normals:Vec<Vector3<f32>> = vertices.into_iter().map(|_vertex| vec3(0.0, 0.0, 0.0)).collect();
+	// ? Axis aligned bounds of the vertices in object space as (min, max), both at the origin for an empty object
+	pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>) {
+		self.bounds
+	}
 
-		// ? Fill the temporary normal data
-		let nb_tris = indices.len() / 3;
-		for i in 0..nb_tris {
-			let real_index = 3 * i as usize;
-			let index_v1 = indices[real_index] as usize;
-			let index_v2 = indices[real_index + 1] as usize;
-			let index_v3 = indices[real_index + 2] as usize;
-			let v1 = vertices[index_v1];
-			let v2 = vertices[index_v2];
-			let normal = Object3D::normal(
-				&vec3(v1[0], v1[1], v1[2]),
-				&vec3(v2[0], v2[1], v2[2])
-			);
-			normals[index_v1] += normal;
-			normals[index_v2] += normal;
-			normals[index_v3] += normal;
+	fn evaluate_bounds(vertices: &[Vertex]) -> (Vector3<f32>, Vector3<f32>) {
+		if vertices.is_empty() {
+			return (vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0));
 		}
-
-		// ? Assign and normalize each normal for the corresponding vertices
-		let normals: Vec<[f32; 3]> = normals.iter().map(|normal| {
-			let normalized_normal = normal.normalize();
-			[normalized_normal.x, normalized_normal.y, normalized_normal.z]
-		}).collect();
-		normals
+		let mut min = vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+		let mut max = vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+		for vertex in vertices {
+			let position = vertex.position();
+			for axis in 0..3 {
+				min[axis] = min[axis].min(position[axis]);
+				max[axis] = max[axis].max(position[axis]);
+			}
+		}
+		(min, max)
 	}
 
-	fn normal(v1: &Vector3<f32>, v2: &Vector3<f32>) -> Vector3<f32> {
-		let normal = v1.cross(*v2).normalize();
-		normal
+	// ? Per-corner tangents (xyz) and bitangent sign (w), following MikkTSpace: triangle tangents are
+	// ? accumulated on corners sharing the same position, normal and uv, then orthogonalized against the normal.
+	// ? See: http://www.mikktspace.com/
+	fn evaluate_vertex_tangents(positions: &Vec<[f32; 3]>, normals: &Vec<[f32; 3]>, uvs: &Vec<[f32; 2]>) -> Vec<[f32; 4]> {
+		let mut tangents: Vec<Vector3<f32>> = positions.iter().map(|_position| vec3(0.0, 0.0, 0.0)).collect();
+		let mut bitangents: Vec<Vector3<f32>> = positions.iter().map(|_position| vec3(0.0, 0.0, 0.0)).collect();
+
+		// ? Corners sharing position, normal and uv are the same vertex as far as MikkTSpace is concerned
+		let mut shared_corners: HashMap<[u32; 8], Vec<usize>> = HashMap::new();
+		for n in 0..positions.len() {
+			let p = positions[n];
+			let normal = normals.get(n).cloned().unwrap_or([0.0; 3]);
+			let uv = uvs[n];
+			let key = [
+				p[0].to_bits(), p[1].to_bits(), p[2].to_bits(),
+				normal[0].to_bits(), normal[1].to_bits(), normal[2].to_bits(),
+				uv[0].to_bits(), uv[1].to_bits(),
+			];
+			shared_corners.entry(key).or_insert_with(Vec::new).push(n);
+		}
+
+		// ? Accumulate the triangle tangents, weighted by the triangle's area in uv space
+		let nb_tris = positions.len() / 3;
+		for i in 0..nb_tris {
+			let real_index = 3 * i;
+			let p1 = Vector3::from(positions[real_index]);
+			let p2 = Vector3::from(positions[real_index + 1]);
+			let p3 = Vector3::from(positions[real_index + 2]);
+			let uv1 = uvs[real_index];
+			let uv2 = uvs[real_index + 1];
+			let uv3 = uvs[real_index + 2];
+			let edge1 = p2 - p1;
+			let edge2 = p3 - p1;
+			let (du1, dv1) = (uv2[0] - uv1[0], uv2[1] - uv1[1]);
+			let (du2, dv2) = (uv3[0] - uv1[0], uv3[1] - uv1[1]);
+			let determinant = du1 * dv2 - du2 * dv1;
+			if determinant.abs() < ::std::f32::EPSILON {
+				continue;
+			}
+			let r = 1.0 / determinant;
+			let tangent = (edge1 * dv2 - edge2 * dv1) * r;
+			// ? The uvs were flipped vertically on import (see `new`), the bitangent goes towards the texture's +v
+			// ? as it was authored, which is where the green channel of OpenGL (Y+) normal maps points
+			let bitangent = (edge1 * du2 - edge2 * du1) * r;
+			for corner in real_index..real_index + 3 {
+				tangents[corner] += tangent;
+				bitangents[corner] += bitangent;
+			}
+		}
+		for corners in shared_corners.values() {
+			let tangent = corners.iter().fold(vec3(0.0, 0.0, 0.0), |sum, corner| sum + tangents[*corner]);
+			let bitangent = corners.iter().fold(vec3(0.0, 0.0, 0.0), |sum, corner| sum + bitangents[*corner]);
+			for corner in corners {
+				tangents[*corner] = tangent;
+				bitangents[*corner] = bitangent;
+			}
+		}
+
+		// ? Gram-Schmidt orthogonalize and compute the handedness
+		tangents.iter().zip(bitangents.iter()).enumerate().map(|(n, (tangent, bitangent))| {
+			let normal = Vector3::from(normals.get(n).cloned().unwrap_or([0.0, 0.0, 1.0]));
+			let orthogonal_tangent = *tangent - normal * normal.dot(*tangent);
+			if orthogonal_tangent.magnitude2() < ::std::f32::EPSILON {
+				// ? No usable uv gradient, pick any tangent perpendicular to the normal
+				let axis = if normal.x.abs() < 0.9 { vec3(1.0, 0.0, 0.0) } else { vec3(0.0, 1.0, 0.0) };
+				let fallback = normal.cross(axis).normalize();
+				return [fallback.x, fallback.y, fallback.z, 1.0];
+			}
+			let orthogonal_tangent = orthogonal_tangent.normalize();
+			let handedness = if normal.cross(orthogonal_tangent).dot(*bitangent) < 0.0 { -1.0 } else { 1.0 };
+			[orthogonal_tangent.x, orthogonal_tangent.y, orthogonal_tangent.z, handedness]
+		}).collect()
 	}
 }
 
@@ -180,3 +362,37 @@ impl Entity3D for Object3D {
 		self.rotation = self.rotation * Matrix4::from_axis_angle(axis, angle);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bitangent_follows_the_authored_v() {
+		// ? A quad facing +z with u along +x and v along +y, as in an OBJ file
+		let positions = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+		let uvs = vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+		let normals = vec![[0.0, 0.0, 1.0]; 6];
+		let object = Object3D::new("quad".to_string(), positions, vec![0, 1, 2, 0, 2, 3], uvs, normals, None);
+		for vertex in &object.vertices {
+			let tangent = vertex.tangent();
+			let normal = Vector3::from(vertex.normal());
+			let bitangent = normal.cross(vec3(tangent[0], tangent[1], tangent[2])) * tangent[3];
+			assert!((vec3(tangent[0], tangent[1], tangent[2]) - vec3(1.0, 0.0, 0.0)).magnitude() < 1e-5);
+			assert!((bitangent - vec3(0.0, 1.0, 0.0)).magnitude() < 1e-5);
+		}
+	}
+
+	#[test]
+	fn control_vertices_are_kept_from_before_the_reordering() {
+		use scene::primitives;
+		let options = SubdivisionOptions::from_spec("auto:1").unwrap();
+		let mut expected = primitives::plane(2.0, 2.0, 8, 8).into_object("plane");
+		expected.subdivide(&options);
+		let mut object = primitives::plane(2.0, 2.0, 8, 8).into_object("plane");
+		object.keep_control_vertices();
+		object.optimize();
+		object.subdivide(&options);
+		assert_eq!(object.vertices.len(), expected.vertices.len());
+	}
+}
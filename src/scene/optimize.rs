@@ -0,0 +1,296 @@
+use std::collections::{HashMap, VecDeque};
+
+use cgmath::{vec3, Vector3};
+use cgmath::InnerSpace;
+
+use common::*;
+use scene::normals;
+
+/*
+..####...#####...######..######..##...##..######..######..######.
+.##..##..##..##....##......##....###.###....##.......##...##.....
+.##..##..#####.....##......##....##.#.##....##......##....####...
+.##..##..##........##......##....##...##....##.....##.....##.....
+..####...##........##....######..##...##..######..######..######.
+.................................................................
+*/
+
+// ? Post-transform cache the triangle order is tuned for and ACMR is measured with
+pub const CACHE_SIZE: usize = 32;
+
+// ? Scoring from: Tom Forsyth, Linear-Speed Vertex Cache Optimisation, 2006
+// ? See: https://tomforsyth1000.github.io/papers/fast_vert_cache_opt.html
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+// ? Shares identical corners of a triangle list, vertices are numbered in the order the triangles first use them
+// ? so that drawing fetches them mostly in order
+pub fn index(vertices: &[Vertex]) -> (Vec<Vertex>, Vec<u32>) {
+	let mut unique = vec![];
+	let mut indices = Vec::with_capacity(vertices.len());
+	let mut lookup: HashMap<[u32; 12], u32> = HashMap::new();
+	for vertex in vertices {
+		let (p, n, t, uv) = (vertex.position(), vertex.normal(), vertex.tangent(), vertex.uv());
+		let key = [
+			p[0].to_bits(), p[1].to_bits(), p[2].to_bits(),
+			n[0].to_bits(), n[1].to_bits(), n[2].to_bits(),
+			t[0].to_bits(), t[1].to_bits(), t[2].to_bits(), t[3].to_bits(),
+			uv[0].to_bits(), uv[1].to_bits(),
+		];
+		let index = *lookup.entry(key).or_insert_with(|| {
+			unique.push(*vertex);
+			unique.len() as u32 - 1
+		});
+		indices.push(index);
+	}
+	(unique, indices)
+}
+
+// ? Average cache miss ratio, vertices transformed per triangle with a FIFO cache of `cache_size`:
+// ? 3 without any reuse, 0.5 to 0.7 for a well ordered regular mesh
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+	let triangle_count = indices.len() / 3;
+	if triangle_count == 0 {
+		return 0.0;
+	}
+	let mut cache = VecDeque::with_capacity(cache_size + 1);
+	let mut misses = 0;
+	for index in indices {
+		if !cache.contains(index) {
+			misses += 1;
+			cache.push_back(*index);
+			if cache.len() > cache_size {
+				cache.pop_front();
+			}
+		}
+	}
+	misses as f32 / triangle_count as f32
+}
+
+// ? Reorders the triangles of a triangle list for the vertex cache, then their clusters against overdraw
+pub fn optimize_triangle_order(vertices: &[Vertex]) -> Vec<Vertex> {
+	let (unique, indices) = index(vertices);
+	let indices = optimize_vertex_cache(&indices, unique.len());
+	let indices = optimize_overdraw(&unique, &indices);
+	indices.iter().map(|&index| unique[index as usize]).collect()
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+	if remaining_triangles == 0 {
+		return -1.0;
+	}
+	let cache_score = match cache_position {
+		None => 0.0,
+		// ? The triangle just drawn, its vertices are a little less interesting than the ones right after them
+		Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+		Some(position) => (1.0 - (position - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(CACHE_DECAY_POWER),
+	};
+	// ? Vertices with few triangles left are finished first so they stop taking cache space
+	cache_score + VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// ? Greedily draws the triangle whose vertices score best, scores only change around the simulated LRU cache
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+	let triangle_count = indices.len() / 3;
+	let mut vertex_triangles: Vec<Vec<usize>> = vec![vec![]; vertex_count];
+	for t in 0..triangle_count {
+		for k in 0..3 {
+			vertex_triangles[indices[3 * t + k] as usize].push(t);
+		}
+	}
+	let mut cache_positions: Vec<Option<usize>> = vec![None; vertex_count];
+	let mut vertex_scores: Vec<f32> = vertex_triangles.iter().map(|triangles| vertex_score(None, triangles.len())).collect();
+	let mut triangle_scores: Vec<f32> = (0..triangle_count).map(|t| {
+		(0..3).map(|k| vertex_scores[indices[3 * t + k] as usize]).sum()
+	}).collect();
+	let mut emitted = vec![false; triangle_count];
+	let mut output = Vec::with_capacity(indices.len());
+	let mut cache: Vec<u32> = vec![];
+
+	let mut best = (0..triangle_count).fold(None, |best: Option<usize>, t| {
+		match best {
+			Some(best) if triangle_scores[best] >= triangle_scores[t] => Some(best),
+			_ => Some(t),
+		}
+	});
+	// ? When the cache has nothing left to offer, the next triangle that hasn't been drawn is taken
+	let mut cursor = 0;
+	while output.len() < 3 * triangle_count {
+		let t = match best {
+			Some(t) => t,
+			None => {
+				while emitted[cursor] {
+					cursor += 1;
+				}
+				cursor
+			},
+		};
+		emitted[t] = true;
+		let triangle = [indices[3 * t], indices[3 * t + 1], indices[3 * t + 2]];
+		output.extend_from_slice(&triangle);
+		for &vertex in &triangle {
+			let triangles = &mut vertex_triangles[vertex as usize];
+			if let Some(position) = triangles.iter().position(|&other| other == t) {
+				triangles.swap_remove(position);
+			}
+		}
+
+		// ? The triangle's vertices move to the front of the cache, the ones pushed past its end are evicted
+		let mut new_cache: Vec<u32> = triangle.to_vec();
+		new_cache.extend(cache.iter().cloned().filter(|vertex| !triangle.contains(vertex)));
+		let evicted: Vec<u32> = if new_cache.len() > CACHE_SIZE { new_cache.split_off(CACHE_SIZE) } else { vec![] };
+		for &vertex in &evicted {
+			cache_positions[vertex as usize] = None;
+		}
+		for (position, &vertex) in new_cache.iter().enumerate() {
+			cache_positions[vertex as usize] = Some(position);
+		}
+		cache = new_cache;
+
+		// ? Rescore the vertices whose position changed and the triangles around them
+		best = None;
+		let mut best_score = -1.0;
+		for &vertex in cache.iter().chain(evicted.iter()) {
+			let vertex = vertex as usize;
+			vertex_scores[vertex] = vertex_score(cache_positions[vertex], vertex_triangles[vertex].len());
+		}
+		for &vertex in cache.iter().chain(evicted.iter()) {
+			for &other in &vertex_triangles[vertex as usize] {
+				let score: f32 = (0..3).map(|k| vertex_scores[indices[3 * other + k] as usize]).sum();
+				triangle_scores[other] = score;
+				if score > best_score {
+					best_score = score;
+					best = Some(other);
+				}
+			}
+		}
+	}
+	output
+}
+
+// ? Splits the cache ordered triangles where the cache would be cold anyway (all three vertices miss),
+// ? then draws the clusters facing away from the mesh's center first: they are the most likely to hide the others.
+// ? See: Sander, Nehab and Barczak, Fast Triangle Reordering for Vertex Locality and Reduced Overdraw, 2007
+pub fn optimize_overdraw(vertices: &[Vertex], indices: &[u32]) -> Vec<u32> {
+	let triangle_count = indices.len() / 3;
+	let mut cluster_starts = vec![];
+	let mut cache = VecDeque::with_capacity(CACHE_SIZE + 1);
+	for t in 0..triangle_count {
+		let mut misses = 0;
+		for k in 0..3 {
+			let index = indices[3 * t + k];
+			if !cache.contains(&index) {
+				misses += 1;
+				cache.push_back(index);
+				if cache.len() > CACHE_SIZE {
+					cache.pop_front();
+				}
+			}
+		}
+		if t == 0 || misses == 3 {
+			cluster_starts.push(t);
+		}
+	}
+	if cluster_starts.len() < 2 {
+		return indices.to_vec();
+	}
+
+	let position = |index: u32| Vector3::from(vertices[index as usize].position());
+	let mut mesh_center = vec3(0.0, 0.0, 0.0);
+	let mut mesh_area = 0.0;
+	let mut clusters = vec![];
+	for (c, &start) in cluster_starts.iter().enumerate() {
+		let end = cluster_starts.get(c + 1).cloned().unwrap_or(triangle_count);
+		let mut center = vec3(0.0, 0.0, 0.0);
+		let mut normal = vec3(0.0, 0.0, 0.0);
+		let mut area = 0.0;
+		for t in start..end {
+			let p = [position(indices[3 * t]), position(indices[3 * t + 1]), position(indices[3 * t + 2])];
+			let (triangle_normal, triangle_area) = normals::triangle_normal(p[0], p[1], p[2]);
+			center += (p[0] + p[1] + p[2]) * (triangle_area / 3.0);
+			normal += triangle_normal * triangle_area;
+			area += triangle_area;
+		}
+		mesh_center += center;
+		mesh_area += area;
+		clusters.push((start, end, center, normal, area));
+	}
+	if mesh_area > 0.0 {
+		mesh_center /= mesh_area;
+	}
+
+	let mut sorted: Vec<(f32, usize, usize)> = clusters.into_iter().map(|(start, end, center, normal, area)| {
+		let occlusion = if area > 0.0 && normal.magnitude2() > 0.0 {
+			(center / area - mesh_center).dot(normal.normalize())
+		} else {
+			0.0
+		};
+		(occlusion, start, end)
+	}).collect();
+	sorted.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(::std::cmp::Ordering::Equal));
+	sorted.into_iter().flat_map(|(_, start, end)| indices[3 * start..3 * end].to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scene::primitives;
+
+	fn sorted_triangles(indices: &[u32]) -> Vec<[u32; 3]> {
+		// ? Rotated so that the smallest index comes first, which keeps the winding
+		let mut triangles: Vec<[u32; 3]> = indices.chunks(3).map(|t| {
+			let first = (0..3).min_by_key(|&k| t[k]).unwrap();
+			[t[first], t[(first + 1) % 3], t[(first + 2) % 3]]
+		}).collect();
+		triangles.sort();
+		triangles
+	}
+
+	#[test]
+	fn index_shares_identical_corners() {
+		let vertex = |x: f32, z: f32| Vertex::new([x, 0.0, z], [1.0; 3], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0, 1.0], [x, z]);
+		let vertices = [
+			vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 1.0),
+			vertex(0.0, 0.0), vertex(1.0, 1.0), vertex(1.0, 0.0),
+		];
+		let (unique, indices) = index(&vertices);
+		assert_eq!(unique.len(), 4);
+		assert_eq!(indices, vec![0, 1, 2, 0, 2, 3]);
+	}
+
+	#[test]
+	fn acmr_counts_misses_per_triangle() {
+		assert_eq!(acmr(&[0, 1, 2, 3, 4, 5], CACHE_SIZE), 3.0);
+		assert_eq!(acmr(&[0, 1, 2, 0, 2, 3], CACHE_SIZE), 2.0);
+		assert_eq!(acmr(&[], CACHE_SIZE), 0.0);
+	}
+
+	#[test]
+	fn vertex_cache_order_doesnt_raise_acmr_on_a_grid() {
+		let grid = primitives::plane(2.0, 2.0, 32, 32);
+		let optimized = optimize_vertex_cache(&grid.indices, grid.positions.len());
+		assert_eq!(sorted_triangles(&optimized), sorted_triangles(&grid.indices));
+		assert!(acmr(&optimized, CACHE_SIZE) <= acmr(&grid.indices, CACHE_SIZE));
+
+		// ? Scattered triangles miss almost every time, the optimized order shouldn't care where it started from
+		let triangle_count = grid.indices.len() / 3;
+		let scattered: Vec<u32> = (0..triangle_count)
+			.flat_map(|t| grid.indices[3 * (t * 7 % triangle_count)..3 * (t * 7 % triangle_count) + 3].to_vec())
+			.collect();
+		let optimized = optimize_vertex_cache(&scattered, grid.positions.len());
+		assert!(acmr(&optimized, CACHE_SIZE) < 0.5 * acmr(&scattered, CACHE_SIZE));
+	}
+
+	#[test]
+	fn overdraw_order_keeps_the_triangles() {
+		let sphere = primitives::uv_sphere(1.0, 32, 16);
+		let vertices: Vec<Vertex> = sphere.positions.iter().zip(&sphere.normals).zip(&sphere.uvs)
+			.map(|((&position, &normal), &uv)| Vertex::new(position, [1.0; 3], normal, [1.0, 0.0, 0.0, 1.0], uv))
+			.collect();
+		let indices = optimize_vertex_cache(&sphere.indices, vertices.len());
+		let reordered = optimize_overdraw(&vertices, &indices);
+		assert_eq!(sorted_triangles(&reordered), sorted_triangles(&sphere.indices));
+	}
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use cgmath::{vec3, InnerSpace, Vector3};
+
+use scene::object::Object3D;
+
+/*
+.#####...#####...######..##...##..######..######..######..##..##..######...####..
+.##..##..##..##....##....###.###....##......##......##....##..##..##......##.....
+.#####...#####.....##....##.#.##....##......##......##....##..##..####.....####..
+.##......##..##....##....##...##....##......##......##.....####...##..........##.
+.##......##..##..######..##...##..######....##....######....##....######...####..
+.................................................................................
+*/
+
+// ? Indexed triangle mesh with per-vertex attributes, triangles wound counter-clockwise seen from outside.
+// ? UVs follow the OBJ convention, v goes up.
+#[derive(Clone, Debug, Default)]
+pub struct Mesh {
+	pub positions: Vec<[f32; 3]>,
+	pub normals: Vec<[f32; 3]>,
+	pub uvs: Vec<[f32; 2]>,
+	pub indices: Vec<u32>,
+}
+
+impl Mesh {
+	pub fn new() -> Self {
+		Mesh::default()
+	}
+
+	pub fn vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, uv: [f32; 2]) -> u32 {
+		self.positions.push(position.into());
+		self.normals.push(normal.into());
+		self.uvs.push(uv);
+		(self.positions.len() - 1) as u32
+	}
+
+	pub fn triangle(&mut self, a: u32, b: u32, c: u32) {
+		self.indices.extend_from_slice(&[a, b, c]);
+	}
+
+	pub fn quad(&mut self, a: u32, b: u32, c: u32, d: u32) {
+		self.triangle(a, b, c);
+		self.triangle(c, d, a);
+	}
+
+	// ? Object3D takes normals and uvs per triangle corner
+	pub fn into_object(self, name: &str) -> Object3D {
+		let normals = self.indices.iter().map(|&index| self.normals[index as usize]).collect();
+		let uvs = self.indices.iter().map(|&index| self.uvs[index as usize]).collect();
+		Object3D::new(name.to_string(), self.positions, self.indices, uvs, normals, None)
+	}
+
+	// ? Surface of revolution around +Y, each profile point going upwards gives a ring of `segments` quads.
+	// ? Rings of radius 0 are poles, their degenerate triangles are skipped.
+	fn lathe(&mut self, profile: &[ProfilePoint], segments: u32) {
+		let first = self.positions.len() as u32;
+		for point in profile {
+			for i in 0..=segments {
+				let u = i as f32 / segments as f32;
+				let (sin, cos) = (2.0 * PI * u).sin_cos();
+				self.vertex(
+					vec3(point.radius * sin, point.height, point.radius * cos),
+					vec3(point.normal[0] * sin, point.normal[1], point.normal[0] * cos).normalize(),
+					[u, point.v]
+				);
+			}
+		}
+		let columns = segments + 1;
+		for row in 0..profile.len() as u32 - 1 {
+			for i in 0..segments {
+				let a = first + row * columns + i;
+				let (b, c, d) = (a + 1, a + columns + 1, a + columns);
+				if profile[row as usize].radius > 0.0 {
+					self.triangle(a, b, c);
+				}
+				if profile[row as usize + 1].radius > 0.0 {
+					self.triangle(c, d, a);
+				}
+			}
+		}
+	}
+
+	// ? Flat disk facing up or down, uvs mapped from above
+	fn disk(&mut self, radius: f32, height: f32, segments: u32, facing_up: bool) {
+		let normal = vec3(0.0, if facing_up { 1.0 } else { -1.0 }, 0.0);
+		let center = self.vertex(vec3(0.0, height, 0.0), normal, [0.5, 0.5]);
+		let first = self.positions.len() as u32;
+		for i in 0..=segments {
+			let (sin, cos) = (2.0 * PI * i as f32 / segments as f32).sin_cos();
+			self.vertex(vec3(radius * sin, height, radius * cos), normal, [0.5 + 0.5 * sin, 0.5 + 0.5 * cos]);
+		}
+		for i in 0..segments {
+			if facing_up {
+				self.triangle(center, first + i, first + i + 1);
+			} else {
+				self.triangle(center, first + i + 1, first + i);
+			}
+		}
+	}
+}
+
+struct ProfilePoint {
+	radius: f32,
+	height: f32,
+	// ? Radial and vertical parts of the normal
+	normal: [f32; 2],
+	v: f32,
+}
+
+// ? Half circle from the south to the north pole, `rings` rows between them
+fn sphere_profile(radius: f32, rings: u32, height_offset: f32, v_range: (f32, f32), angle_range: (f32, f32)) -> Vec<ProfilePoint> {
+	(0..=rings).map(|j| {
+		let t = j as f32 / rings as f32;
+		let angle = angle_range.0 + (angle_range.1 - angle_range.0) * t;
+		let (sin, cos) = angle.sin_cos();
+		ProfilePoint {
+			// ? Exactly 0 at the poles so lathe() can skip their triangles
+			radius: if sin.abs() < 1e-6 { 0.0 } else { radius * sin },
+			height: height_offset - radius * cos,
+			normal: [sin, -cos],
+			v: v_range.0 + (v_range.1 - v_range.0) * t,
+		}
+	}).collect()
+}
+
+pub fn cube(size: f32, segments: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let half = size / 2.0;
+	let segments = segments.max(1);
+	// ? (normal, u axis, v axis), u x v = normal so the faces wind counter-clockwise from outside
+	let faces = [
+		(vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0), vec3(0.0, 1.0, 0.0)),
+		(vec3(-1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 0.0)),
+		(vec3(0.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0)),
+		(vec3(0.0, -1.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)),
+		(vec3(0.0, 0.0, 1.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)),
+		(vec3(0.0, 0.0, -1.0), vec3(-1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)),
+	];
+	for &(normal, u_axis, v_axis) in &faces {
+		let first = mesh.positions.len() as u32;
+		for j in 0..=segments {
+			for i in 0..=segments {
+				let (u, v) = (i as f32 / segments as f32, j as f32 / segments as f32);
+				let position = (normal + u_axis * (2.0 * u - 1.0) + v_axis * (2.0 * v - 1.0)) * half;
+				mesh.vertex(position, normal, [u, v]);
+			}
+		}
+		grid_quads(&mut mesh, first, segments, segments);
+	}
+	mesh
+}
+
+// ? Quads of a (columns + 1) x (rows + 1) vertex grid laid out row by row
+fn grid_quads(mesh: &mut Mesh, first: u32, columns: u32, rows: u32) {
+	for j in 0..rows {
+		for i in 0..columns {
+			let a = first + j * (columns + 1) + i;
+			mesh.quad(a, a + 1, a + columns + 2, a + columns + 1);
+		}
+	}
+}
+
+// ? In the XZ plane facing +Y
+pub fn plane(width: f32, depth: f32, x_segments: u32, z_segments: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let (x_segments, z_segments) = (x_segments.max(1), z_segments.max(1));
+	for j in 0..=z_segments {
+		for i in 0..=x_segments {
+			let (u, v) = (i as f32 / x_segments as f32, j as f32 / z_segments as f32);
+			mesh.vertex(vec3((u - 0.5) * width, 0.0, (0.5 - v) * depth), vec3(0.0, 1.0, 0.0), [u, v]);
+		}
+	}
+	grid_quads(&mut mesh, 0, x_segments, z_segments);
+	mesh
+}
+
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	mesh.lathe(&sphere_profile(radius, rings.max(2), 0.0, (0.0, 1.0), (0.0, PI)), segments.max(3));
+	mesh
+}
+
+// ? Subdivided icosahedron, evenly spread triangles. Uvs are spherical, vertices on the seam are split.
+pub fn icosphere(radius: f32, subdivisions: u32) -> Mesh {
+	let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+	let mut positions: Vec<Vector3<f32>> = [
+		(-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+		(0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+		(t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+	].iter().map(|&(x, y, z)| vec3(x, y, z).normalize()).collect();
+	let mut triangles: Vec<[u32; 3]> = vec![
+		[0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+		[1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+		[3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+		[4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+	];
+	for _ in 0..subdivisions {
+		let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+		let mut midpoint = |a: u32, b: u32, positions: &mut Vec<Vector3<f32>>| {
+			let key = (a.min(b), a.max(b));
+			*midpoints.entry(key).or_insert_with(|| {
+				let middle = ((positions[a as usize] + positions[b as usize]) / 2.0).normalize();
+				positions.push(middle);
+				(positions.len() - 1) as u32
+			})
+		};
+		triangles = triangles.iter().flat_map(|&[a, b, c]| {
+			let ab = midpoint(a, b, &mut positions);
+			let bc = midpoint(b, c, &mut positions);
+			let ca = midpoint(c, a, &mut positions);
+			vec![[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+		}).collect();
+	}
+
+	let spherical_uv = |direction: Vector3<f32>| {
+		[0.5 + direction.x.atan2(direction.z) / (2.0 * PI), 0.5 + direction.y.max(-1.0).min(1.0).asin() / PI]
+	};
+	let mut mesh = Mesh::new();
+	for &direction in &positions {
+		mesh.vertex(direction * radius, direction, spherical_uv(direction));
+	}
+	// ? Triangles crossing the u = 0 / 1 seam get copies of their low u vertices shifted by 1
+	let mut seam_copies: HashMap<u32, u32> = HashMap::new();
+	for triangle in &triangles {
+		let mut triangle = *triangle;
+		let us: Vec<f32> = triangle.iter().map(|&index| mesh.uvs[index as usize][0]).collect();
+		let max_u = us.iter().cloned().fold(0.0, f32::max);
+		if max_u - us.iter().cloned().fold(1.0, f32::min) > 0.5 {
+			for corner in triangle.iter_mut() {
+				if mesh.uvs[*corner as usize][0] < 0.5 {
+					let index = *corner;
+					*corner = *seam_copies.entry(index).or_insert_with(|| {
+						let (position, normal, uv) = (mesh.positions[index as usize], mesh.normals[index as usize], mesh.uvs[index as usize]);
+						mesh.positions.push(position);
+						mesh.normals.push(normal);
+						mesh.uvs.push([uv[0] + 1.0, uv[1]]);
+						(mesh.positions.len() - 1) as u32
+					});
+				}
+			}
+		}
+		mesh.triangle(triangle[0], triangle[1], triangle[2]);
+	}
+	mesh
+}
+
+// ? Along Y, centered on the origin, closed by caps
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let segments = segments.max(3);
+	let half = height / 2.0;
+	mesh.lathe(&[
+		ProfilePoint { radius, height: -half, normal: [1.0, 0.0], v: 0.0 },
+		ProfilePoint { radius, height: half, normal: [1.0, 0.0], v: 1.0 },
+	], segments);
+	mesh.disk(radius, half, segments, true);
+	mesh.disk(radius, -half, segments, false);
+	mesh
+}
+
+// ? Apex up, base centered at -height / 2
+pub fn cone(radius: f32, height: f32, segments: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let segments = segments.max(3);
+	let half = height / 2.0;
+	// ? The side normal leans up by the slope of the cone
+	let normal = [height, radius];
+	mesh.lathe(&[
+		ProfilePoint { radius, height: -half, normal, v: 0.0 },
+		ProfilePoint { radius: 0.0, height: half, normal, v: 1.0 },
+	], segments);
+	mesh.disk(radius, -half, segments, false);
+	mesh
+}
+
+// ? Around Y, the tube of radius minor_radius follows a circle of radius major_radius
+pub fn torus(major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let (major_segments, minor_segments) = (major_segments.max(3), minor_segments.max(3));
+	for j in 0..=minor_segments {
+		let v = j as f32 / minor_segments as f32;
+		// ? Starts on the inside of the ring so the seam is hidden
+		let (tube_sin, tube_cos) = (2.0 * PI * v + PI).sin_cos();
+		for i in 0..=major_segments {
+			let u = i as f32 / major_segments as f32;
+			let (sin, cos) = (2.0 * PI * u).sin_cos();
+			let outward = vec3(sin, 0.0, cos);
+			let normal = outward * tube_cos + vec3(0.0, tube_sin, 0.0);
+			mesh.vertex(outward * major_radius + normal * minor_radius, normal, [u, v]);
+		}
+	}
+	grid_quads(&mut mesh, 0, major_segments, minor_segments);
+	mesh
+}
+
+// ? Cylinder of the given height between two hemispheres, the total height is height + 2 * radius
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> Mesh {
+	let mut mesh = Mesh::new();
+	let rings = rings.max(1);
+	let half = height / 2.0;
+	let total = height + 2.0 * radius;
+	// ? v is proportional to the height so the texture isn't stretched along the cylinder
+	let v_bottom = radius / total;
+	let mut profile = sphere_profile(radius, rings, -half, (0.0, v_bottom), (0.0, PI / 2.0));
+	profile.extend(sphere_profile(radius, rings, half, (1.0 - v_bottom, 1.0), (PI / 2.0, PI)));
+	mesh.lathe(&profile, segments.max(3));
+	mesh
+}
+
+// ? Procedural shapes with their parameters, see Primitive::from_spec
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primitive {
+	Cube { size: f32, segments: u32 },
+	Plane { width: f32, depth: f32, segments: u32 },
+	UvSphere { radius: f32, segments: u32, rings: u32 },
+	Icosphere { radius: f32, subdivisions: u32 },
+	Cylinder { radius: f32, height: f32, segments: u32 },
+	Cone { radius: f32, height: f32, segments: u32 },
+	Torus { major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32 },
+	Capsule { radius: f32, height: f32, segments: u32, rings: u32 },
+}
+
+impl Primitive {
+	// ? `name` or `name:count[,count]` with the segment counts of the shape, for example `uv_sphere:32,16`.
+	// ? Shapes are about 2 units wide: cube, plane, uv_sphere, icosphere, cylinder, cone, torus, capsule.
+	pub fn from_spec(spec: &str) -> Result<Self, String> {
+		let mut parts = spec.splitn(2, ':');
+		let name = parts.next().unwrap_or("");
+		let counts = match parts.next() {
+			Some(counts) => counts.split(',').map(|count| count.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>()
+				.map_err(|_| format!("Invalid segment counts in primitive: {}", spec))?,
+			None => vec![],
+		};
+		let count = |index: usize, default: u32| counts.get(index).cloned().unwrap_or(default);
+		match name {
+			"cube" => Ok(Primitive::Cube { size: 2.0, segments: count(0, 1) }),
+			"plane" => Ok(Primitive::Plane { width: 2.0, depth: 2.0, segments: count(0, 1) }),
+			"uv_sphere" | "sphere" => Ok(Primitive::UvSphere { radius: 1.0, segments: count(0, 32), rings: count(1, 16) }),
+			"icosphere" => Ok(Primitive::Icosphere { radius: 1.0, subdivisions: count(0, 3) }),
+			"cylinder" => Ok(Primitive::Cylinder { radius: 1.0, height: 2.0, segments: count(0, 32) }),
+			"cone" => Ok(Primitive::Cone { radius: 1.0, height: 2.0, segments: count(0, 32) }),
+			"torus" => Ok(Primitive::Torus { major_radius: 0.75, minor_radius: 0.25, major_segments: count(0, 48), minor_segments: count(1, 24) }),
+			"capsule" => Ok(Primitive::Capsule { radius: 0.5, height: 1.0, segments: count(0, 32), rings: count(1, 8) }),
+			_ => Err(format!("Unknown primitive: {}", name)),
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Primitive::Cube { .. } => "cube",
+			Primitive::Plane { .. } => "plane",
+			Primitive::UvSphere { .. } => "uv_sphere",
+			Primitive::Icosphere { .. } => "icosphere",
+			Primitive::Cylinder { .. } => "cylinder",
+			Primitive::Cone { .. } => "cone",
+			Primitive::Torus { .. } => "torus",
+			Primitive::Capsule { .. } => "capsule",
+		}
+	}
+
+	pub fn mesh(&self) -> Mesh {
+		match *self {
+			Primitive::Cube { size, segments } => cube(size, segments),
+			Primitive::Plane { width, depth, segments } => plane(width, depth, segments, segments),
+			Primitive::UvSphere { radius, segments, rings } => uv_sphere(radius, segments, rings),
+			Primitive::Icosphere { radius, subdivisions } => icosphere(radius, subdivisions),
+			Primitive::Cylinder { radius, height, segments } => cylinder(radius, height, segments),
+			Primitive::Cone { radius, height, segments } => cone(radius, height, segments),
+			Primitive::Torus { major_radius, minor_radius, major_segments, minor_segments } => torus(major_radius, minor_radius, major_segments, minor_segments),
+			Primitive::Capsule { radius, height, segments, rings } => capsule(radius, height, segments, rings),
+		}
+	}
+
+	pub fn object(&self) -> Object3D {
+		self.mesh().into_object(self.name())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// ? Every triangle of a convex shape around the origin faces away from it, like the normals of its corners
+	fn assert_faces_outwards(mesh: &Mesh) {
+		assert!(!mesh.indices.is_empty());
+		for triangle in mesh.indices.chunks(3) {
+			let p: Vec<Vector3<f32>> = triangle.iter().map(|&index| Vector3::from(mesh.positions[index as usize])).collect();
+			// ? Counter-clockwise from outside, not normalized
+			let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+			assert!(normal.magnitude2() > 0.0);
+			assert!(normal.dot((p[0] + p[1] + p[2]) / 3.0) > 0.0);
+			for &index in triangle {
+				assert!(normal.dot(Vector3::from(mesh.normals[index as usize])) > 0.0);
+			}
+		}
+	}
+
+	#[test]
+	fn convex_shapes_face_outwards() {
+		assert_faces_outwards(&cube(2.0, 3));
+		assert_faces_outwards(&uv_sphere(1.0, 16, 8));
+		assert_faces_outwards(&icosphere(1.0, 2));
+		assert_faces_outwards(&cylinder(1.0, 2.0, 12));
+		assert_faces_outwards(&cone(1.0, 2.0, 12));
+		assert_faces_outwards(&capsule(0.5, 1.0, 12, 4));
+	}
+
+	#[test]
+	fn triangle_counts() {
+		assert_eq!(cube(2.0, 3).indices.len() / 3, 6 * 3 * 3 * 2);
+		assert_eq!(plane(2.0, 2.0, 4, 2).indices.len() / 3, 4 * 2 * 2);
+		assert_eq!(icosphere(1.0, 2).indices.len() / 3, 20 * 4 * 4);
+		// ? The triangles touching the poles are single
+		assert_eq!(uv_sphere(1.0, 16, 8).indices.len() / 3, 16 * (8 - 1) * 2);
+	}
+
+	#[test]
+	fn spheres_have_their_radius() {
+		for mesh in &[uv_sphere(2.0, 16, 8), icosphere(2.0, 2)] {
+			for &position in &mesh.positions {
+				assert!((Vector3::from(position).magnitude() - 2.0).abs() < 1e-5);
+			}
+		}
+	}
+
+	#[test]
+	fn from_spec() {
+		assert_eq!(Primitive::from_spec("uv_sphere:8,4"), Ok(Primitive::UvSphere { radius: 1.0, segments: 8, rings: 4 }));
+		assert_eq!(Primitive::from_spec("cube"), Ok(Primitive::Cube { size: 2.0, segments: 1 }));
+		assert!(Primitive::from_spec("cube:x").is_err());
+		assert!(Primitive::from_spec("teapot").is_err());
+	}
+}
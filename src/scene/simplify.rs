@@ -0,0 +1,412 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Add;
+
+use cgmath::Vector3;
+use cgmath::InnerSpace;
+
+use common::*;
+use scene::normals;
+
+/*
+..####...######..##...##..#####...##......######..######..##..##.
+.##........##....###.###..##..##..##........##....##.......####..
+..####.....##....##.#.##..#####...##........##....####......##...
+.....##....##....##...##..##......##........##....##........##...
+..####...######..##...##..##......######..######..##........##...
+.................................................................
+*/
+
+// ? How much moving a border or uv seam costs compared to moving the surface
+const BOUNDARY_WEIGHT: f64 = 10.0;
+// ? A collapse is refused when it turns a triangle by more than ~80 degrees
+const MIN_NORMAL_DOT: f32 = 0.2;
+
+// ? Reduces a triangle list to about `target_triangles` with quadric error metrics.
+// ? See: Garland and Heckbert, Surface Simplification Using Quadric Error Metrics, 1997
+// ? Collapses move a vertex onto one of its neighbours, so the remaining vertices keep their exact attributes.
+// ? Vertices on borders and uv seams may only slide along them, and those meeting several boundaries never move.
+pub fn simplify(vertices: &[Vertex], target_triangles: usize) -> Vec<Vertex> {
+	let mut simplifier = Simplifier::new(vertices);
+	simplifier.run(target_triangles);
+	simplifier.vertices()
+}
+
+// ? Symmetric 4x4 matrix measuring the squared distance to a set of planes: aa ab ac ad bb bc bd cc cd dd
+#[derive(Clone, Copy)]
+struct Quadric([f64; 10]);
+impl Quadric {
+	fn zero() -> Self {
+		Quadric([0.0; 10])
+	}
+
+	fn from_plane(normal: Vector3<f32>, point: Vector3<f32>, weight: f64) -> Self {
+		let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+		let d = -normal.dot(point) as f64;
+		let mut quadric = Quadric([
+			a * a, a * b, a * c, a * d,
+			b * b, b * c, b * d,
+			c * c, c * d,
+			d * d,
+		]);
+		for value in quadric.0.iter_mut() {
+			*value *= weight;
+		}
+		quadric
+	}
+
+	fn evaluate(&self, point: Vector3<f32>) -> f64 {
+		let q = &self.0;
+		let (x, y, z) = (point.x as f64, point.y as f64, point.z as f64);
+		q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+			+ q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+			+ q[7] * z * z + 2.0 * q[8] * z
+			+ q[9]
+	}
+}
+impl Add for Quadric {
+	type Output = Quadric;
+
+	fn add(self, other: Quadric) -> Quadric {
+		let mut sum = self;
+		for (value, other) in sum.0.iter_mut().zip(other.0.iter()) {
+			*value += other;
+		}
+		sum
+	}
+}
+
+// ? Moving position `from` onto position `to`, ordered so that the heap pops the cheapest first
+struct Collapse {
+	cost: f64,
+	from: u32,
+	to: u32,
+	versions: (u32, u32),
+}
+impl PartialEq for Collapse {
+	fn eq(&self, other: &Collapse) -> bool {
+		self.cost == other.cost
+	}
+}
+impl Eq for Collapse {}
+impl PartialOrd for Collapse {
+	fn partial_cmp(&self, other: &Collapse) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+impl Ord for Collapse {
+	fn cmp(&self, other: &Collapse) -> Ordering {
+		other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+	}
+}
+
+// ? Triangles index attribute vertices (unique position, normal and uv), which are grouped by position.
+// ? Triangles and positions are never deleted, only flagged, the adjacency lists are filtered when read.
+struct Simplifier {
+	attributes: Vec<Vertex>,
+	attribute_positions: Vec<u32>,
+	positions: Vec<Vector3<f32>>,
+	triangles: Vec<[u32; 3]>,
+	removed: Vec<bool>,
+	live_triangles: usize,
+	position_triangles: Vec<Vec<usize>>,
+	quadrics: Vec<Quadric>,
+	collapsed: Vec<bool>,
+	// ? Bumped when a position's quadric changes, older collapses in the heap are stale
+	versions: Vec<u32>,
+	heap: BinaryHeap<Collapse>,
+}
+impl Simplifier {
+	fn new(vertices: &[Vertex]) -> Self {
+		let mut attributes = vec![];
+		let mut attribute_lookup: HashMap<[u32; 8], u32> = HashMap::new();
+		let mut corners = vec![];
+		for vertex in vertices {
+			let (p, n, uv) = (vertex.position(), vertex.normal(), vertex.uv());
+			let key = [
+				p[0].to_bits(), p[1].to_bits(), p[2].to_bits(),
+				n[0].to_bits(), n[1].to_bits(), n[2].to_bits(),
+				uv[0].to_bits(), uv[1].to_bits(),
+			];
+			let index = *attribute_lookup.entry(key).or_insert_with(|| {
+				attributes.push(*vertex);
+				attributes.len() as u32 - 1
+			});
+			corners.push(index);
+		}
+		let attribute_points: Vec<[f32; 3]> = attributes.iter().map(Vertex::position).collect();
+		let (positions, attribute_positions) = normals::weld(&attribute_points);
+		let positions: Vec<Vector3<f32>> = positions.into_iter().map(Vector3::from).collect();
+
+		// ? Triangles that are already degenerate would only get in the way
+		let triangles: Vec<[u32; 3]> = corners.chunks(3)
+			.filter(|corners| corners.len() == 3)
+			.map(|corners| [corners[0], corners[1], corners[2]])
+			.filter(|triangle| {
+				let p = |corner: usize| attribute_positions[triangle[corner] as usize];
+				p(0) != p(1) && p(1) != p(2) && p(2) != p(0)
+			})
+			.collect();
+
+		let mut position_triangles = vec![vec![]; positions.len()];
+		for (t, triangle) in triangles.iter().enumerate() {
+			for attribute in triangle {
+				position_triangles[attribute_positions[*attribute as usize] as usize].push(t);
+			}
+		}
+
+		let mut simplifier = Simplifier {
+			attributes,
+			attribute_positions,
+			live_triangles: triangles.len(),
+			removed: vec![false; triangles.len()],
+			triangles,
+			quadrics: vec![Quadric::zero(); positions.len()],
+			collapsed: vec![false; positions.len()],
+			versions: vec![0; positions.len()],
+			positions,
+			position_triangles,
+			heap: BinaryHeap::new(),
+		};
+		simplifier.evaluate_quadrics();
+		for p in 0..simplifier.positions.len() as u32 {
+			simplifier.push_collapses(p);
+		}
+		simplifier
+	}
+
+	// ? Area weighted triangle planes, plus planes perpendicular to the triangles along borders and seams
+	fn evaluate_quadrics(&mut self) {
+		for t in 0..self.triangles.len() {
+			let p = self.triangle_positions(t);
+			let points = [self.positions[p[0] as usize], self.positions[p[1] as usize], self.positions[p[2] as usize]];
+			let (normal, area) = normals::triangle_normal(points[0], points[1], points[2]);
+			if area == 0.0 {
+				continue;
+			}
+			let quadric = Quadric::from_plane(normal, points[0], area as f64);
+			for k in 0..3 {
+				self.quadrics[p[k] as usize] = self.quadrics[p[k] as usize] + quadric;
+			}
+			for k in 0..3 {
+				let (a, b) = (p[k], p[(k + 1) % 3]);
+				if !self.is_boundary_edge(a, b) {
+					continue;
+				}
+				let edge = points[(k + 1) % 3] - points[k];
+				let perpendicular = edge.cross(normal);
+				if perpendicular.magnitude2() == 0.0 {
+					continue;
+				}
+				let quadric = Quadric::from_plane(perpendicular.normalize(), points[k], BOUNDARY_WEIGHT * edge.magnitude2() as f64);
+				self.quadrics[a as usize] = self.quadrics[a as usize] + quadric;
+				self.quadrics[b as usize] = self.quadrics[b as usize] + quadric;
+			}
+		}
+	}
+
+	fn run(&mut self, target_triangles: usize) {
+		while self.live_triangles > target_triangles {
+			let collapse = match self.heap.pop() {
+				Some(collapse) => collapse,
+				None => break,
+			};
+			let (from, to) = (collapse.from as usize, collapse.to as usize);
+			if self.collapsed[from] || self.collapsed[to] || collapse.versions != (self.versions[from], self.versions[to]) {
+				continue;
+			}
+			if let Some(remap) = self.plan_collapse(collapse.from, collapse.to) {
+				self.apply_collapse(collapse.from, collapse.to, &remap);
+				self.push_collapses(collapse.to);
+			}
+		}
+	}
+
+	fn vertices(&self) -> Vec<Vertex> {
+		let mut vertices = Vec::with_capacity(3 * self.live_triangles);
+		for (t, triangle) in self.triangles.iter().enumerate() {
+			if self.removed[t] {
+				continue;
+			}
+			for attribute in triangle {
+				vertices.push(self.attributes[*attribute as usize]);
+			}
+		}
+		vertices
+	}
+
+	fn triangle_positions(&self, t: usize) -> [u32; 3] {
+		let triangle = self.triangles[t];
+		[
+			self.attribute_positions[triangle[0] as usize],
+			self.attribute_positions[triangle[1] as usize],
+			self.attribute_positions[triangle[2] as usize],
+		]
+	}
+
+	fn triangles_around(&self, p: u32) -> Vec<usize> {
+		self.position_triangles[p as usize].iter().cloned().filter(|&t| !self.removed[t]).collect()
+	}
+
+	fn neighbours(&self, p: u32) -> Vec<u32> {
+		let mut neighbours: Vec<u32> = self.triangles_around(p).into_iter()
+			.flat_map(|t| self.triangle_positions(t).to_vec())
+			.filter(|&q| q != p)
+			.collect();
+		neighbours.sort();
+		neighbours.dedup();
+		neighbours
+	}
+
+	// ? The attribute vertex a triangle uses at a position
+	fn attribute_at(&self, t: usize, p: u32) -> Option<u32> {
+		self.triangles[t].iter().cloned().find(|&attribute| self.attribute_positions[attribute as usize] == p)
+	}
+
+	fn edge_triangles(&self, a: u32, b: u32) -> Vec<usize> {
+		self.triangles_around(a).into_iter().filter(|&t| self.attribute_at(t, b).is_some()).collect()
+	}
+
+	// ? Borders have a single triangle, seams have two that disagree on the attributes, anything else is non-manifold
+	fn is_boundary_edge(&self, a: u32, b: u32) -> bool {
+		let triangles = self.edge_triangles(a, b);
+		triangles.len() != 2
+			|| self.attribute_at(triangles[0], a) != self.attribute_at(triangles[1], a)
+			|| self.attribute_at(triangles[0], b) != self.attribute_at(triangles[1], b)
+	}
+
+	fn push_collapses(&mut self, p: u32) {
+		for q in self.neighbours(p) {
+			for &(from, to) in &[(p, q), (q, p)] {
+				let (from_index, to_index) = (from as usize, to as usize);
+				let cost = (self.quadrics[from_index] + self.quadrics[to_index]).evaluate(self.positions[to_index]);
+				self.heap.push(Collapse {
+					cost,
+					from,
+					to,
+					versions: (self.versions[from_index], self.versions[to_index]),
+				});
+			}
+		}
+	}
+
+	// ? Checks that moving `from` onto `to` keeps the mesh manifold, its boundaries and its orientation,
+	// ? returns the attribute vertex of `to` replacing each attribute vertex of `from`
+	fn plan_collapse(&self, from: u32, to: u32) -> Option<Vec<(u32, u32)>> {
+		let edge_triangles = self.edge_triangles(from, to);
+		if edge_triangles.is_empty() {
+			return None;
+		}
+		let from_neighbours = self.neighbours(from);
+		let boundary_edges = from_neighbours.iter().filter(|&&q| self.is_boundary_edge(from, q)).count();
+		if boundary_edges != 0 && (boundary_edges != 2 || !self.is_boundary_edge(from, to)) {
+			return None;
+		}
+
+		// ? Link condition, the only neighbours both ends share are the opposite corners of the collapsed triangles
+		let to_neighbours = self.neighbours(to);
+		let shared = from_neighbours.iter().filter(|q| to_neighbours.contains(q)).count();
+		if shared != edge_triangles.len() {
+			return None;
+		}
+
+		let around = self.triangles_around(from);
+		let mut remap: Vec<(u32, u32)> = vec![];
+		for &t in &around {
+			let attribute = self.attribute_at(t, from).unwrap();
+			if remap.iter().any(|&(old, _)| old == attribute) {
+				continue;
+			}
+			let replacement = edge_triangles.iter()
+				.find(|&&e| self.attribute_at(e, from) == Some(attribute))
+				.and_then(|&e| self.attribute_at(e, to));
+			match replacement {
+				Some(replacement) => remap.push((attribute, replacement)),
+				// ? This side of a seam doesn't reach `to`, there is no attribute to give it
+				None => return None,
+			}
+		}
+
+		for &t in &around {
+			if edge_triangles.contains(&t) {
+				continue;
+			}
+			let p = self.triangle_positions(t);
+			let points: Vec<Vector3<f32>> = p.iter().map(|&q| self.positions[q as usize]).collect();
+			let moved: Vec<Vector3<f32>> = p.iter().map(|&q| {
+				let q = if q == from { to } else { q };
+				self.positions[q as usize]
+			}).collect();
+			let (old_normal, _) = normals::triangle_normal(points[0], points[1], points[2]);
+			let (new_normal, new_area) = normals::triangle_normal(moved[0], moved[1], moved[2]);
+			if new_area == 0.0 || old_normal.dot(new_normal) < MIN_NORMAL_DOT {
+				return None;
+			}
+		}
+		Some(remap)
+	}
+
+	fn apply_collapse(&mut self, from: u32, to: u32, remap: &[(u32, u32)]) {
+		for t in self.triangles_around(from) {
+			if self.attribute_at(t, to).is_some() {
+				self.removed[t] = true;
+				self.live_triangles -= 1;
+				continue;
+			}
+			for corner in 0..3 {
+				let attribute = self.triangles[t][corner];
+				if let Some(&(_, replacement)) = remap.iter().find(|&&(old, _)| old == attribute) {
+					self.triangles[t][corner] = replacement;
+				}
+			}
+			self.position_triangles[to as usize].push(t);
+		}
+		self.position_triangles[from as usize].clear();
+		self.quadrics[to as usize] = self.quadrics[to as usize] + self.quadrics[from as usize];
+		self.collapsed[from as usize] = true;
+		self.versions[to as usize] += 1;
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scene::primitives::Primitive;
+
+	fn triangles(vertices: &[Vertex]) -> usize {
+		vertices.len() / 3
+	}
+
+	#[test]
+	fn reaches_the_target_triangle_count() {
+		let shapes = [
+			Primitive::Plane { width: 2.0, depth: 2.0, segments: 16 },
+			Primitive::UvSphere { radius: 1.0, segments: 32, rings: 16 },
+		];
+		for shape in &shapes {
+			let vertices = shape.object().vertices;
+			let target = triangles(&vertices) / 4;
+			let simplified = simplify(&vertices, target);
+			assert!(triangles(&simplified) <= target, "{:?} kept {} triangles", shape, triangles(&simplified));
+			assert!(triangles(&simplified) > 0);
+		}
+	}
+
+	#[test]
+	fn keeps_meshes_under_the_target() {
+		let vertices = Primitive::Cube { size: 2.0, segments: 1 }.object().vertices;
+		assert_eq!(simplify(&vertices, 100).len(), vertices.len());
+	}
+
+	#[test]
+	fn flat_plane_keeps_its_outline() {
+		let vertices = Primitive::Plane { width: 2.0, depth: 2.0, segments: 8 }.object().vertices;
+		let simplified = simplify(&vertices, 2);
+		let area: f32 = simplified.chunks(3).map(|triangle| {
+			let p: Vec<Vector3<f32>> = triangle.iter().map(|vertex| Vector3::from(vertex.position())).collect();
+			normals::triangle_normal(p[0], p[1], p[2]).1
+		}).sum();
+		assert!((area - 4.0).abs() < 1e-4);
+	}
+}
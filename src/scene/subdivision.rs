@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+
+use cgmath::{Deg, Rad, vec3, Vector3};
+use cgmath::InnerSpace;
+
+use common::*;
+use scene::normals;
+
+/*
+..####...##..##..#####...#####...######..##..##..######...####...######...####...##..##.
+.##......##..##..##..##..##..##....##....##..##....##....##........##....##..##..###.##.
+..####...##..##..#####...##..##....##....##..##....##.....####.....##....##..##..##.###.
+.....##..##..##..##..##..##..##....##.....####.....##........##....##....##..##..##..##.
+..####....####...#####...#####...######....##....######...####...######...####...##..##.
+........................................................................................
+*/
+
+// ? Every level multiplies the triangle count by 4
+pub const MAX_SUBDIVISION_LEVELS: u32 = 4;
+// ? Consecutive triangles fanning out of the same corner are merged back into a polygon when they are this flat,
+// ? the OBJ importer triangulates polygons that way
+const COPLANAR_DOT: f32 = 0.999;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubdivisionScheme {
+	// ? Catmull-Clark when most faces are quads, Loop otherwise
+	Auto,
+	Loop,
+	CatmullClark,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SubdivisionOptions {
+	pub scheme: SubdivisionScheme,
+	pub levels: u32,
+	// ? Edges whose faces meet at more than this angle stay sharp, as do borders
+	pub crease_angle: Option<Deg<f32>>,
+}
+impl Default for SubdivisionOptions {
+	fn default() -> Self {
+		SubdivisionOptions {
+			scheme: SubdivisionScheme::Auto,
+			levels: 0,
+			crease_angle: None,
+		}
+	}
+}
+impl SubdivisionOptions {
+	// ? "<auto|loop|catmull-clark>[:levels[:crease angle in degrees]]", e.g. "auto:2" or "catmull-clark:1:40"
+	pub fn from_spec(spec: &str) -> Result<Self, String> {
+		let parts: Vec<&str> = spec.split(':').collect();
+		let scheme = match parts[0] {
+			"auto" => SubdivisionScheme::Auto,
+			"loop" => SubdivisionScheme::Loop,
+			"catmull-clark" => SubdivisionScheme::CatmullClark,
+			_ => return Err(format!("Unknown subdivision scheme: {}", spec)),
+		};
+		let levels = match parts.get(1) {
+			None => 1,
+			Some(levels) => match levels.parse::<u32>() {
+				Ok(levels) if levels <= MAX_SUBDIVISION_LEVELS => levels,
+				_ => return Err(format!("Invalid subdivision levels: {}, at most {}", spec, MAX_SUBDIVISION_LEVELS)),
+			},
+		};
+		let crease_angle = match parts.get(2) {
+			None => None,
+			Some(angle) => match angle.parse::<f32>() {
+				Ok(angle) if angle >= 0.0 && angle <= 180.0 => Some(Deg(angle)),
+				_ => return Err(format!("Invalid crease angle: {}", spec)),
+			},
+		};
+		if parts.len() > 3 {
+			return Err(format!("Invalid subdivision: {}", spec));
+		}
+		Ok(SubdivisionOptions { scheme, levels, crease_angle })
+	}
+}
+
+// ? Subdivides a triangle list, returns the positions and uvs of the resulting triangles' corners
+pub fn subdivide(vertices: &[Vertex], options: &SubdivisionOptions) -> (Vec<[f32; 3]>, Vec<[f32; 2]>) {
+	let polygons = ControlMesh::from_vertices(vertices, true, options.crease_angle);
+	let catmull_clark = match options.scheme {
+		SubdivisionScheme::Loop => false,
+		SubdivisionScheme::CatmullClark => true,
+		SubdivisionScheme::Auto => polygons.is_quad_dominant(),
+	};
+	let mut mesh = if catmull_clark {
+		polygons
+	} else {
+		ControlMesh::from_vertices(vertices, false, options.crease_angle)
+	};
+	for _ in 0..options.levels {
+		mesh = if catmull_clark { mesh.catmull_clark() } else { mesh.loop_subdivision() };
+	}
+	mesh.corners()
+}
+
+// ? Edges are identified by their positions, smallest first
+type EdgeKey = (u32, u32);
+
+fn edge_key(a: u32, b: u32) -> EdgeKey {
+	if a < b { (a, b) } else { (b, a) }
+}
+
+fn other_end(edge: EdgeKey, p: u32) -> u32 {
+	if edge.0 == p { edge.1 } else { edge.0 }
+}
+
+fn midpoint(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+	[(a[0] + b[0]) * 0.5, (a[1] + b[1]) * 0.5]
+}
+
+// ? Where `triangle` continues `face` across one of the face's edges, going through it the other way: the next triangle
+// ? of a fan (a polygon as the OBJ importer triangulates it), or the second half of a quad split along either diagonal.
+// ? Returns the index in `face` to insert the triangle's third corner at.
+fn continuation(face: &[u32], triangle: &[u32]) -> Option<(usize, u32)> {
+	let n = face.len();
+	(0..3).filter_map(|k| {
+		let (a, b, c) = (triangle[k], triangle[(k + 1) % 3], triangle[(k + 2) % 3]);
+		// ? A polygon only grows along its closing edge, so that strips of coplanar quads stay apart
+		let edges = if n == 3 { 0..n } else { n - 1..n };
+		edges.filter(|&i| face[i] == b && face[(i + 1) % n] == a && !face.contains(&c))
+			.map(|i| (i + 1, c))
+			.next()
+	}).next()
+}
+
+enum VertexRule {
+	Smooth,
+	// ? On a border or crease, moves along it towards its two sharp neighbours
+	Crease(u32, u32),
+	// ? Where more than two sharp edges meet, doesn't move
+	Corner,
+}
+
+struct Topology {
+	edge_faces: HashMap<EdgeKey, Vec<usize>>,
+	vertex_edges: Vec<Vec<EdgeKey>>,
+}
+
+// ? Corners with the same uv around a position share an attribute, a uv seam gives a position several
+struct AttributeTable {
+	lookup: HashMap<(u32, [u32; 2]), u32>,
+	attributes: Vec<(u32, [f32; 2])>,
+}
+impl AttributeTable {
+	fn new() -> Self {
+		AttributeTable {
+			lookup: HashMap::new(),
+			attributes: vec![],
+		}
+	}
+
+	fn get(&mut self, position: u32, uv: [f32; 2]) -> u32 {
+		let attributes = &mut self.attributes;
+		*self.lookup.entry((position, [uv[0].to_bits(), uv[1].to_bits()])).or_insert_with(|| {
+			attributes.push((position, uv));
+			attributes.len() as u32 - 1
+		})
+	}
+}
+
+// ? Polygons over attributes (a position and a uv), with the topology taken from the positions only
+struct ControlMesh {
+	positions: Vec<Vector3<f32>>,
+	attributes: Vec<(u32, [f32; 2])>,
+	faces: Vec<Vec<u32>>,
+	// ? Sharp edges besides the borders
+	creases: HashSet<EdgeKey>,
+}
+impl ControlMesh {
+	fn from_vertices(vertices: &[Vertex], group_polygons: bool, crease_angle: Option<Deg<f32>>) -> Self {
+		let points: Vec<[f32; 3]> = vertices.iter().map(Vertex::position).collect();
+		let (positions, vertex_positions) = normals::weld(&points);
+		let positions: Vec<Vector3<f32>> = positions.into_iter().map(Vector3::from).collect();
+		let mut table = AttributeTable::new();
+		let corners: Vec<u32> = vertices.iter().zip(&vertex_positions)
+			.map(|(vertex, &position)| table.get(position, vertex.uv()))
+			.collect();
+
+		let mut mesh = ControlMesh {
+			positions,
+			attributes: table.attributes,
+			faces: vec![],
+			creases: HashSet::new(),
+		};
+		for triangle in corners.chunks(3).filter(|triangle| triangle.len() == 3) {
+			let p: Vec<u32> = triangle.iter().map(|&attribute| mesh.position(attribute)).collect();
+			if p[0] == p[1] || p[1] == p[2] || p[2] == p[0] {
+				continue;
+			}
+			let continuation = if group_polygons {
+				mesh.faces.last().and_then(|face| {
+					continuation(face, triangle).filter(|_| mesh.face_normal(face).dot(mesh.face_normal(triangle)) > COPLANAR_DOT)
+				})
+			} else {
+				None
+			};
+			match continuation {
+				Some((index, attribute)) => mesh.faces.last_mut().unwrap().insert(index, attribute),
+				None => mesh.faces.push(triangle.to_vec()),
+			}
+		}
+
+		if let Some(crease_angle) = crease_angle {
+			let min_cos = Rad::from(crease_angle).0.cos();
+			let topology = mesh.topology();
+			for (&edge, faces) in &topology.edge_faces {
+				if faces.len() == 2 && mesh.face_normal(&mesh.faces[faces[0]]).dot(mesh.face_normal(&mesh.faces[faces[1]])) < min_cos {
+					mesh.creases.insert(edge);
+				}
+			}
+		}
+		mesh
+	}
+
+	fn position(&self, attribute: u32) -> u32 {
+		self.attributes[attribute as usize].0
+	}
+
+	fn point(&self, attribute: u32) -> Vector3<f32> {
+		self.positions[self.position(attribute) as usize]
+	}
+
+	fn uv(&self, attribute: u32) -> [f32; 2] {
+		self.attributes[attribute as usize].1
+	}
+
+	// ? Sum of the fan's triangle normals, so it also holds for slightly bent polygons
+	fn face_normal(&self, face: &[u32]) -> Vector3<f32> {
+		let mut normal = vec3(0.0, 0.0, 0.0);
+		for i in 1..face.len() - 1 {
+			normal += (self.point(face[i]) - self.point(face[0])).cross(self.point(face[i + 1]) - self.point(face[0]));
+		}
+		if normal.magnitude2() > 0.0 { normal.normalize() } else { normal }
+	}
+
+	fn is_quad_dominant(&self) -> bool {
+		2 * self.faces.iter().filter(|face| face.len() == 4).count() > self.faces.len()
+	}
+
+	fn topology(&self) -> Topology {
+		let mut edge_faces: HashMap<EdgeKey, Vec<usize>> = HashMap::new();
+		for (f, face) in self.faces.iter().enumerate() {
+			for i in 0..face.len() {
+				let edge = edge_key(self.position(face[i]), self.position(face[(i + 1) % face.len()]));
+				edge_faces.entry(edge).or_insert_with(Vec::new).push(f);
+			}
+		}
+		let mut vertex_edges = vec![vec![]; self.positions.len()];
+		for &edge in edge_faces.keys() {
+			vertex_edges[edge.0 as usize].push(edge);
+			vertex_edges[edge.1 as usize].push(edge);
+		}
+		Topology { edge_faces, vertex_edges }
+	}
+
+	// ? Borders and non-manifold edges are sharp too
+	fn is_sharp(&self, topology: &Topology, edge: EdgeKey) -> bool {
+		topology.edge_faces[&edge].len() != 2 || self.creases.contains(&edge)
+	}
+
+	fn vertex_rule(&self, topology: &Topology, p: u32) -> VertexRule {
+		let edges = &topology.vertex_edges[p as usize];
+		if edges.is_empty() {
+			return VertexRule::Corner;
+		}
+		let sharp: Vec<EdgeKey> = edges.iter().cloned().filter(|&edge| self.is_sharp(topology, edge)).collect();
+		match sharp.len() {
+			0 | 1 => VertexRule::Smooth,
+			2 => VertexRule::Crease(other_end(sharp[0], p), other_end(sharp[1], p)),
+			_ => VertexRule::Corner,
+		}
+	}
+
+	// ? Sorted so that the same mesh always subdivides to the same vertex order
+	fn sorted_edges(topology: &Topology) -> Vec<EdgeKey> {
+		let mut edges: Vec<EdgeKey> = topology.edge_faces.keys().cloned().collect();
+		edges.sort();
+		edges
+	}
+
+	// ? Children of a sharp edge are sharp
+	fn split_creases(&self, edge_points: &HashMap<EdgeKey, u32>) -> HashSet<EdgeKey> {
+		let mut creases = HashSet::new();
+		for edge in &self.creases {
+			if let Some(&middle) = edge_points.get(edge) {
+				creases.insert(edge_key(edge.0, middle));
+				creases.insert(edge_key(middle, edge.1));
+			}
+		}
+		creases
+	}
+
+	// ? Every n-gon becomes n quads. See: Catmull and Clark, Recursively generated B-spline surfaces on arbitrary
+	// ? topological meshes, 1978, and DeRose et al., Subdivision Surfaces in Character Animation, 1998 for the creases
+	fn catmull_clark(&self) -> ControlMesh {
+		let topology = self.topology();
+		let face_points: Vec<Vector3<f32>> = self.faces.iter().map(|face| {
+			face.iter().fold(vec3(0.0, 0.0, 0.0), |sum, &attribute| sum + self.point(attribute)) / face.len() as f32
+		}).collect();
+
+		// ? Vertex points keep the positions' indices, edge points come after them, then face points
+		let mut positions = self.positions.clone();
+		let mut edge_points = HashMap::new();
+		for edge in ControlMesh::sorted_edges(&topology) {
+			let (a, b) = (self.positions[edge.0 as usize], self.positions[edge.1 as usize]);
+			let faces = &topology.edge_faces[&edge];
+			let point = if self.is_sharp(&topology, edge) {
+				(a + b) * 0.5
+			} else {
+				(a + b + face_points[faces[0]] + face_points[faces[1]]) * 0.25
+			};
+			edge_points.insert(edge, positions.len() as u32);
+			positions.push(point);
+		}
+		let face_offset = positions.len() as u32;
+		positions.extend_from_slice(&face_points);
+
+		for p in 0..self.positions.len() {
+			let v = self.positions[p];
+			positions[p] = match self.vertex_rule(&topology, p as u32) {
+				VertexRule::Corner => v,
+				VertexRule::Crease(a, b) => v * 0.75 + (self.positions[a as usize] + self.positions[b as usize]) * 0.125,
+				VertexRule::Smooth => {
+					let edges = &topology.vertex_edges[p];
+					let n = edges.len() as f32;
+					let mut faces: Vec<usize> = edges.iter().flat_map(|edge| topology.edge_faces[edge].clone()).collect();
+					faces.sort();
+					faces.dedup();
+					let face_average = faces.iter().fold(vec3(0.0, 0.0, 0.0), |sum, &f| sum + face_points[f]) / faces.len() as f32;
+					let edge_average = edges.iter().fold(vec3(0.0, 0.0, 0.0), |sum, &edge| {
+						sum + (self.positions[edge.0 as usize] + self.positions[edge.1 as usize]) * 0.5
+					}) / n;
+					(face_average + edge_average * 2.0 + v * (n - 3.0)) / n
+				},
+			};
+		}
+
+		let mut table = AttributeTable::new();
+		let mut faces = vec![];
+		for (f, face) in self.faces.iter().enumerate() {
+			let n = face.len();
+			let face_uv = face.iter().fold([0.0, 0.0], |sum, &attribute| {
+				let uv = self.uv(attribute);
+				[sum[0] + uv[0] / n as f32, sum[1] + uv[1] / n as f32]
+			});
+			let face_attribute = table.get(face_offset + f as u32, face_uv);
+			for i in 0..n {
+				let (previous, current, next) = (face[(i + n - 1) % n], face[i], face[(i + 1) % n]);
+				let next_edge = edge_points[&edge_key(self.position(current), self.position(next))];
+				let previous_edge = edge_points[&edge_key(self.position(previous), self.position(current))];
+				faces.push(vec![
+					table.get(self.position(current), self.uv(current)),
+					table.get(next_edge, midpoint(self.uv(current), self.uv(next))),
+					face_attribute,
+					table.get(previous_edge, midpoint(self.uv(previous), self.uv(current))),
+				]);
+			}
+		}
+
+		ControlMesh {
+			positions,
+			attributes: table.attributes,
+			faces,
+			creases: self.split_creases(&edge_points),
+		}
+	}
+
+	// ? Every triangle becomes 4. See: Loop, Smooth Subdivision Surfaces Based on Triangles, 1987,
+	// ? and Hoppe et al., Piecewise Smooth Surface Reconstruction, 1994 for the creases
+	fn loop_subdivision(&self) -> ControlMesh {
+		let topology = self.topology();
+
+		let mut positions = self.positions.clone();
+		let mut edge_points = HashMap::new();
+		for edge in ControlMesh::sorted_edges(&topology) {
+			let (a, b) = (self.positions[edge.0 as usize], self.positions[edge.1 as usize]);
+			let point = if self.is_sharp(&topology, edge) {
+				(a + b) * 0.5
+			} else {
+				let opposite = topology.edge_faces[&edge].iter().fold(vec3(0.0, 0.0, 0.0), |sum, &f| {
+					let corner = self.faces[f].iter()
+						.map(|&attribute| self.position(attribute))
+						.find(|&p| p != edge.0 && p != edge.1)
+						.unwrap_or(edge.0);
+					sum + self.positions[corner as usize]
+				});
+				(a + b) * 0.375 + opposite * 0.125
+			};
+			edge_points.insert(edge, positions.len() as u32);
+			positions.push(point);
+		}
+
+		for p in 0..self.positions.len() {
+			let v = self.positions[p];
+			positions[p] = match self.vertex_rule(&topology, p as u32) {
+				VertexRule::Corner => v,
+				VertexRule::Crease(a, b) => v * 0.75 + (self.positions[a as usize] + self.positions[b as usize]) * 0.125,
+				VertexRule::Smooth => {
+					let edges = &topology.vertex_edges[p];
+					let n = edges.len() as f32;
+					let beta = (0.625 - (0.375 + 0.25 * (2.0 * PI / n).cos()).powi(2)) / n;
+					let neighbours = edges.iter().fold(vec3(0.0, 0.0, 0.0), |sum, &edge| {
+						sum + self.positions[other_end(edge, p as u32) as usize]
+					});
+					v * (1.0 - n * beta) + neighbours * beta
+				},
+			};
+		}
+
+		let mut table = AttributeTable::new();
+		let mut faces = vec![];
+		for face in &self.faces {
+			let corners: Vec<u32> = face.iter().map(|&attribute| table.get(self.position(attribute), self.uv(attribute))).collect();
+			let middles: Vec<u32> = (0..3).map(|i| {
+				let (a, b) = (face[i], face[(i + 1) % 3]);
+				table.get(edge_points[&edge_key(self.position(a), self.position(b))], midpoint(self.uv(a), self.uv(b)))
+			}).collect();
+			faces.push(vec![corners[0], middles[0], middles[2]]);
+			faces.push(vec![corners[1], middles[1], middles[0]]);
+			faces.push(vec![corners[2], middles[2], middles[1]]);
+			faces.push(vec![middles[0], middles[1], middles[2]]);
+		}
+
+		ControlMesh {
+			positions,
+			attributes: table.attributes,
+			faces,
+			creases: self.split_creases(&edge_points),
+		}
+	}
+
+	// ? Faces are fanned into triangles
+	fn corners(&self) -> (Vec<[f32; 3]>, Vec<[f32; 2]>) {
+		let mut positions = vec![];
+		let mut uvs = vec![];
+		for face in &self.faces {
+			for i in 1..face.len() - 1 {
+				for &attribute in &[face[0], face[i], face[i + 1]] {
+					positions.push(self.point(attribute).into());
+					uvs.push(self.uv(attribute));
+				}
+			}
+		}
+		(positions, uvs)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scene::primitives::Primitive;
+
+	fn options(scheme: SubdivisionScheme, levels: u32) -> SubdivisionOptions {
+		SubdivisionOptions { scheme, levels, crease_angle: None }
+	}
+
+	#[test]
+	fn loop_step_quadruples_the_triangles() {
+		let vertices = Primitive::Icosphere { radius: 1.0, subdivisions: 0 }.object().vertices;
+		let (positions, uvs) = subdivide(&vertices, &options(SubdivisionScheme::Loop, 1));
+		assert_eq!(positions.len(), 4 * vertices.len());
+		assert_eq!(uvs.len(), positions.len());
+		// ? Loop only averages points, so the sphere stays inside its control mesh
+		for &position in &positions {
+			assert!(Vector3::from(position).magnitude() <= 1.0 + 1e-5);
+		}
+	}
+
+	#[test]
+	fn catmull_clark_step_quadruples_the_triangles() {
+		let vertices = Primitive::Cube { size: 2.0, segments: 1 }.object().vertices;
+		let (positions, _) = subdivide(&vertices, &options(SubdivisionScheme::CatmullClark, 1));
+		assert_eq!(positions.len(), 4 * vertices.len());
+		let (positions, _) = subdivide(&vertices, &options(SubdivisionScheme::CatmullClark, 2));
+		assert_eq!(positions.len(), 16 * vertices.len());
+		// ? Loop gives as many triangles, the cube has to be seen as quads for Auto to pick Catmull-Clark
+		let (auto_positions, _) = subdivide(&vertices, &options(SubdivisionScheme::Auto, 2));
+		assert_eq!(auto_positions, positions);
+	}
+
+	#[test]
+	fn quads_are_rebuilt_whatever_the_diagonal() {
+		let vertex = |x: f32, y: f32| Vertex::new([x, y, 0.0], [1.0; 3], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0], [x, y]);
+		let (a, b, c, d) = (vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0));
+		// ? A fan as the OBJ importer cuts polygons, Mesh::quad, and the other diagonal
+		for quad in &[[a, b, c, a, c, d], [a, b, c, c, d, a], [a, b, d, b, c, d]] {
+			let mesh = ControlMesh::from_vertices(quad, true, None);
+			assert_eq!(mesh.faces.len(), 1);
+			assert_eq!(mesh.faces[0].len(), 4);
+		}
+		let cube = Primitive::Cube { size: 2.0, segments: 1 }.object().vertices;
+		let mesh = ControlMesh::from_vertices(&cube, true, None);
+		assert_eq!(mesh.faces.len(), 6);
+		assert!(mesh.is_quad_dominant());
+	}
+
+	#[test]
+	fn zero_levels_keeps_the_triangles() {
+		let vertices = Primitive::Cube { size: 2.0, segments: 1 }.object().vertices;
+		let (positions, _) = subdivide(&vertices, &options(SubdivisionScheme::Loop, 0));
+		assert_eq!(positions.len(), vertices.len());
+	}
+
+	#[test]
+	fn from_spec() {
+		assert_eq!(SubdivisionOptions::from_spec("catmull-clark:1:40"), Ok(SubdivisionOptions {
+			scheme: SubdivisionScheme::CatmullClark,
+			levels: 1,
+			crease_angle: Some(Deg(40.0)),
+		}));
+		assert_eq!(SubdivisionOptions::from_spec("loop").unwrap().levels, 1);
+		assert!(SubdivisionOptions::from_spec("loop:5").is_err());
+		assert!(SubdivisionOptions::from_spec("butterfly").is_err());
+	}
+}
@@ -0,0 +1,402 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use cgmath::Vector3;
+use cgmath::InnerSpace;
+
+use common::*;
+use scene::normals;
+
+/*
+.##..##...####...##......######..#####....####...######..######...####...##..##.
+.##..##..##..##..##........##....##..##..##..##....##......##....##..##..###.##.
+.##..##..######..##........##....##..##..######....##......##....##..##..##.###.
+..####...##..##..##........##....##..##..##..##....##......##....##..##..##..##.
+...##....##..##..######..######..#####...##..##....##....######...####...##..##.
+................................................................................
+*/
+
+// ? Positions closer than this fraction of the bounding box's diagonal are considered the same
+pub const WELD_TOLERANCE: f32 = 1e-5;
+// ? How far a normal's length may be from 1
+const NORMAL_LENGTH_TOLERANCE: f32 = 1e-3;
+
+// ? Problems found in a triangle list, see `validate`
+#[derive(Clone, Debug, Default)]
+pub struct MeshReport {
+	pub triangles: usize,
+	pub positions: usize,
+	// ? Dropped when the object was created, they can't be recovered
+	pub out_of_range_indices: usize,
+	pub non_finite_positions: usize,
+	pub degenerate_triangles: usize,
+	// ? Not unit length, not finite, or facing away from their triangle
+	pub invalid_normals: usize,
+	// ? Positions within the weld tolerance of another one, they leave cracks
+	pub unwelded_positions: usize,
+	pub boundary_edges: usize,
+	// ? Shared by more than two triangles
+	pub non_manifold_edges: usize,
+	// ? Shared by two triangles going through it in the same direction
+	pub inconsistent_edges: usize,
+	// ? Closed and consistently wound, but the enclosed volume is negative
+	pub inverted_winding: bool,
+}
+impl MeshReport {
+	pub fn is_manifold(&self) -> bool {
+		self.non_manifold_edges == 0
+	}
+
+	pub fn is_watertight(&self) -> bool {
+		self.is_manifold() && self.boundary_edges == 0
+	}
+
+	pub fn is_valid(&self) -> bool {
+		self.out_of_range_indices == 0
+			&& self.non_finite_positions == 0
+			&& self.degenerate_triangles == 0
+			&& self.invalid_normals == 0
+			&& self.inconsistent_edges == 0
+			&& !self.inverted_winding
+	}
+}
+impl fmt::Display for MeshReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{} triangles, {} positions, {}, {}{}",
+			self.triangles,
+			self.positions,
+			if self.is_manifold() { "manifold" } else { "non-manifold" },
+			if self.is_watertight() { "watertight" } else { "open" },
+			if self.inverted_winding { ", inside out" } else { "" }
+		)?;
+		writeln!(f, "  out of range indices: {}", self.out_of_range_indices)?;
+		writeln!(f, "  non-finite positions: {}", self.non_finite_positions)?;
+		writeln!(f, "  degenerate triangles: {}", self.degenerate_triangles)?;
+		writeln!(f, "  invalid normals: {}", self.invalid_normals)?;
+		writeln!(f, "  unwelded positions: {}", self.unwelded_positions)?;
+		writeln!(f, "  boundary edges: {}", self.boundary_edges)?;
+		writeln!(f, "  non-manifold edges: {}", self.non_manifold_edges)?;
+		write!(f, "  inconsistent edges: {}", self.inconsistent_edges)
+	}
+}
+
+// ? What `Object3D::repair` changed
+#[derive(Clone, Debug, Default)]
+pub struct RepairSummary {
+	// ? Out of range indices whose triangles were dropped when the object was created
+	pub dropped_indices: usize,
+	pub removed_triangles: usize,
+	pub welded_positions: usize,
+	pub flipped_triangles: usize,
+	pub recomputed_normals: usize,
+}
+impl fmt::Display for RepairSummary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "dropped {} out of range indices, removed {} triangles, welded {} positions, flipped {} triangles, recomputed {} normals",
+			self.dropped_indices, self.removed_triangles, self.welded_positions, self.flipped_triangles, self.recomputed_normals)
+	}
+}
+
+pub fn validate(vertices: &[Vertex]) -> MeshReport {
+	let mut report = MeshReport::default();
+	report.triangles = vertices.len() / 3;
+	let points: Vec<[f32; 3]> = vertices.iter().map(Vertex::position).collect();
+	let (positions, indices) = normals::weld(&points);
+	report.positions = positions.len();
+	report.non_finite_positions = positions.iter().filter(|p| !is_finite(**p)).count();
+	report.unwelded_positions = {
+		let representatives = weld_groups(&positions, weld_tolerance(vertices));
+		representatives.iter().enumerate().filter(|&(p, &representative)| representative as usize != p).count()
+	};
+
+	let degenerate: Vec<bool> = (0..report.triangles).map(|t| is_degenerate(vertices, &indices, t)).collect();
+	report.degenerate_triangles = degenerate.iter().filter(|&&degenerate| degenerate).count();
+	report.invalid_normals = invalid_normal_corners(vertices).iter().filter(|&&invalid| invalid).count();
+
+	let edges = edge_triangles(&indices, &degenerate);
+	for triangles in edges.values() {
+		match triangles.len() {
+			1 => report.boundary_edges += 1,
+			2 => if triangles[0].1 == triangles[1].1 {
+				report.inconsistent_edges += 1;
+			},
+			_ => report.non_manifold_edges += 1,
+		}
+	}
+	if report.is_watertight() && report.inconsistent_edges == 0 {
+		let triangles: Vec<usize> = (0..report.triangles).filter(|&t| !degenerate[t]).collect();
+		report.inverted_winding = signed_volume(vertices, &triangles) < 0.0;
+	}
+	report
+}
+
+// ? Drops triangles with repeated or non-finite positions or without area, returns how many
+pub fn remove_degenerate_triangles(vertices: &mut Vec<Vertex>) -> usize {
+	let points: Vec<[f32; 3]> = vertices.iter().map(Vertex::position).collect();
+	let (_, indices) = normals::weld(&points);
+	let triangle_count = vertices.len() / 3;
+	let kept: Vec<Vertex> = (0..triangle_count)
+		.filter(|&t| !is_degenerate(vertices, &indices, t))
+		.flat_map(|t| vertices[3 * t..3 * t + 3].to_vec())
+		.collect();
+	let removed = triangle_count - kept.len() / 3;
+	*vertices = kept;
+	removed
+}
+
+// ? Moves positions within `tolerance` of each other onto the same one, returns how many moved
+pub fn weld(vertices: &mut Vec<Vertex>, tolerance: f32) -> usize {
+	let points: Vec<[f32; 3]> = vertices.iter().map(Vertex::position).collect();
+	let (positions, indices) = normals::weld(&points);
+	let representatives = weld_groups(&positions, tolerance);
+	for (vertex, &index) in vertices.iter_mut().zip(&indices) {
+		let representative = representatives[index as usize] as usize;
+		if representative != index as usize {
+			vertex.set_position(positions[representative]);
+		}
+	}
+	representatives.iter().enumerate().filter(|&(p, &representative)| representative as usize != p).count()
+}
+
+// ? Orients the triangles of every connected piece like their neighbours, keeping the winding most of them had,
+// ? then turns closed pieces enclosing a negative volume inside out. Returns the number of flipped triangles.
+pub fn fix_winding(vertices: &mut Vec<Vertex>) -> usize {
+	let points: Vec<[f32; 3]> = vertices.iter().map(Vertex::position).collect();
+	let (_, indices) = normals::weld(&points);
+	let triangle_count = vertices.len() / 3;
+	let degenerate: Vec<bool> = (0..triangle_count).map(|t| is_degenerate(vertices, &indices, t)).collect();
+	let edges = edge_triangles(&indices, &degenerate);
+
+	// ? Neighbours across manifold edges, with whether they go through the edge in the same direction
+	let mut neighbours: Vec<Vec<(usize, bool)>> = vec![vec![]; triangle_count];
+	for triangles in edges.values() {
+		if triangles.len() == 2 {
+			let ((a, a_forward), (b, b_forward)) = (triangles[0], triangles[1]);
+			neighbours[a].push((b, a_forward == b_forward));
+			neighbours[b].push((a, a_forward == b_forward));
+		}
+	}
+
+	let mut flipped: Vec<Option<bool>> = vec![None; triangle_count];
+	let mut flip_count = 0;
+	for seed in 0..triangle_count {
+		if flipped[seed].is_some() || degenerate[seed] {
+			continue;
+		}
+		flipped[seed] = Some(false);
+		let mut component = vec![seed];
+		let mut queue = VecDeque::new();
+		queue.push_back(seed);
+		while let Some(t) = queue.pop_front() {
+			for &(neighbour, same_direction) in &neighbours[t] {
+				if flipped[neighbour].is_none() {
+					flipped[neighbour] = Some(flipped[t].unwrap() ^ same_direction);
+					component.push(neighbour);
+					queue.push_back(neighbour);
+				}
+			}
+		}
+
+		// ? The seed's winding is arbitrary, keep the one most triangles already have
+		let mut flip_all = 2 * component.iter().filter(|&&t| flipped[t] == Some(true)).count() > component.len();
+		let closed = component.iter().all(|&t| {
+			(0..3).all(|k| edges[&edge_key(indices[3 * t + k], indices[3 * t + (k + 1) % 3])].len() == 2)
+		});
+		if closed {
+			let volume = component.iter().fold(0.0, |volume, &t| {
+				let sign = if flipped[t] == Some(true) { -1.0 } else { 1.0 };
+				volume + sign * signed_volume(vertices, &[t])
+			});
+			flip_all = volume < 0.0;
+		}
+		for &t in &component {
+			let flip = flipped[t] != Some(flip_all);
+			if flip {
+				vertices.swap(3 * t + 1, 3 * t + 2);
+				flip_count += 1;
+			}
+		}
+	}
+	flip_count
+}
+
+// ? Corners whose normal is not unit length, not finite, or facing away from their triangle
+pub fn invalid_normal_corners(vertices: &[Vertex]) -> Vec<bool> {
+	let mut invalid = vec![false; vertices.len()];
+	for t in 0..vertices.len() / 3 {
+		let p: Vec<Vector3<f32>> = (0..3).map(|k| Vector3::from(vertices[3 * t + k].position())).collect();
+		let (face_normal, _) = normals::triangle_normal(p[0], p[1], p[2]);
+		for k in 0..3 {
+			let normal = Vector3::from(vertices[3 * t + k].normal());
+			invalid[3 * t + k] = !is_finite(normal.into())
+				|| (normal.magnitude() - 1.0).abs() > NORMAL_LENGTH_TOLERANCE
+				|| normal.dot(face_normal) < 0.0;
+		}
+	}
+	invalid
+}
+
+pub fn weld_tolerance(vertices: &[Vertex]) -> f32 {
+	let mut min = [::std::f32::INFINITY; 3];
+	let mut max = [::std::f32::NEG_INFINITY; 3];
+	for position in vertices.iter().map(Vertex::position).filter(|p| is_finite(*p)) {
+		for axis in 0..3 {
+			min[axis] = min[axis].min(position[axis]);
+			max[axis] = max[axis].max(position[axis]);
+		}
+	}
+	if min[0] > max[0] {
+		return 0.0;
+	}
+	let diagonal = Vector3::from(max) - Vector3::from(min);
+	diagonal.magnitude() * WELD_TOLERANCE
+}
+
+fn is_finite(position: [f32; 3]) -> bool {
+	position.iter().all(|value| value.is_finite())
+}
+
+fn is_degenerate(vertices: &[Vertex], indices: &[u32], t: usize) -> bool {
+	let (a, b, c) = (indices[3 * t], indices[3 * t + 1], indices[3 * t + 2]);
+	if a == b || b == c || c == a {
+		return true;
+	}
+	let p: Vec<[f32; 3]> = (0..3).map(|k| vertices[3 * t + k].position()).collect();
+	if !p.iter().all(|&p| is_finite(p)) {
+		return true;
+	}
+	let (_, area) = normals::triangle_normal(Vector3::from(p[0]), Vector3::from(p[1]), Vector3::from(p[2]));
+	area == 0.0
+}
+
+type EdgeKey = (u32, u32);
+
+fn edge_key(a: u32, b: u32) -> EdgeKey {
+	if a < b { (a, b) } else { (b, a) }
+}
+
+// ? Triangles around every edge of the non-degenerate triangles, with whether they go from its smallest position to its largest
+fn edge_triangles(indices: &[u32], degenerate: &[bool]) -> HashMap<EdgeKey, Vec<(usize, bool)>> {
+	let mut edges: HashMap<EdgeKey, Vec<(usize, bool)>> = HashMap::new();
+	for t in 0..degenerate.len() {
+		if degenerate[t] {
+			continue;
+		}
+		for k in 0..3 {
+			let (a, b) = (indices[3 * t + k], indices[3 * t + (k + 1) % 3]);
+			edges.entry(edge_key(a, b)).or_insert_with(Vec::new).push((t, a < b));
+		}
+	}
+	edges
+}
+
+// ? Volume enclosed by the triangles with the origin, positive when they face outwards
+fn signed_volume(vertices: &[Vertex], triangles: &[usize]) -> f32 {
+	triangles.iter().fold(0.0, |volume, &t| {
+		let p: Vec<Vector3<f32>> = (0..3).map(|k| Vector3::from(vertices[3 * t + k].position())).collect();
+		volume + p[0].dot(p[1].cross(p[2])) / 6.0
+	})
+}
+
+// ? For every position, the first position within `tolerance` of it, found through a grid of `tolerance` sized cells
+fn weld_groups(positions: &[[f32; 3]], tolerance: f32) -> Vec<u32> {
+	let mut representatives = Vec::with_capacity(positions.len());
+	if tolerance <= 0.0 {
+		return (0..positions.len() as u32).collect();
+	}
+	let cell = |value: f32| (value / tolerance).floor() as i64;
+	let mut grid: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+	for (p, position) in positions.iter().enumerate() {
+		if !is_finite(*position) {
+			representatives.push(p as u32);
+			continue;
+		}
+		let (x, y, z) = (cell(position[0]), cell(position[1]), cell(position[2]));
+		let mut found = None;
+		'search: for dx in -1..2 {
+			for dy in -1..2 {
+				for dz in -1..2 {
+					if let Some(candidates) = grid.get(&(x + dx, y + dy, z + dz)) {
+						for &candidate in candidates {
+							let distance = (Vector3::from(positions[candidate as usize]) - Vector3::from(*position)).magnitude();
+							if distance <= tolerance {
+								found = Some(candidate);
+								break 'search;
+							}
+						}
+					}
+				}
+			}
+		}
+		match found {
+			Some(representative) => representatives.push(representative),
+			None => {
+				grid.entry((x, y, z)).or_insert_with(Vec::new).push(p as u32);
+				representatives.push(p as u32);
+			},
+		}
+	}
+	representatives
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scene::object::Object3D;
+
+	#[test]
+	fn cube_is_valid_and_watertight() {
+		let report = validate(&Object3D::new_cube().vertices);
+		assert_eq!(report.triangles, 12);
+		assert_eq!(report.positions, 8);
+		assert!(report.is_watertight());
+		assert!(report.is_valid());
+	}
+
+	#[test]
+	fn weld_and_degenerate_removal_close_the_seams() {
+		let mut vertices = Object3D::new_cube().vertices;
+		// ? Every triangle gets its own copy of the corners, a little off, as if exported with split seams
+		for (corner, vertex) in vertices.iter_mut().enumerate() {
+			let offset = 1e-6 * (corner / 3) as f32;
+			let p = vertex.position();
+			vertex.set_position([p[0] + offset, p[1] - offset, p[2] + offset]);
+		}
+		let first = vertices[0];
+		vertices.extend_from_slice(&[first, first, first]);
+
+		let report = validate(&vertices);
+		assert!(!report.is_watertight());
+		assert!(report.unwelded_positions > 0);
+		assert_eq!(report.degenerate_triangles, 1);
+
+		let tolerance = weld_tolerance(&vertices);
+		assert!(weld(&mut vertices, tolerance) > 0);
+		assert_eq!(remove_degenerate_triangles(&mut vertices), 1);
+		let report = validate(&vertices);
+		assert_eq!(report.positions, 8);
+		assert!(report.is_watertight());
+		assert!(report.is_valid());
+	}
+
+	#[test]
+	fn fix_winding_flips_the_odd_triangle() {
+		let mut vertices = Object3D::new_cube().vertices;
+		vertices.swap(1, 2);
+		let report = validate(&vertices);
+		assert_eq!(report.inconsistent_edges, 3);
+		assert_eq!(fix_winding(&mut vertices), 1);
+		assert!(validate(&vertices).is_valid());
+	}
+
+	#[test]
+	fn fix_winding_turns_inside_out_meshes() {
+		let mut vertices = Object3D::new_cube().vertices;
+		for triangle in vertices.chunks_mut(3) {
+			triangle.swap(1, 2);
+		}
+		assert!(validate(&vertices).inverted_winding);
+		assert_eq!(fix_winding(&mut vertices), 12);
+		assert!(!validate(&vertices).inverted_winding);
+	}
+}
@@ -0,0 +1,43 @@
+// ? Clustered light lists of the forward path, see renderer::cluster
+#define CLUSTER_TILES_X 16
+#define CLUSTER_TILES_Y 9
+#define CLUSTER_SLICES 24
+#define CLUSTER_DATA_WIDTH 1024
+#define LIGHT_FIRST_ROW 0
+#define GRID_FIRST_ROW 24
+#define INDEX_FIRST_ROW 28
+
+// ? Bands of rows holding the light data (six texels per light), the offset and count of each cluster,
+// ? and the light indices (four per texel)
+uniform sampler2D u_ClusterData;
+
+vec4 cluster_data(int first_row, int texel) {
+	return texelFetch(u_ClusterData, ivec2(texel % CLUSTER_DATA_WIDTH, first_row + texel / CLUSTER_DATA_WIDTH), 0);
+}
+
+int cluster_index(vec2 frag_coord, float view_depth) {
+	ivec2 tile = ivec2(frag_coord / u_ClusterScale.xy);
+	tile = clamp(tile, ivec2(0), ivec2(CLUSTER_TILES_X - 1, CLUSTER_TILES_Y - 1));
+	int slice = int(floor(log(max(view_depth, 1e-4)) * u_ClusterScale.z + u_ClusterScale.w));
+	slice = clamp(slice, 0, CLUSTER_SLICES - 1);
+	return (slice * CLUSTER_TILES_Y + tile.y) * CLUSTER_TILES_X + tile.x;
+}
+
+// ? Offset into the index list and light count of the fragment's cluster
+ivec2 cluster_light_list(vec2 frag_coord, float view_depth) {
+	vec4 cluster = cluster_data(GRID_FIRST_ROW, cluster_index(frag_coord, view_depth));
+	return ivec2(cluster.xy);
+}
+
+Light cluster_light(int list_index) {
+	int light_index = int(cluster_data(INDEX_FIRST_ROW, list_index / 4)[list_index % 4]);
+	int first_texel = 6 * light_index;
+	Light light;
+	light.pos = cluster_data(LIGHT_FIRST_ROW, first_texel);
+	light.color = cluster_data(LIGHT_FIRST_ROW, first_texel + 1);
+	light.direction = cluster_data(LIGHT_FIRST_ROW, first_texel + 2);
+	light.attenuation = cluster_data(LIGHT_FIRST_ROW, first_texel + 3);
+	light.cone = cluster_data(LIGHT_FIRST_ROW, first_texel + 4);
+	light.shadow = cluster_data(LIGHT_FIRST_ROW, first_texel + 5);
+	return light;
+}
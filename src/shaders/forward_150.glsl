@@ -0,0 +1,62 @@
+// ? Forward shading of a fragment, shared by the opaque, sorted and order-independent transparent passes.
+// ? Appended to frame_150.glsl, lighting_150.glsl, material_150.glsl and clusters_150.glsl (see renderer::shader_source)
+
+varying vec4 f_vertexpos; // view space
+varying vec4 f_worldpos;
+varying vec3 f_color;
+varying vec4 f_normal;
+varying vec4 f_tangent; // w holds the bitangent sign
+varying vec2 f_uv;
+
+// ? Linear color and coverage, tone mapping and sRGB encoding happen in tone_map_150.glslf
+vec4 forward_shading() {
+	Surface surface = sample_surface(f_normal.xyz, f_tangent, f_uv);
+	if (surface.coverage < dissolve_cutoff) {
+		discard;
+	}
+
+	vec3 position = f_worldpos.xyz;
+	float view_depth = -f_vertexpos.z;
+	vec3 N = surface.N;
+	vec3 O = normalize(u_EyePosition.xyz - position);
+	vec3 geometric_normal = normalize(f_normal.xyz);
+	ivec2 light_list = cluster_light_list(gl_FragCoord.xy, view_depth);
+
+	// ? Metallic-roughness path, the MTL illumination model is ignored
+	if (u_Pbr == 1) {
+		vec3 radiance_sum = (surface.kd * ambient_light() + ibl_pbr(N, O, surface.kd, surface.metallic, surface.roughness)) * surface.occlusion;
+		for (int i = 0; i < light_list.y; ++i) {
+			Light light = cluster_light(light_list.x + i);
+			vec3 L = light_vector(light, position);
+			float attenuationFactor = light_attenuation(light, L, position) * shadow_factor(light, position, view_depth, geometric_normal);
+			radiance_sum += cook_torrance(light, L, N, O, surface.kd, surface.metallic, surface.roughness) * attenuationFactor;
+		}
+		radiance_sum += surface.emissive;
+		return vec4(radiance_sum, u_Dissolve * surface.coverage);
+	}
+
+	// ? illum 0: color = Kd
+	if (u_IlluminationModel == 0) {
+		return vec4(surface.kd, u_Dissolve);
+	}
+
+	bool use_specular = u_IlluminationModel >= 2;
+	bool use_reflection = u_IlluminationModel >= 3 && u_IlluminationModel <= 9;
+	bool use_fresnel = u_IlluminationModel == 5 || u_IlluminationModel == 7;
+	vec3 ks = use_specular ? surface.ks : vec3(0.0);
+
+	vec3 intensity = surface.ka * ambient_light() + surface.kd * ibl_irradiance(N);
+	for (int i = 0; i < light_list.y; ++i) {
+		Light light = cluster_light(light_list.x + i);
+		vec3 L = light_vector(light, position);
+		float attenuationFactor = light_attenuation(light, L, position) * shadow_factor(light, position, view_depth, geometric_normal);
+		intensity += blinn_phong(light, L, N, O, surface.kd, ks, u_Shininess, use_fresnel) * attenuationFactor;
+	}
+
+	if (use_reflection) {
+		vec3 fr = use_fresnel ? fresnel(max(dot(N, O), 0.0), ks) : ks;
+		intensity += fr * environment_radiance(reflect(-O, N));
+	}
+
+	return vec4(intensity, u_Dissolve * surface.coverage);
+}
@@ -0,0 +1,49 @@
+// ? Per frame inputs shared by the scene shaders, see ForwardLocals
+uniform Locals {
+	vec4 u_EyePosition;
+	vec4 u_AmbientColor;
+	vec4 u_Ibl; // image-based lighting intensity, 0 without an environment
+	vec4 u_ClusterScale; // tile width and height in pixels, depth slice scale and bias
+	int u_NumLights; // active number of lights
+};
+
+uniform sampler2D u_AmbientOcclusion; // white when SSAO is disabled, see renderer::ssao
+uniform samplerCube u_EnvironmentMap; // the clear color when the scene has no environment, see renderer::skybox
+uniform samplerCube u_PrefilteredMap; // one mip level per roughness step, then the irradiance, see renderer::ibl
+uniform sampler2D u_BrdfLut; // split sum scale and bias, indexed by (n.v, roughness)
+
+// ? Keep in sync with renderer::ibl::PREFILTERED_MIP_LEVELS
+const int PREFILTERED_MIP_LEVELS = 5;
+const float IRRADIANCE_MIP_LEVEL = float(PREFILTERED_MIP_LEVELS);
+
+float ambient_occlusion() {
+	return texelFetch(u_AmbientOcclusion, ivec2(gl_FragCoord.xy), 0).r;
+}
+
+// ? Ambient light reaching the fragment, screen-space ambient occlusion included
+vec3 ambient_light() {
+	return u_AmbientColor.rgb * ambient_occlusion();
+}
+
+// ? Diffuse light from the surroundings for a world space normal
+vec3 ibl_irradiance(vec3 N) {
+	return textureLod(u_PrefilteredMap, N, IRRADIANCE_MIP_LEVEL).rgb * u_Ibl.x * ambient_occlusion();
+}
+
+// ? Split sum approximation of the environment lighting a metallic/roughness surface, see: https://learnopengl.com/PBR/IBL/Specular-IBL
+vec3 ibl_pbr(vec3 N, vec3 O, vec3 base_color, float metallic, float roughness) {
+	float n_dot_v = max(dot(N, O), 0.0);
+	vec3 f0 = mix(vec3(0.04), base_color, metallic);
+	vec3 F = f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - n_dot_v, 5.0);
+	vec3 kd = (1.0 - F) * (1.0 - metallic);
+	vec3 diffuse = kd * base_color * textureLod(u_PrefilteredMap, N, IRRADIANCE_MIP_LEVEL).rgb;
+	vec3 prefiltered = textureLod(u_PrefilteredMap, reflect(-O, N), roughness * float(PREFILTERED_MIP_LEVELS - 1)).rgb;
+	vec2 brdf = texture(u_BrdfLut, vec2(n_dot_v, roughness)).rg;
+	vec3 specular = prefiltered * (F * brdf.x + brdf.y);
+	return (diffuse + specular) * u_Ibl.x * ambient_occlusion();
+}
+
+// ? Light coming from the surroundings along a world space direction
+vec3 environment_radiance(vec3 direction) {
+	return texture(u_EnvironmentMap, direction).rgb;
+}
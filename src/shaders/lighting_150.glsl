@@ -0,0 +1,172 @@
+// ? Light and shadow evaluation shared by the forward and deferred fragment shaders
+#define MAX_SHADOW_MAPS 8
+#define MAX_POINT_SHADOW_CASTERS 4
+#define PI 3.14159265359
+
+#define LIGHT_DIRECTIONAL 0
+#define LIGHT_POINT 1
+#define LIGHT_SPOT 2
+
+
+struct Light {
+	vec4 pos; // world position, w: kind
+	vec4 color; // w: intensity
+	vec4 direction;
+	vec4 attenuation; // constant, linear, quadratic, range (0 = use the coefficients)
+	vec4 cone; // cosines of the inner and outer angles
+	vec4 shadow; // first shadow map layer, layer count (0 = no shadows), bias, normal bias
+};
+
+struct ShadowMap {
+	mat4 light_vp;
+	vec4 split; // far view distance covered, texel world size, 1 if the size grows with the distance to the light
+};
+
+uniform sampler2DArrayShadow u_ShadowMaps;
+uniform sampler2DArrayShadow u_PointShadowMaps; // cube map faces, 6 layers per light
+
+uniform b_shadow_maps {
+	ShadowMap u_shadow_maps[MAX_SHADOW_MAPS];
+};
+
+uniform b_point_shadow_maps {
+	ShadowMap u_point_shadow_maps[6 * MAX_POINT_SHADOW_CASTERS];
+};
+
+// ? Schlick's approximation, used as Fr() by the fresnel models (5 and 7)
+vec3 fresnel(float cos_theta, vec3 f0) {
+	return f0 + (vec3(1.0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// ? Cook-Torrance terms, see: https://learnopengl.com/PBR/Lighting
+float distribution_ggx(float n_dot_h, float roughness) {
+	float a = roughness * roughness;
+	float a2 = a * a;
+	float denominator = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+	return a2 / (PI * denominator * denominator);
+}
+
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+	float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+	return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+	return geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+}
+
+// ? Direction from the fragment to the light
+vec3 light_vector(Light light, vec3 position) {
+	if (int(light.pos.w) == LIGHT_DIRECTIONAL) {
+		return -normalize(light.direction.xyz);
+	}
+	return normalize(light.pos.xyz - position);
+}
+
+// ? Distance falloff, spot cone and intensity
+float light_attenuation(Light light, vec3 L, vec3 position) {
+	int kind = int(light.pos.w);
+	if (kind == LIGHT_DIRECTIONAL) {
+		return light.color.w;
+	}
+	float d = length(light.pos.xyz - position);
+	float falloff;
+	if (light.attenuation.w > 0.0) {
+		float window = clamp(1.0 - pow(d / light.attenuation.w, 4.0), 0.0, 1.0);
+		falloff = window * window / (d * d + 1.0);
+	} else {
+		falloff = 1.0 / (light.attenuation.x + light.attenuation.y * d + light.attenuation.z * d * d);
+	}
+	if (kind == LIGHT_SPOT) {
+		float cos_angle = dot(-L, normalize(light.direction.xyz));
+		falloff *= smoothstep(light.cone.y, light.cone.x, cos_angle);
+	}
+	return falloff * light.color.w;
+}
+
+// ? 5x5 PCF over the comparison sampler, the fragment is first pushed along its normal by `normal_bias` texels
+float filtered_shadow(sampler2DArrayShadow shadow_maps, ShadowMap shadow_map, int layer, Light light, vec3 position, vec3 geometric_normal) {
+	float texel_size = shadow_map.split.y;
+	if (shadow_map.split.z > 0.0) {
+		texel_size *= length(light.pos.xyz - position);
+	}
+	vec3 offset_position = position + geometric_normal * light.shadow.w * texel_size;
+	vec4 light_clip = shadow_map.light_vp * vec4(offset_position, 1.0);
+	vec3 coords = light_clip.xyz / light_clip.w * 0.5 + 0.5;
+	if (coords.z > 1.0) {
+		return 1.0;
+	}
+
+	vec2 texel_uv = 1.0 / vec2(textureSize(shadow_maps, 0).xy);
+	float lit = 0.0;
+	for (int x = -2; x <= 2; ++x) {
+		for (int y = -2; y <= 2; ++y) {
+			lit += texture(shadow_maps, vec4(coords.xy + vec2(x, y) * texel_uv, float(layer), coords.z - light.shadow.z));
+		}
+	}
+	return lit / 25.0;
+}
+
+// ? Fraction of the light reaching the fragment
+float shadow_factor(Light light, vec3 position, float view_depth, vec3 geometric_normal) {
+	int layer_count = int(light.shadow.y);
+	if (layer_count == 0) {
+		return 1.0;
+	}
+	int layer = int(light.shadow.x);
+
+	// ? Point lights: pick the cube face along the major axis of the light to fragment vector
+	if (int(light.pos.w) == LIGHT_POINT) {
+		vec3 v = position - light.pos.xyz;
+		vec3 a = abs(v);
+		int face;
+		if (a.x >= a.y && a.x >= a.z) {
+			face = v.x > 0.0 ? 0 : 1;
+		} else if (a.y >= a.z) {
+			face = v.y > 0.0 ? 2 : 3;
+		} else {
+			face = v.z > 0.0 ? 4 : 5;
+		}
+		layer += face;
+		return filtered_shadow(u_PointShadowMaps, u_point_shadow_maps[layer], layer, light, position, geometric_normal);
+	}
+
+	// ? Pick the first cascade covering the fragment, spot lights only have one
+	for (int cascade = 0; cascade < layer_count - 1; ++cascade) {
+		if (view_depth <= u_shadow_maps[layer].split.x) {
+			break;
+		}
+		layer++;
+	}
+	return filtered_shadow(u_ShadowMaps, u_shadow_maps[layer], layer, light, position, geometric_normal);
+}
+
+// ? Diffuse and Blinn-Phong specular of one light, before attenuation
+vec3 blinn_phong(Light light, vec3 L, vec3 N, vec3 O, vec3 kd, vec3 ks, float shininess, bool use_fresnel) {
+	float cosTheta = max(dot(N, L), 0.0);
+	if (cosTheta <= 0) {
+		return vec3(0.0);
+	}
+	vec3 H = normalize(L + O);
+	vec3 fr = use_fresnel ? fresnel(max(dot(L, H), 0.0), ks) : ks;
+	vec3 diffuse = kd * cosTheta;
+	vec3 specular = fr * pow(max(dot(N, H), 0.0), shininess);
+	return light.color.rgb * (diffuse + specular);
+}
+
+// ? Cook-Torrance GGX of one light, before attenuation
+vec3 cook_torrance(Light light, vec3 L, vec3 N, vec3 O, vec3 base_color, float metallic, float roughness) {
+	float n_dot_l = max(dot(N, L), 0.0);
+	if (n_dot_l <= 0) {
+		return vec3(0.0);
+	}
+	float n_dot_v = max(dot(N, O), 0.0001);
+	vec3 H = normalize(L + O);
+	vec3 f0 = mix(vec3(0.04), base_color, metallic);
+	vec3 F = fresnel(max(dot(H, O), 0.0), f0);
+	float D = distribution_ggx(max(dot(N, H), 0.0), roughness);
+	float G = geometry_smith(n_dot_v, n_dot_l, roughness);
+	vec3 specular = D * G * F / (4.0 * n_dot_v * n_dot_l + 0.0001);
+	vec3 diffuse = (vec3(1.0) - F) * (1.0 - metallic) * base_color / PI;
+	return (diffuse + specular) * light.color.rgb * n_dot_l;
+}
@@ -0,0 +1,62 @@
+// ? Material inputs shared by the forward and G-buffer fragment shaders
+const float dissolve_cutoff = 0.5; // map_d texels under this are cut out
+
+uniform sampler2D u_AmbientTexture;
+uniform sampler2D u_DiffuseTexture;
+uniform sampler2D u_SpecularTexture;
+uniform sampler2D u_NormalTexture; // tangent space
+uniform sampler2D u_DissolveTexture;
+uniform sampler2D u_MetallicTexture;
+uniform sampler2D u_RoughnessTexture;
+uniform sampler2D u_EmissiveTexture;
+uniform sampler2D u_OcclusionTexture;
+
+// ? Values taken from the MTL file, see IlluminationModel in scene/material.rs
+uniform MaterialLocals {
+	vec4 u_Ambient;
+	vec4 u_Diffuse;
+	vec4 u_Specular;
+	float u_Shininess;
+	float u_Dissolve;
+	float u_OpticalDensity;
+	int u_IlluminationModel;
+	vec4 u_Emissive;
+	float u_Metallic;
+	float u_Roughness;
+	float u_OcclusionStrength;
+	int u_Pbr;
+};
+
+struct Surface {
+	vec3 ka;
+	vec3 kd; // base color for PBR materials
+	vec3 ks;
+	vec3 emissive;
+	vec3 N; // after normal mapping
+	float metallic;
+	float roughness;
+	float occlusion;
+	float coverage; // from map_d
+};
+
+vec3 perturbed_normal(vec3 normal, vec4 tangent, vec2 uv) {
+	vec3 N = normalize(normal);
+	vec3 T = normalize(tangent.xyz - N * dot(N, tangent.xyz));
+	vec3 B = cross(N, T) * tangent.w;
+	vec3 tangent_space_normal = texture(u_NormalTexture, uv).xyz * 2.0 - 1.0;
+	return normalize(mat3(T, B, N) * tangent_space_normal);
+}
+
+Surface sample_surface(vec3 normal, vec4 tangent, vec2 uv) {
+	Surface surface;
+	surface.coverage = texture(u_DissolveTexture, uv).r;
+	surface.ka = u_Ambient.rgb * texture(u_AmbientTexture, uv).rgb;
+	surface.kd = u_Diffuse.rgb * texture(u_DiffuseTexture, uv).rgb;
+	surface.ks = u_Specular.rgb * texture(u_SpecularTexture, uv).rgb;
+	surface.emissive = u_Emissive.rgb * texture(u_EmissiveTexture, uv).rgb;
+	surface.N = perturbed_normal(normal, tangent, uv);
+	surface.metallic = u_Metallic * texture(u_MetallicTexture, uv).r;
+	surface.roughness = clamp(u_Roughness * texture(u_RoughnessTexture, uv).r, 0.04, 1.0);
+	surface.occlusion = mix(1.0, texture(u_OcclusionTexture, uv).r, u_OcclusionStrength);
+	return surface;
+}
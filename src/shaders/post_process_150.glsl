@@ -0,0 +1,15 @@
+// ? Inputs shared by the post-process passes, see renderer::post_process
+uniform sampler2D u_Source;
+
+uniform PostLocals {
+	vec4 u_Params; // effect specific
+	vec4 u_TexelSize; // 1 / width, 1 / height, width, height of u_Source
+};
+
+in vec2 v_uv;
+
+out vec4 Target0;
+
+float luminance(vec3 color) {
+	return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}